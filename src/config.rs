@@ -0,0 +1,90 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fs;
+
+use v6502::bus::{parse_range, Mirror, ReadOnly, UnmappedPolicy};
+use v6502::device::parse_seed;
+
+use crate::Opts;
+
+/**
+ * Reads a configuration file of `key = value` lines. Keys are the long
+ * command line option names. Lines starting with # are comments. Options
+ * given on the command line take precedence, except that devices, RAM,
+ * read-only regions, mirrors, and symbol files from both are used.
+ */
+pub fn apply(opts: &mut Opts, filename: &str) -> Result<(), String> {
+    let text = fs::read_to_string(filename)
+        .map_err(|e| format!("couldn't read {}: {}", filename, e))?;
+    let mut devices = Vec::new();
+    let mut symbols = Vec::new();
+    let mut rom = Vec::new();
+    let mut ram = Vec::new();
+    let mut mirrors = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message: &str| format!("{} line {}: {}", filename, n + 1, message);
+        let mut parts = line.splitn(2, '=');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key.trim(), value.trim()),
+            _ => return Err(error("expected key = value")),
+        };
+        match key {
+            "program" => if opts.program.is_none() {
+                opts.program = Some(value.to_string());
+            },
+            "seed" => if opts.seed.is_none() {
+                opts.seed = Some(parse_seed(value).ok_or_else(|| error("bad seed"))?);
+            },
+            "device" => devices.push(value.to_string()),
+            "rom" => rom.push(ReadOnly::parse(value).map_err(|e| error(&e))?),
+            "ram" => ram.push(parse_range(value).map_err(|e| error(&e))?),
+            "mirror" => mirrors.push(Mirror::parse(value).map_err(|e| error(&e))?),
+            "unmapped" => if opts.unmapped.is_none() {
+                opts.unmapped = Some(UnmappedPolicy::parse(value)
+                    .ok_or_else(|| error("unmapped must be open-bus or halt"))?);
+            },
+            "symbols" => symbols.push(value.to_string()),
+            "listing" => if opts.listing.is_none() {
+                opts.listing = Some(value.to_string());
+            },
+            "raw" => match value {
+                "true" => opts.raw = true,
+                "false" => {},
+                _ => return Err(error("raw must be true or false")),
+            },
+            _ => return Err(error(&format!("unknown setting {}", key))),
+        }
+    }
+    devices.append(&mut opts.device);
+    opts.device = devices;
+    symbols.append(&mut opts.symbols);
+    opts.symbols = symbols;
+    rom.append(&mut opts.rom);
+    opts.rom = rom;
+    ram.append(&mut opts.ram);
+    opts.ram = ram;
+    mirrors.append(&mut opts.mirror);
+    opts.mirror = mirrors;
+    Ok(())
+}
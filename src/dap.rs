@@ -0,0 +1,582 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! A Debug Adapter Protocol server that talks to the editor over stdin and stdout.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use serde_json::{json, Value};
+
+use v6502::callstack::CallStack;
+use v6502::cpu::Cpu;
+use v6502::instruction::InstructionType;
+use v6502::source_map::SourceMap;
+use v6502::util::try_load_hex;
+
+const THREAD_ID: i64 = 1;
+const REGISTERS_REFERENCE: i64 = 1;
+const FLAGS_REFERENCE: i64 = 2;
+const ZERO_PAGE_REFERENCE: i64 = 3;
+
+/** Number of instructions to run between checks for new requests. */
+const RUN_CHUNK: usize = 10000;
+
+/** Collects terminal output so it can be forwarded as output events. */
+#[derive(Clone)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Stop {
+    Step,
+    Breakpoint,
+    Pause,
+    Exited,
+}
+
+struct Session {
+    cpu: Cpu,
+    requests: Receiver<Value>,
+    /// Requests that arrived while stepping, to be handled next
+    pending: VecDeque<Value>,
+    out: Box<dyn Write>,
+    seq: i64,
+    source_map: SourceMap,
+    call_stack: CallStack,
+    source_breakpoints: BTreeMap<String, Vec<u16>>,
+    instruction_breakpoints: Vec<u16>,
+    function_breakpoints: Vec<u16>,
+    breakpoints: BTreeSet<u16>,
+    output: SharedBuffer,
+    stop_on_entry: bool,
+    running: bool,
+    finished: bool,
+}
+
+/** Reads requests from the client on a separate thread. */
+fn read_requests<R: Read + Send + 'static>(input: R) -> Receiver<Value> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = BufReader::new(input);
+        loop {
+            let mut length = None;
+            loop {
+                let mut line = String::new();
+                if input.read_line(&mut line).unwrap_or(0) == 0 {
+                    return;
+                }
+                let line = line.trim();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("Content-Length:") {
+                    length = value.trim().parse::<usize>().ok();
+                }
+            }
+            let length = match length {
+                Some(length) => length,
+                None => continue,
+            };
+            let mut body = vec![0; length];
+            if input.read_exact(&mut body).is_err() {
+                return;
+            }
+            if let Ok(message) = serde_json::from_slice(&body) {
+                if tx.send(message).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    rx
+}
+
+/** Runs a DAP session over stdin and stdout until the client disconnects. */
+pub fn serve(cpu: Cpu, listing: Option<&str>) {
+    serve_streams(cpu, listing, io::stdin(), Box::new(io::stdout()));
+}
+
+/** Runs a DAP session over the given streams until the client disconnects. */
+pub fn serve_streams<R: Read + Send + 'static>(cpu: Cpu, listing: Option<&str>, input: R, output: Box<dyn Write>) {
+    let mut session = Session::new(cpu, read_requests(input), output);
+    if let Some(listing) = listing {
+        session.load_listing(listing);
+    }
+    while !session.finished {
+        if session.running {
+            if let Some(stop) = session.run(RUN_CHUNK) {
+                session.stopped(stop);
+            }
+            session.flush_output();
+            let connected = session.poll();
+            while let Some(request) = session.pending.pop_front() {
+                session.handle(&request);
+            }
+            if !connected {
+                return;
+            }
+        } else {
+            let request = match session.pending.pop_front() {
+                Some(request) => request,
+                None => match session.requests.recv() {
+                    Ok(request) => request,
+                    Err(_) => return,
+                },
+            };
+            session.handle(&request);
+        }
+    }
+}
+
+impl Session {
+    fn new(mut cpu: Cpu, requests: Receiver<Value>, out: Box<dyn Write>) -> Session {
+        // stdin and stdout carry the protocol, so the terminal is redirected
+        let output = SharedBuffer(Rc::new(RefCell::new(Vec::new())));
+        cpu.terminal.set_input(Box::new(io::empty()));
+        cpu.terminal.output = Box::new(output.clone());
+        Session {
+            cpu,
+            requests,
+            pending: VecDeque::new(),
+            out,
+            seq: 1,
+            source_map: SourceMap::new(),
+            call_stack: CallStack::new(),
+            source_breakpoints: BTreeMap::new(),
+            instruction_breakpoints: Vec::new(),
+            function_breakpoints: Vec::new(),
+            breakpoints: BTreeSet::new(),
+            output,
+            stop_on_entry: false,
+            running: false,
+            finished: false,
+        }
+    }
+
+    fn load_listing(&mut self, listing: &str) {
+        match SourceMap::load(listing) {
+            Ok(map) => self.source_map = map,
+            Err(e) => self.log(&format!("Couldn't load listing {}: {}\n", listing, e)),
+        }
+    }
+
+    /** Loads symbol files named by a string or an array of strings. */
+    fn load_symbols(&mut self, files: &Value) {
+        let files: Vec<&str> = match files {
+            Value::String(file) => vec![file.as_str()],
+            Value::Array(files) => files.iter().filter_map(|f| f.as_str()).collect(),
+            _ => Vec::new(),
+        };
+        for file in files {
+            if let Err(e) = self.cpu.symbols.load(file) {
+                self.log(&format!("Couldn't load symbols {}: {}\n", file, e));
+            }
+        }
+    }
+
+    fn send(&mut self, mut message: Value) {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        let body = message.to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.out.flush().unwrap();
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({"type": "event", "event": event, "body": body}));
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }));
+    }
+
+    fn fail(&mut self, request: &Value, message: &str) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }));
+    }
+
+    fn log(&mut self, text: &str) {
+        self.event("output", json!({"category": "console", "output": text}));
+    }
+
+    fn flush_output(&mut self) {
+        let data: Vec<u8> = self.output.0.borrow_mut().drain(..).collect();
+        if !data.is_empty() {
+            let text = String::from_utf8_lossy(&data).into_owned();
+            self.event("output", json!({"category": "stdout", "output": text}));
+        }
+    }
+
+    fn step_instruction(&mut self) -> Option<Stop> {
+        self.call_stack.step(&mut self.cpu);
+        if self.cpu.is_break() {
+            Some(Stop::Exited)
+        } else {
+            None
+        }
+    }
+
+    /** Runs up to count instructions, stopping early at a breakpoint. */
+    fn run(&mut self, count: usize) -> Option<Stop> {
+        for _ in 0..count {
+            if let Some(stop) = self.step_instruction() {
+                return Some(stop);
+            }
+            if self.breakpoints.contains(&self.cpu.pc) {
+                return Some(Stop::Breakpoint);
+            }
+        }
+        None
+    }
+
+    /**
+     * Queues requests that have arrived without waiting. Returns false
+     * once the client has disconnected.
+     */
+    fn poll(&mut self) -> bool {
+        loop {
+            match self.requests.try_recv() {
+                Ok(request) => self.pending.push_back(request),
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
+
+    /**
+     * Runs until the call stack is no deeper than the given depth.
+     * Used to step over and out of subroutines. Stops early if the
+     * client pauses, disconnects, or terminates.
+     */
+    fn run_to_depth(&mut self, depth: usize) -> Stop {
+        let mut count = 0;
+        loop {
+            if let Some(stop) = self.step_instruction() {
+                return stop;
+            }
+            if self.call_stack.depth() <= depth {
+                return Stop::Step;
+            }
+            if self.breakpoints.contains(&self.cpu.pc) {
+                return Stop::Breakpoint;
+            }
+            count += 1;
+            if count % RUN_CHUNK == 0 {
+                self.flush_output();
+                if !self.poll() {
+                    return Stop::Pause;
+                }
+                if let Some(i) = self.pending.iter().position(|r| r["command"] == "pause") {
+                    let request = self.pending.remove(i).unwrap();
+                    self.respond(&request, json!({}));
+                    return Stop::Pause;
+                }
+                // Disconnect and terminate are handled once the step stops
+                if self.pending.iter().any(|r| r["command"] == "disconnect" || r["command"] == "terminate") {
+                    return Stop::Pause;
+                }
+            }
+        }
+    }
+
+    fn stopped(&mut self, stop: Stop) {
+        self.running = false;
+        self.flush_output();
+        let reason = match stop {
+            Stop::Step => "step",
+            Stop::Breakpoint => "breakpoint",
+            Stop::Pause => "pause",
+            Stop::Exited => {
+                let code = self.cpu.exit_code.unwrap_or(0);
+                self.event("exited", json!({"exitCode": code}));
+                self.event("terminated", json!({}));
+                return;
+            },
+        };
+        self.event("stopped", json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        }));
+    }
+
+    fn update_breakpoints(&mut self) {
+        self.breakpoints = self.source_breakpoints.values().flatten()
+            .chain(self.instruction_breakpoints.iter())
+            .chain(self.function_breakpoints.iter())
+            .cloned()
+            .collect();
+    }
+
+    fn source(&self, path: &str) -> Value {
+        let name = std::path::Path::new(path).file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.to_string());
+        json!({"name": name, "path": path})
+    }
+
+    fn frame(&self, id: usize, name: String, pc: u16) -> Value {
+        let mut frame = json!({
+            "id": id,
+            "name": name,
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": format!("0x{:04X}", pc),
+        });
+        if let Some(loc) = self.source_map.location(pc) {
+            frame["source"] = self.source(&loc.file);
+            frame["line"] = json!(loc.line);
+            frame["column"] = json!(1);
+        }
+        frame
+    }
+
+    fn stack_trace(&self) -> Vec<Value> {
+        let mut frames = Vec::new();
+        let mut pc = self.cpu.pc;
+        for (id, f) in self.call_stack.frames.iter().rev().enumerate() {
+            let name = match self.cpu.symbols.name(f.entry) {
+                Some(name) => name.to_string(),
+                None => format!("${:04X}", f.entry),
+            };
+            frames.push(self.frame(id, name, pc));
+            pc = f.call_site;
+        }
+        frames.push(self.frame(frames.len(), "main".to_string(), pc));
+        frames
+    }
+
+    fn variables(&self, reference: i64) -> Vec<Value> {
+        let cpu = &self.cpu;
+        let var = |name: String, value: String| json!({
+            "name": name, "value": value, "variablesReference": 0,
+        });
+        match reference {
+            REGISTERS_REFERENCE => vec![
+                var("A".to_string(), format!("${:02X}", cpu.a)),
+                var("X".to_string(), format!("${:02X}", cpu.x)),
+                var("Y".to_string(), format!("${:02X}", cpu.y)),
+                var("SP".to_string(), format!("${:02X}", cpu.sp)),
+                var("PC".to_string(), format!("${:04X}", cpu.pc)),
+                var("SR".to_string(), format!("${:02X}", cpu.sr)),
+            ],
+            FLAGS_REFERENCE => ["N", "V", "-", "B", "D", "I", "Z", "C"].iter().enumerate()
+                .filter(|(_, name)| **name != "-")
+                .map(|(i, name)| {
+                    var(name.to_string(), cpu.get_status_bit(7 - i as i8).to_string())
+                })
+                .collect(),
+            ZERO_PAGE_REFERENCE => cpu.peek_range(0x0000, 0x0100).chunks(16).enumerate()
+                .map(|(row, chunk)| {
+                    let bytes: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+                    var(format!("${:04X}", row * 16), bytes.join(" "))
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn handle(&mut self, request: &Value) {
+        let args = &request["arguments"];
+        match request["command"].as_str().unwrap_or("") {
+            "initialize" => {
+                self.respond(request, json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsFunctionBreakpoints": true,
+                    "supportsInstructionBreakpoints": true,
+                    "supportsTerminateRequest": true,
+                }));
+                self.event("initialized", json!({}));
+            },
+            "launch" => {
+                if let Some(program) = args["program"].as_str() {
+                    if let Err(e) = try_load_hex(&mut self.cpu, program) {
+                        self.fail(request, &e);
+                        return;
+                    }
+                    self.cpu.reset();
+                }
+                if let Some(listing) = args["listing"].as_str() {
+                    self.load_listing(listing);
+                }
+                self.load_symbols(&args["symbols"]);
+                self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                self.respond(request, json!({}));
+            },
+            "attach" => {
+                if let Some(listing) = args["listing"].as_str() {
+                    self.load_listing(listing);
+                }
+                self.load_symbols(&args["symbols"]);
+                self.stop_on_entry = true;
+                self.respond(request, json!({}));
+            },
+            "setBreakpoints" => {
+                let path = args["source"]["path"].as_str().unwrap_or("").to_string();
+                let mut addresses = Vec::new();
+                let mut results = Vec::new();
+                for bp in args["breakpoints"].as_array().unwrap_or(&Vec::new()) {
+                    let line = bp["line"].as_u64().unwrap_or(0) as usize;
+                    match self.source_map.address(&path, line) {
+                        Some((address, line)) => {
+                            addresses.push(address);
+                            results.push(json!({
+                                "verified": true,
+                                "line": line,
+                                "instructionReference": format!("0x{:04X}", address),
+                            }));
+                        },
+                        None => results.push(json!({
+                            "verified": false,
+                            "line": line,
+                            "message": "No code at this line",
+                        })),
+                    }
+                }
+                self.source_breakpoints.insert(path, addresses);
+                self.update_breakpoints();
+                self.respond(request, json!({"breakpoints": results}));
+            },
+            "setInstructionBreakpoints" | "setFunctionBreakpoints" => {
+                let key = if request["command"] == "setFunctionBreakpoints" { "name" } else { "instructionReference" };
+                let mut addresses = Vec::new();
+                let mut results = Vec::new();
+                for bp in args["breakpoints"].as_array().unwrap_or(&Vec::new()) {
+                    let offset = bp["offset"].as_i64().unwrap_or(0);
+                    match bp[key].as_str().and_then(|s| self.cpu.symbols.resolve(s)) {
+                        Some(address) => {
+                            let address = (address as i64 + offset) as u16;
+                            addresses.push(address);
+                            results.push(json!({
+                                "verified": true,
+                                "instructionReference": format!("0x{:04X}", address),
+                            }));
+                        },
+                        None => results.push(json!({"verified": false, "message": "Invalid address"})),
+                    }
+                }
+                if key == "name" {
+                    self.function_breakpoints = addresses;
+                } else {
+                    self.instruction_breakpoints = addresses;
+                }
+                self.update_breakpoints();
+                self.respond(request, json!({"breakpoints": results}));
+            },
+            "setExceptionBreakpoints" => self.respond(request, json!({"breakpoints": []})),
+            "configurationDone" => {
+                self.respond(request, json!({}));
+                if self.stop_on_entry {
+                    self.event("stopped", json!({
+                        "reason": "entry",
+                        "threadId": THREAD_ID,
+                        "allThreadsStopped": true,
+                    }));
+                } else {
+                    self.running = true;
+                }
+            },
+            "threads" => self.respond(request, json!({"threads": [{"id": THREAD_ID, "name": "6502"}]})),
+            "stackTrace" => {
+                let frames = self.stack_trace();
+                let total = frames.len();
+                self.respond(request, json!({"stackFrames": frames, "totalFrames": total}));
+            },
+            "scopes" => self.respond(request, json!({"scopes": [
+                {"name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false},
+                {"name": "Flags", "variablesReference": FLAGS_REFERENCE, "expensive": false},
+                {"name": "Zero Page", "variablesReference": ZERO_PAGE_REFERENCE, "expensive": false},
+            ]})),
+            "variables" => {
+                let variables = self.variables(args["variablesReference"].as_i64().unwrap_or(0));
+                self.respond(request, json!({"variables": variables}));
+            },
+            "continue" => {
+                self.respond(request, json!({"allThreadsContinued": true}));
+                self.running = true;
+            },
+            "next" => {
+                self.respond(request, json!({}));
+                let (i, _) = self.cpu.decode(self.cpu.pc);
+                let depth = self.call_stack.depth();
+                let stop = if i.t == InstructionType::Jsr {
+                    self.run_to_depth(depth)
+                } else {
+                    self.step_instruction().unwrap_or(Stop::Step)
+                };
+                self.stopped(stop);
+            },
+            "stepIn" => {
+                self.respond(request, json!({}));
+                let stop = self.step_instruction().unwrap_or(Stop::Step);
+                self.stopped(stop);
+            },
+            "stepOut" => {
+                self.respond(request, json!({}));
+                let depth = self.call_stack.depth();
+                let stop = if depth == 0 {
+                    self.step_instruction().unwrap_or(Stop::Step)
+                } else {
+                    self.run_to_depth(depth - 1)
+                };
+                self.stopped(stop);
+            },
+            "pause" => {
+                self.respond(request, json!({}));
+                self.stopped(Stop::Pause);
+            },
+            "disconnect" | "terminate" => {
+                self.respond(request, json!({}));
+                if request["command"] == "terminate" {
+                    self.event("terminated", json!({}));
+                }
+                self.finished = true;
+            },
+            command => {
+                let message = format!("Unsupported request: {}", command);
+                self.fail(request, &message);
+            },
+        }
+    }
+}
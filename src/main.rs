@@ -17,35 +17,300 @@
     along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+#[cfg(test)]
+mod tests;
+
+mod config;
+mod dap;
+
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::process;
 use std::time::Instant;
 
 use clap::{AppSettings, Clap};
 
+use v6502::bus::{parse_range, Mirror, ReadOnly, UnmappedPolicy};
+use v6502::coverage::Coverage;
 use v6502::cpu::Cpu;
+use v6502::debugger::Debugger;
+use v6502::device::{parse_seed, DeviceSpec, Rand};
+use v6502::heatmap::Heatmap;
+use v6502::gdb::GdbServer;
+use v6502::observer::{self, Observer};
+use v6502::profiler::Profiler;
+use v6502::source_map::SourceMap;
+use v6502::trace::Tracer;
 use v6502::util::load_hex;
 
 #[derive(Clap)]
 #[clap(version = "1.0", author = "Andrew C. Young <andrew@vaelen.org>")]
 #[clap(setting = AppSettings::ColoredHelp)]
 struct Opts {
-    #[clap(short, long, default_value = "program.hex")]
-    program: String,
+    /// Program to run, in Intel HEX format [default: program.hex]
+    #[clap(short, long)]
+    program: Option<String>,
+    /// Read settings from a file of key = value lines (program, seed, device, ram, rom, mirror, unmapped, symbols, listing, raw)
+    #[clap(short, long)]
+    config: Option<String>,
+    /// Seed for the random number generator at $FF, so runs can be repeated
+    #[clap(long, parse(try_from_str = parse_seed_arg))]
+    seed: Option<u32>,
+    /// Map a device into memory, e.g. via@$6000 or acia@$5000,port=pty; may be repeated
+    #[clap(long, multiple_occurrences(true), number_of_values(1))]
+    device: Vec<String>,
+    /// Make RAM read-only, e.g. $E000-$FFFF; add ,log or ,halt to record or stop on writes; may be repeated
+    #[clap(long, multiple_occurrences(true), number_of_values(1), parse(try_from_str = ReadOnly::parse))]
+    rom: Vec<ReadOnly>,
+    /// Declare RAM, e.g. $0000-$7FFF; once given, addresses outside RAM, ROM, and devices are unmapped; may be repeated
+    #[clap(long, multiple_occurrences(true), number_of_values(1), parse(try_from_str = parse_range))]
+    ram: Vec<(u16, u16)>,
+    /// Repeat memory through a range keeping only the address bits in a mask, e.g. $0000-$1FFF,$07FF; may be repeated
+    #[clap(long, multiple_occurrences(true), number_of_values(1), parse(try_from_str = Mirror::parse))]
+    mirror: Vec<Mirror>,
+    /// What unmapped addresses do: open-bus returns the last value on the data bus, halt stops the program
+    #[clap(long, parse(try_from_str = parse_unmapped_arg))]
+    unmapped: Option<UnmappedPolicy>,
+    /// Put the host terminal in raw mode so the program receives keys as they are typed
+    #[clap(long)]
+    raw: bool,
+    /// Start an interactive debugger instead of running the program
+    #[clap(short, long)]
+    debug: bool,
+    /// Number of instructions the debugger can reverse
+    #[clap(long, default_value = "10000")]
+    history: usize,
+    /// Wait for a GDB client on a TCP address (e.g. 127.0.0.1:6502) or a Unix socket (unix:/path)
+    #[clap(long)]
+    gdb: Option<String>,
+    /// Serve the Debug Adapter Protocol on stdin and stdout
+    #[clap(long)]
+    dap: bool,
+    /// Assembler listing or ca65 debug file (.dbg) used to map addresses to source lines
+    #[clap(short, long)]
+    listing: Option<String>,
+    /// Symbol file (VICE .lbl, ca65 .dbg, ld65 .map, or name = $addr lines); may be repeated
+    #[clap(short, long, multiple_occurrences(true), number_of_values(1))]
+    symbols: Vec<String>,
+    /// Write an instruction trace to a file, or to stderr if the file is -
+    #[clap(short, long)]
+    trace: Option<String>,
+    /// Profile the program and write a report of hot spots to a file, or to stderr if the file is -
+    #[clap(long)]
+    profile: Option<String>,
+    /// Profile the program and write folded call stacks for flame graph tools to a file
+    #[clap(long)]
+    folded: Option<String>,
+    /// Write an annotated disassembly showing code coverage to a file, or to stderr if the file is -
+    #[clap(long)]
+    coverage: Option<String>,
+    /// Write code coverage in lcov format to a file; requires --listing
+    #[clap(long, requires = "listing")]
+    lcov: Option<String>,
+    /// Write a map of memory reads, writes, and executions to a file, or to stderr if the file is -
+    #[clap(long)]
+    memory_map: Option<String>,
+    /// Write a memory access heatmap image to a file (.pgm for grayscale, .ppm for color)
+    #[clap(long)]
+    heatmap: Option<String>,
+}
+
+fn parse_seed_arg(s: &str) -> Result<u32, String> {
+    parse_seed(s).ok_or_else(|| format!("bad seed {}", s))
+}
+
+fn parse_unmapped_arg(s: &str) -> Result<UnmappedPolicy, String> {
+    UnmappedPolicy::parse(s).ok_or_else(|| format!("unknown unmapped policy {}", s))
+}
+
+/** Opens a file for writing, or stderr if the name is -. */
+fn create_output(filename: &str) -> Box<dyn Write> {
+    match filename {
+        "-" => Box::new(io::stderr()),
+        _ => match File::create(filename) {
+            Ok(file) => Box::new(io::BufWriter::new(file)),
+            Err(e) => panic!("couldn't create {}: {}", filename, e),
+        },
+    }
+}
+
+fn write_output(filename: &str, text: &str) {
+    let mut out = create_output(filename);
+    if let Err(e) = out.write_all(text.as_bytes()).and_then(|_| out.flush()) {
+        panic!("couldn't write {}: {}", filename, e);
+    }
+}
+
+fn write_binary(filename: &str, data: &[u8]) {
+    if let Err(e) = File::create(filename).and_then(|mut f| f.write_all(data)) {
+        panic!("couldn't write {}: {}", filename, e);
+    }
+}
+
+fn gdb(cpu: &mut Cpu, address: &str) {
+    let mut server = GdbServer::new();
+    let result = match address.strip_prefix("unix:") {
+        #[cfg(unix)]
+        Some(path) => server.listen_unix(cpu, path),
+        #[cfg(not(unix))]
+        Some(_) => panic!("Unix sockets are not supported on this platform"),
+        None => server.listen_tcp(cpu, address),
+    };
+    if let Err(e) = result {
+        panic!("GDB server failed: {}", e);
+    }
+}
+
+fn debug(cpu: &mut Cpu, history: usize) {
+    let mut debugger = Debugger::new();
+    debugger.attach(cpu, history);
+    eprintln!("Type help for a list of commands");
+    eprintln!("{}", debugger.command(cpu, "registers"));
+    let stdin = io::stdin();
+    loop {
+        eprint!("(v6502) ");
+        io::stderr().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+        let output = debugger.command(cpu, &line);
+        if !output.is_empty() {
+            eprintln!("{}", output);
+        }
+        if debugger.is_finished() {
+            break;
+        }
+    }
 }
 
 fn main() {
-    let opts: Opts = Opts::parse();
+    let mut opts: Opts = Opts::parse();
+    if let Some(filename) = opts.config.clone() {
+        if let Err(e) = config::apply(&mut opts, &filename) {
+            panic!("{}", e);
+        }
+    }
+    let program = opts.program.clone().unwrap_or_else(|| "program.hex".to_string());
     eprint!("Initializing...");
     let mut cpu = Cpu::new6502();
+    if let Some(seed) = opts.seed {
+        cpu.rand = Rand::from_seed(seed);
+    }
+    for filename in &opts.symbols {
+        if let Err(e) = cpu.symbols.load(filename) {
+            panic!("couldn't read symbols from {}: {}", filename, e);
+        }
+    }
+    for spec in &opts.device {
+        if opts.dap && matches!(DeviceSpec::parse(spec), Ok(s) if s.uses_stdio()) {
+            panic!("couldn't map device {}: stdin and stdout carry the Debug Adapter Protocol", spec);
+        }
+        if let Err(e) = cpu.bus.map_spec(spec) {
+            panic!("couldn't map device {}: {}", spec, e);
+        }
+    }
+    for region in &opts.rom {
+        cpu.bus.protect(*region);
+    }
+    for (start, end) in &opts.ram {
+        cpu.bus.add_ram(*start, *end);
+    }
+    for mirror in &opts.mirror {
+        cpu.bus.add_mirror(*mirror);
+    }
+    if let Some(policy) = opts.unmapped {
+        cpu.bus.set_unmapped_policy(policy);
+    }
     eprintln!("Done");
+    eprintln!("Random seed: {}", cpu.rand.seed());
+    for m in cpu.bus.iter() {
+        match m.mirror() {
+            Some(mirror) => eprintln!("{} at ${:04X}, mirrored through ${:04X} (mask ${:04X}): {}",
+                m.device.name(), m.start, mirror.end, mirror.mask, m.device.status()),
+            None => eprintln!("{} at ${:04X}: {}", m.device.name(), m.start, m.device.status()),
+        }
+    }
+    for (start, end) in cpu.bus.ram() {
+        eprintln!("RAM ${:04X}-${:04X}", start, end);
+    }
+    for mirror in cpu.bus.mirrors() {
+        eprintln!("Mirror ${:04X}-${:04X} (mask ${:04X})", mirror.start, mirror.end, mirror.mask);
+    }
+    for region in cpu.bus.read_only() {
+        eprintln!("Read-only ${:04X}-${:04X} ({})", region.start, region.end, region.policy);
+    }
+    if cpu.bus.ram().next().is_some() {
+        eprintln!("Unmapped addresses: {}", cpu.bus.unmapped_policy());
+    }
+    if opts.dap {
+        // The program can also be given in the launch request
+        if Path::new(&program).exists() {
+            load_hex(&mut cpu, &program);
+            cpu.reset();
+        }
+        dap::serve(cpu, opts.listing.as_deref());
+        return;
+    }
     eprint!("Loading Program...");
-    load_hex(&mut cpu, &opts.program);
+    load_hex(&mut cpu, &program);
     cpu.reset();
     eprintln!("Done");
     eprintln!("Initial PC: {:04X}", cpu.pc);
+    if opts.debug {
+        debug(&mut cpu, opts.history);
+        return;
+    }
+    if let Some(address) = &opts.gdb {
+        gdb(&mut cpu, address);
+        return;
+    }
+    if opts.raw {
+        if let Err(e) = cpu.terminal.set_raw_mode() {
+            eprintln!("Couldn't put the terminal in raw mode: {}", e);
+        }
+    }
     eprint!("Running...");
     let start_time = Instant::now();
-    cpu.run();
+    let mut tracer = opts.trace.as_deref().map(|f| Tracer::new(create_output(f)));
+    let mut profiler = if opts.profile.is_some() || opts.folded.is_some() {
+        Some(Profiler::new())
+    } else {
+        None
+    };
+    let mut coverage = if opts.coverage.is_some() || opts.lcov.is_some() {
+        Some(Coverage::new())
+    } else {
+        None
+    };
+    let mut heatmap = if opts.memory_map.is_some() || opts.heatmap.is_some() {
+        let heatmap = Heatmap::new();
+        heatmap.attach(&mut cpu);
+        Some(heatmap)
+    } else {
+        None
+    };
+    let mut observers: Vec<&mut dyn Observer> = Vec::new();
+    if let Some(tracer) = &mut tracer {
+        observers.push(tracer);
+    }
+    if let Some(profiler) = &mut profiler {
+        observers.push(profiler);
+    }
+    if let Some(coverage) = &mut coverage {
+        observers.push(coverage);
+    }
+    if let Some(heatmap) = &mut heatmap {
+        observers.push(heatmap);
+    }
+    if observers.is_empty() {
+        cpu.run();
+    } else {
+        observer::run(&mut cpu, &mut observers);
+    }
     let runtime = start_time.elapsed();
+    cpu.terminal.restore_mode();
     eprintln!("Done");
     if runtime.as_secs() > 0 {
         eprintln!("Runtime: {} s", runtime.as_secs_f32());
@@ -54,7 +319,66 @@ fn main() {
     } else {
         eprintln!("Runtime: {} μs", runtime.as_micros());
     }
-    eprintln!("");
+    if let Some(tracer) = &mut tracer {
+        if let Err(e) = tracer.finish() {
+            panic!("couldn't write trace: {}", e);
+        }
+    }
+    if let Some(profiler) = &profiler {
+        if let Some(filename) = &opts.profile {
+            write_output(filename, &profiler.report(&cpu.symbols));
+        }
+        if let Some(filename) = &opts.folded {
+            write_output(filename, &profiler.folded(&cpu.symbols));
+        }
+    }
+    if let Some(coverage) = &coverage {
+        let source_map = match &opts.listing {
+            Some(listing) => match SourceMap::load(listing) {
+                Ok(source_map) => source_map,
+                Err(e) => panic!("couldn't read listing {}: {}", listing, e),
+            },
+            None => SourceMap::new(),
+        };
+        if let Some(filename) = &opts.coverage {
+            write_output(filename, &coverage.annotated(&cpu, &source_map));
+        }
+        if let Some(filename) = &opts.lcov {
+            write_output(filename, &coverage.lcov(&cpu, &source_map, &program));
+        }
+    }
+    if let Some(heatmap) = &heatmap {
+        if let Some(filename) = &opts.memory_map {
+            write_output(filename, &heatmap.text_map());
+        }
+        if let Some(filename) = &opts.heatmap {
+            if filename.to_lowercase().ends_with(".pgm") {
+                write_binary(filename, &heatmap.pgm());
+            } else {
+                write_binary(filename, &heatmap.ppm());
+            }
+        }
+    }
+    eprintln!();
     eprintln!("{:?}", cpu);
     //println!("{:X}", cpu);
+    for fault in &cpu.write_faults {
+        eprintln!("Ignored {}", fault);
+    }
+    let stopped = match (cpu.fault, cpu.unmapped_access) {
+        (Some(fault), _) => Some(fault.to_string()),
+        (None, Some(access)) => Some(access.to_string()),
+        (None, None) => None,
+    };
+    if let Some(reason) = stopped {
+        eprintln!("Stopped by a {}", reason);
+        io::stdout().flush().unwrap();
+        process::exit(1);
+    }
+    if let Some(code) = cpu.exit_code {
+        eprintln!("Exit code: {}", code);
+        // process::exit doesn't run destructors, so flush output first
+        io::stdout().flush().unwrap();
+        process::exit(code as i32);
+    }
 }
@@ -0,0 +1,228 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use v6502::cpu::Cpu;
+
+use crate::dap;
+
+/** The adapter's end of an in-memory stream. */
+struct ChannelReader {
+    rx: Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buffer.len() {
+            match self.rx.recv() {
+                Ok(data) => {
+                    self.buffer = data;
+                    self.pos = 0;
+                },
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.buffer.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buffer[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+struct ChannelWriter(Sender<Vec<u8>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let _ = self.0.send(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/** A DAP client that sends requests and waits for messages from the adapter. */
+struct Client {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+    seq: i64,
+}
+
+impl Client {
+    fn request(&mut self, command: &str, arguments: Value) {
+        let body = json!({"seq": self.seq, "type": "request", "command": command, "arguments": arguments})
+            .to_string();
+        self.seq += 1;
+        self.tx.send(format!("Content-Length: {}\r\n\r\n{}", body.len(), body).into_bytes()).unwrap();
+    }
+
+    fn message(&mut self) -> Value {
+        loop {
+            let text = String::from_utf8_lossy(&self.buffer).into_owned();
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length: usize = text[..end].trim_start_matches("Content-Length:").trim().parse().unwrap();
+                let start = end + 4;
+                if self.buffer.len() >= start + length {
+                    let message = serde_json::from_slice(&self.buffer[start..start + length]).unwrap();
+                    self.buffer.drain(..start + length);
+                    return message;
+                }
+            }
+            let data = self.rx.recv_timeout(Duration::from_secs(10)).expect("no message from the adapter");
+            self.buffer.extend(data);
+        }
+    }
+
+    /** Returns the response to a command, skipping other messages. */
+    fn response(&mut self, command: &str) -> Value {
+        loop {
+            let message = self.message();
+            if message["type"] == "response" && message["command"] == command {
+                return message;
+            }
+        }
+    }
+
+    fn event(&mut self, event: &str) -> Value {
+        loop {
+            let message = self.message();
+            if message["type"] == "event" && message["event"] == event {
+                return message;
+            }
+        }
+    }
+
+    fn register(&mut self, name: &str) -> String {
+        self.request("variables", json!({"variablesReference": 1}));
+        let response = self.response("variables");
+        let variable = response["body"]["variables"].as_array().unwrap().iter()
+            .find(|v| v["name"] == name).unwrap().clone();
+        variable["value"].as_str().unwrap().to_string()
+    }
+}
+
+/** Runs an adapter for a CPU while a client talks to it on another thread. */
+fn with_adapter<F>(cpu: Cpu, client: F)
+    where F: FnOnce(&mut Client) + Send + 'static
+{
+    let (request_tx, request_rx) = mpsc::channel();
+    let (response_tx, response_rx) = mpsc::channel();
+    let client = thread::spawn(move || {
+        let mut c = Client { tx: request_tx, rx: response_rx, buffer: Vec::new(), seq: 1 };
+        client(&mut c);
+    });
+    let input = ChannelReader { rx: request_rx, buffer: Vec::new(), pos: 0 };
+    dap::serve_streams(cpu, None, input, Box::new(ChannelWriter(response_tx)));
+    client.join().unwrap();
+}
+
+fn temp_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("v6502-dap-{}-{}", std::process::id(), name));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn dap_breakpoints_and_exit() {
+    let program = temp_file("loop.hex", "0600: A2 00 E8 E0 05 D0 FB 8E F0 FF\nFFFC: 00 06\n");
+    let listing = temp_file("loop.lst", "\
+0600  A2 00     START    LDX #$00
+0602  E8        LOOP     INX
+0603  E0 05              CPX #$05
+0605  D0 FB              BNE LOOP
+0607  8E F0 FF           STX $FFF0
+");
+    let mut cpu = Cpu::new6502();
+    cpu.bus.map_spec("exit@$FFF0").unwrap();
+    let (program_path, listing_path) = (program.clone(), listing.clone());
+    with_adapter(cpu, move |c| {
+        let program = program_path.to_str().unwrap();
+        let listing = listing_path.to_str().unwrap();
+        c.request("initialize", json!({"adapterID": "v6502"}));
+        assert_eq!(c.response("initialize")["success"], true);
+        c.event("initialized");
+
+        c.request("launch", json!({"program": "/nonexistent/program.hex"}));
+        let response = c.response("launch");
+        assert_eq!(response["success"], false, "a missing program fails the launch");
+        assert!(response["message"].as_str().unwrap().contains("couldn't open"));
+
+        c.request("launch", json!({"program": program, "listing": listing, "stopOnEntry": true}));
+        assert_eq!(c.response("launch")["success"], true);
+        c.request("setBreakpoints", json!({"source": {"path": listing}, "breakpoints": [{"line": 2}]}));
+        let response = c.response("setBreakpoints");
+        assert_eq!(response["body"]["breakpoints"][0]["verified"], true);
+        c.request("configurationDone", json!({}));
+        assert_eq!(c.event("stopped")["body"]["reason"], "entry");
+
+        c.request("continue", json!({"threadId": 1}));
+        assert_eq!(c.event("stopped")["body"]["reason"], "breakpoint");
+        assert_eq!(c.register("PC"), "$0602");
+        assert_eq!(c.register("X"), "$00");
+        c.request("continue", json!({"threadId": 1}));
+        assert_eq!(c.event("stopped")["body"]["reason"], "breakpoint");
+        assert_eq!(c.register("X"), "$01");
+
+        c.request("setBreakpoints", json!({"source": {"path": listing}, "breakpoints": []}));
+        c.response("setBreakpoints");
+        c.request("continue", json!({"threadId": 1}));
+        assert_eq!(c.event("exited")["body"]["exitCode"], 5, "the exit device's code is reported");
+        c.event("terminated");
+        c.request("disconnect", json!({}));
+        c.response("disconnect");
+    });
+    std::fs::remove_file(program).unwrap();
+    std::fs::remove_file(listing).unwrap();
+}
+
+#[test]
+fn dap_pause_during_step_over() {
+    // JSR $0604; BRK; $0604: JMP $0604
+    let program = temp_file("spin.hex", "0600: 20 04 06 00 4C 04 06\nFFFC: 00 06\n");
+    let program_path = program.clone();
+    with_adapter(Cpu::new6502(), move |c| {
+        c.request("initialize", json!({}));
+        c.response("initialize");
+        c.request("launch", json!({"program": program_path.to_str().unwrap(), "stopOnEntry": true}));
+        c.response("launch");
+        c.request("configurationDone", json!({}));
+        assert_eq!(c.event("stopped")["body"]["reason"], "entry");
+
+        c.request("next", json!({"threadId": 1}));
+        c.response("next");
+        c.request("pause", json!({"threadId": 1}));
+        c.response("pause");
+        assert_eq!(c.event("stopped")["body"]["reason"], "pause", "pause stops a step over that never returns");
+        c.request("disconnect", json!({}));
+        c.response("disconnect");
+    });
+    std::fs::remove_file(program).unwrap();
+}
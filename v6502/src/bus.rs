@@ -0,0 +1,463 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fmt;
+
+use crate::debugger::parse_address;
+use crate::device::{self, Device, DeviceSpec};
+
+/**
+ * A device and the first address of its register window, which may be
+ * repeated through a larger range.
+ */
+pub struct Mapping {
+    pub start: u16,
+    pub device: Box<dyn Device>,
+    mirror: Option<Mirror>,
+}
+
+impl Mapping {
+    /** Returns the last address in the device's register window, including mirrors. */
+    pub fn end(&self) -> u16 {
+        match self.mirror {
+            Some(mirror) => mirror.end,
+            None => self.start.saturating_add(self.device.size().saturating_sub(1)),
+        }
+    }
+
+    pub fn mirror(&self) -> Option<Mirror> {
+        self.mirror
+    }
+
+    /**
+     * Returns the register an address selects, or None if it is outside
+     * the window or a mirror decodes it past the device's last register.
+     */
+    fn offset(&self, address: u16) -> Option<u16> {
+        if address < self.start || address > self.end() {
+            return None;
+        }
+        let offset = match self.mirror {
+            Some(mirror) => mirror.resolve(address) - self.start,
+            None => address - self.start,
+        };
+        if offset < self.device.size() { Some(offset) } else { None }
+    }
+}
+
+/**
+ * A range of addresses that only decodes the address bits in mask, so it
+ * repeats the memory or registers at its start, e.g. 2KB of RAM seen four
+ * times through $0000-$1FFF.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Mirror {
+    pub start: u16,
+    pub end: u16,
+    pub mask: u16,
+}
+
+impl Mirror {
+    pub fn new(start: u16, end: u16, mask: u16) -> Result<Mirror, String> {
+        if start > end || start as u32 + mask as u32 > 0xFFFF {
+            return Err(format!("mask ${:04X} doesn't fit in ${:04X}-${:04X}", mask, start, end));
+        }
+        Ok(Mirror { start, end, mask })
+    }
+
+    /** Parses a mirror written as START-END,MASK, e.g. $0000-$1FFF,$07FF. */
+    pub fn parse(s: &str) -> Result<Mirror, String> {
+        let mut parts = s.splitn(2, ',');
+        let (start, end) = parse_range(parts.next().unwrap_or(""))?;
+        let mask = match parts.next() {
+            Some(m) => parse_address(m).ok_or_else(|| format!("bad mirror mask {}", m))?,
+            None => return Err(format!("missing mask in mirror {}", s)),
+        };
+        Mirror::new(start, end, mask)
+    }
+
+    pub fn contains(&self, address: u16) -> bool {
+        address >= self.start && address <= self.end
+    }
+
+    /** Returns the address that an address in the range repeats. */
+    pub fn resolve(&self, address: u16) -> u16 {
+        self.start + ((address - self.start) & self.mask)
+    }
+}
+
+/** What happens when the program writes to read-only memory. */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WritePolicy {
+    /// The write is dropped
+    Ignore,
+    /// The write is dropped and recorded in the CPU's write_faults
+    Log,
+    /// The write is dropped and the program stops with the CPU's fault set
+    Halt,
+}
+
+impl WritePolicy {
+    pub fn parse(s: &str) -> Option<WritePolicy> {
+        match s.trim() {
+            "ignore" => Some(WritePolicy::Ignore),
+            "log" => Some(WritePolicy::Log),
+            "halt" => Some(WritePolicy::Halt),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for WritePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            WritePolicy::Ignore => "ignore",
+            WritePolicy::Log => "log",
+            WritePolicy::Halt => "halt",
+        })
+    }
+}
+
+/** A range of RAM that the program can't write. */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ReadOnly {
+    pub start: u16,
+    pub end: u16,
+    pub policy: WritePolicy,
+}
+
+impl ReadOnly {
+    /**
+     * Parses a region written as START-END with an optional policy, e.g.
+     * $E000-$FFFF,halt. The default policy is ignore.
+     */
+    pub fn parse(s: &str) -> Result<ReadOnly, String> {
+        let mut parts = s.splitn(2, ',');
+        let range = parts.next().unwrap_or("");
+        let policy = match parts.next() {
+            Some(p) => WritePolicy::parse(p).ok_or_else(|| format!("unknown write policy {}", p))?,
+            None => WritePolicy::Ignore,
+        };
+        let (start, end) = parse_range(range)?;
+        Ok(ReadOnly { start, end, policy })
+    }
+}
+
+/** Parses an address range written as START-END, e.g. $0000-$7FFF. */
+pub fn parse_range(s: &str) -> Result<(u16, u16), String> {
+    let mut bounds = s.splitn(2, '-').map(parse_address);
+    match (bounds.next().flatten(), bounds.next().flatten()) {
+        (Some(start), Some(end)) if start <= end => Ok((start, end)),
+        _ => Err(format!("bad address range {}", s)),
+    }
+}
+
+/** What happens when the program uses an address that nothing is mapped at. */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UnmappedPolicy {
+    /// Reads return the last value on the data bus and writes are dropped
+    OpenBus,
+    /// The program stops with the CPU's unmapped access set
+    Halt,
+}
+
+impl UnmappedPolicy {
+    pub fn parse(s: &str) -> Option<UnmappedPolicy> {
+        match s.trim() {
+            "open-bus" => Some(UnmappedPolicy::OpenBus),
+            "halt" => Some(UnmappedPolicy::Halt),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for UnmappedPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            UnmappedPolicy::OpenBus => "open-bus",
+            UnmappedPolicy::Halt => "halt",
+        })
+    }
+}
+
+/**
+ * Routes memory accesses to devices mapped into the address space.
+ * Addresses that no device claims are left to RAM, parts of which may
+ * be read-only. Until RAM is added, all of memory is RAM; after that,
+ * addresses outside RAM, read-only memory, and devices are unmapped.
+ * Mirrors repeat devices and RAM through larger ranges, as partial
+ * address decoding does.
+ */
+pub struct Bus {
+    mappings: Vec<Mapping>,
+    read_only: Vec<ReadOnly>,
+    ram: Vec<(u16, u16)>,
+    mirrors: Vec<Mirror>,
+    unmapped_policy: UnmappedPolicy,
+}
+
+impl Bus {
+    pub fn new() -> Bus {
+        Bus {
+            mappings: Vec::new(),
+            read_only: Vec::new(),
+            ram: Vec::new(),
+            mirrors: Vec::new(),
+            unmapped_policy: UnmappedPolicy::OpenBus,
+        }
+    }
+
+    /**
+     * Maps a device at an address. Fails if its register window would
+     * overlap another device or run past the end of memory.
+     */
+    pub fn map(&mut self, start: u16, device: Box<dyn Device>) -> Result<(), String> {
+        self.insert(start, device, None)
+    }
+
+    /**
+     * Maps a device at the start of a mirror, repeating its registers
+     * through the mirror's range.
+     */
+    pub fn map_mirrored(&mut self, mirror: Mirror, device: Box<dyn Device>) -> Result<(), String> {
+        self.insert(mirror.start, device, Some(mirror))
+    }
+
+    fn insert(&mut self, start: u16, device: Box<dyn Device>, mirror: Option<Mirror>) -> Result<(), String> {
+        let size = device.size();
+        if size == 0 || start as u32 + size as u32 > 0x10000 {
+            return Err(format!("{} doesn't fit at ${:04X}", device.name(), start));
+        }
+        if let Some(m) = mirror {
+            if (m.end as u32) < start as u32 + size as u32 - 1 {
+                return Err(format!("mirror of {} must end after ${:04X}", device.name(), start));
+            }
+        }
+        let mapping = Mapping { start, device, mirror };
+        if let Some(other) = self.mappings.iter()
+            .find(|m| m.start <= mapping.end() && mapping.start <= m.end()) {
+            return Err(format!("{} at ${:04X} overlaps {} at ${:04X}-${:04X}",
+                mapping.device.name(), start, other.device.name(), other.start, other.end()));
+        }
+        self.mappings.push(mapping);
+        self.mappings.sort_by_key(|m| m.start);
+        Ok(())
+    }
+
+    /**
+     * Creates and maps the devices for a spec such as via@$6000. A
+     * mirror=END option repeats the device's registers through END, using
+     * the smallest mask that covers them unless mask=MASK is given. If one
+     * of the devices can't be mapped, none of them are.
+     */
+    pub fn map_spec(&mut self, spec: &str) -> Result<(), String> {
+        let mut spec = DeviceSpec::parse(spec)?;
+        let parse = |v: String| parse_address(&v).ok_or_else(|| format!("bad address {}", v));
+        let mirror_end = spec.options.remove("mirror").map(parse).transpose()?;
+        let mask = spec.options.remove("mask").map(parse).transpose()?;
+        if mask.is_some() && mirror_end.is_none() {
+            return Err(format!("{} has a mask but no mirror", spec.name));
+        }
+        let mut mapped = Vec::new();
+        for (start, device) in device::create_all(&spec)? {
+            let result = match mirror_end {
+                Some(end) if start == spec.address => {
+                    let mask = mask.unwrap_or(((device.size() as u32).next_power_of_two() - 1) as u16);
+                    Mirror::new(start, end, mask).and_then(|m| self.map_mirrored(m, device))
+                },
+                _ => self.map(start, device),
+            };
+            if let Err(e) = result {
+                self.mappings.retain(|m| !mapped.contains(&m.start));
+                return Err(e);
+            }
+            mapped.push(start);
+        }
+        Ok(())
+    }
+
+    /**
+     * Makes a range of RAM read-only. Where ranges overlap, the one added
+     * last decides the policy.
+     */
+    pub fn protect(&mut self, region: ReadOnly) {
+        self.read_only.push(region);
+    }
+
+    pub fn read_only(&self) -> impl Iterator<Item = &ReadOnly> {
+        self.read_only.iter()
+    }
+
+    /** Returns the write policy for an address, if it or the RAM it mirrors is read-only. */
+    pub fn write_policy(&self, address: u16) -> Option<WritePolicy> {
+        let address = self.resolve(address);
+        self.read_only.iter().rev()
+            .find(|r| address >= r.start && address <= r.end)
+            .map(|r| r.policy)
+    }
+
+    /** Adds a range of RAM, making addresses outside the memory map unmapped. */
+    pub fn add_ram(&mut self, start: u16, end: u16) {
+        self.ram.push((start, end));
+    }
+
+    pub fn ram(&self) -> impl Iterator<Item = &(u16, u16)> {
+        self.ram.iter()
+    }
+
+    /**
+     * Repeats the memory at the start of a range through the rest of it.
+     * Where mirrors overlap, the one added last is used.
+     */
+    pub fn add_mirror(&mut self, mirror: Mirror) {
+        self.mirrors.push(mirror);
+    }
+
+    pub fn mirrors(&self) -> impl Iterator<Item = &Mirror> {
+        self.mirrors.iter()
+    }
+
+    /** Returns the address of the memory an address mirrors, or the address itself. */
+    pub fn resolve(&self, address: u16) -> u16 {
+        self.mirrors.iter().rev()
+            .find(|m| m.contains(address))
+            .map_or(address, |m| m.resolve(address))
+    }
+
+    pub fn unmapped_policy(&self) -> UnmappedPolicy {
+        self.unmapped_policy
+    }
+
+    pub fn set_unmapped_policy(&mut self, policy: UnmappedPolicy) {
+        self.unmapped_policy = policy;
+    }
+
+    /** Returns true if RAM, read-only memory, or a device is at an address. */
+    pub fn is_mapped(&self, address: u16) -> bool {
+        let ram = self.resolve(address);
+        self.ram.is_empty()
+            || self.ram.iter().any(|(start, end)| ram >= *start && ram <= *end)
+            || self.write_policy(address).is_some()
+            || self.mappings.iter().any(|m| m.offset(address).is_some())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Mapping> {
+        self.mappings.iter()
+    }
+
+    /** Finds the device mapped at an address and the register it selects. */
+    fn find(&mut self, address: u16) -> Option<(&mut Mapping, u16)> {
+        self.mappings.iter_mut().find_map(|m| {
+            let offset = m.offset(address)?;
+            Some((m, offset))
+        })
+    }
+
+    /** Reads from the device mapped at an address, if there is one. */
+    pub fn get(&mut self, address: u16) -> Option<u8> {
+        self.find(address).map(|(m, offset)| m.device.get(offset))
+    }
+
+    /** Peeks at the device mapped at an address, without side effects. */
+    pub fn peek(&self, address: u16) -> Option<u8> {
+        self.mappings.iter().find_map(|m| m.offset(address).map(|offset| m.device.peek(offset)))
+    }
+
+    /** Returns true if the device mapped at an address can undo writes to it. */
+    pub fn undoes_writes(&self, address: u16) -> bool {
+        self.mappings.iter().any(|m| m.offset(address).is_some() && m.device.undoes_writes())
+    }
+
+    /**
+     * Writes to the device mapped at an address. Returns false if no
+     * device is mapped there.
+     */
+    pub fn set(&mut self, address: u16, value: u8) -> bool {
+        match self.find(address) {
+            Some((m, offset)) => {
+                m.device.set(offset, value);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u64) {
+        for m in self.mappings.iter_mut() {
+            m.device.tick(cycles);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for m in self.mappings.iter_mut() {
+            m.device.reset();
+        }
+    }
+
+    /**
+     * Removes the devices, so that they can be given the rest of the
+     * machine while they transfer data. Until replace_devices puts them
+     * back, their addresses are treated as if nothing were mapped there.
+     */
+    pub fn take_devices(&mut self) -> Vec<Mapping> {
+        std::mem::take(&mut self.mappings)
+    }
+
+    /** Puts back the devices removed by take_devices. */
+    pub fn replace_devices(&mut self, mappings: Vec<Mapping>) {
+        self.mappings = mappings;
+    }
+
+    /** Returns the exit code of the first device that asked the emulator to stop. */
+    pub fn exit_code(&self) -> Option<u8> {
+        self.mappings.iter().find_map(|m| m.device.exit_code())
+    }
+
+    /** Returns true if any device is asserting IRQ. */
+    pub fn irq(&self) -> bool {
+        self.mappings.iter().any(|m| m.device.irq())
+    }
+
+    /** Returns true if any device is asserting NMI. */
+    pub fn nmi(&self) -> bool {
+        self.mappings.iter().any(|m| m.device.nmi())
+    }
+
+    /** Saves the state of every device, in address order. */
+    pub fn save(&self) -> Vec<Vec<u8>> {
+        self.mappings.iter().map(|m| m.device.save()).collect()
+    }
+
+    /** Restores state returned by save. */
+    pub fn restore(&mut self, states: &[Vec<u8>]) {
+        for (m, state) in self.mappings.iter_mut().zip(states) {
+            m.device.restore(state);
+        }
+    }
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Bus::new()
+    }
+}
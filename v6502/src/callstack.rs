@@ -0,0 +1,119 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::cpu::Cpu;
+use crate::instruction::InstructionType;
+use crate::instruction::InstructionType::*;
+use crate::observer::Observer;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Frame {
+    /// Address of the subroutine or interrupt handler
+    pub entry: u16,
+    /// Address of the JSR or BRK instruction that entered the frame
+    pub call_site: u16,
+    /// Stack pointer before the call. The frame has returned once the
+    /// stack pointer is back at or above this value.
+    pub return_sp: u8,
+    pub interrupt: bool,
+}
+
+/**
+ * Tracks subroutine calls by watching JSR, BRK, RTS, and RTI instructions.
+ * Frames are ordered from outermost to innermost.
+ */
+pub struct CallStack {
+    pub frames: Vec<Frame>,
+    pending: Option<(u16, u8, InstructionType)>,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack {
+            frames: Vec::new(),
+            pending: None,
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    /** Returns the innermost frame. */
+    pub fn current(&self) -> Option<&Frame> {
+        self.frames.last()
+    }
+
+    /** Executes one instruction, updating the call stack. */
+    pub fn step(&mut self, cpu: &mut Cpu) {
+        let pc = cpu.pc;
+        let sp = cpu.sp;
+        let (i, _) = cpu.decode(pc);
+        cpu.execute_next_instruction();
+        self.update(pc, sp, i.t, cpu);
+    }
+
+    /**
+     * Updates the call stack after an instruction has executed.
+     * The pc and sp arguments are the values from before it executed.
+     */
+    pub fn update(&mut self, pc: u16, sp: u8, t: InstructionType, cpu: &Cpu) {
+        match t {
+            Jsr | Brk => self.frames.push(Frame {
+                entry: cpu.pc,
+                call_site: pc,
+                return_sp: sp,
+                interrupt: t == Brk,
+            }),
+            Rts | Rti | Txs => {
+                while let Some(frame) = self.frames.last() {
+                    if cpu.sp >= frame.return_sp {
+                        self.frames.pop();
+                    } else {
+                        break;
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+impl Observer for CallStack {
+    fn before(&mut self, cpu: &Cpu) {
+        let (i, _) = cpu.decode(cpu.pc);
+        self.pending = Some((cpu.pc, cpu.sp, i.t));
+    }
+
+    fn after(&mut self, cpu: &Cpu) {
+        if let Some((pc, sp, t)) = self.pending.take() {
+            self.update(pc, sp, t, cpu);
+        }
+    }
+}
+
+impl Default for CallStack {
+    fn default() -> Self {
+        CallStack::new()
+    }
+}
@@ -0,0 +1,185 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::cpu::Cpu;
+use crate::disassembler::disassemble_line;
+use crate::observer::Observer;
+use crate::source_map::SourceMap;
+
+/**
+ * Gaps between known instructions longer than this are skipped in the
+ * annotated disassembly, since they are most likely data.
+ */
+const MAX_GAP: u16 = 16;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct BranchCoverage {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+/** Records which instructions were executed and which way branches went. */
+pub struct Coverage {
+    pub executed: Vec<u64>,
+    pub branches: BTreeMap<u16, BranchCoverage>,
+    pending: Option<(u16, u16)>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            executed: vec![0; 0x10000],
+            branches: BTreeMap::new(),
+            pending: None,
+        }
+    }
+
+    pub fn is_executed(&self, address: u16) -> bool {
+        self.executed[address as usize] > 0
+    }
+
+    /**
+     * Produces a disassembly of the executed code and any code in the
+     * source map, with an execution count or ##### for code that never
+     * ran, and the taken and not taken counts for each branch.
+     */
+    pub fn annotated(&self, cpu: &Cpu, source_map: &SourceMap) -> String {
+        let known: BTreeSet<u16> = (0..0x10000usize)
+            .filter(|a| self.executed[*a] > 0)
+            .map(|a| a as u16)
+            .chain(source_map.iter().map(|(a, _)| *a))
+            .collect();
+        let mut out = String::new();
+        let mut next = known.iter().next().cloned();
+        while let Some(pc) = next {
+            let following = known.range(pc.saturating_add(1)..).next().cloned();
+            if !known.contains(&pc) {
+                match following {
+                    Some(f) if f - pc <= MAX_GAP => {},
+                    _ => {
+                        out.push_str("        ...\n");
+                        next = following;
+                        continue;
+                    },
+                }
+            }
+            if let Some(name) = cpu.symbols.name(pc) {
+                out.push_str(&format!("{:>8}  {}:\n", "", name));
+            }
+            let count = match self.executed[pc as usize] {
+                0 => "#####".to_string(),
+                n => n.to_string(),
+            };
+            let (line, len) = disassemble_line(cpu, pc);
+            out.push_str(&format!("{:>8}  {}", count, line));
+            if let Some(b) = self.branches.get(&pc) {
+                out.push_str(&format!("  ; taken {} not taken {}", b.taken, b.not_taken));
+                if b.taken == 0 || b.not_taken == 0 {
+                    out.push_str(" (partial)");
+                }
+            } else if cpu.decode(pc).0.t.is_branch() && self.executed[pc as usize] == 0 {
+                out.push_str("  ; branch never reached");
+            }
+            out.push('\n');
+            let end = pc.checked_add(len);
+            next = match (end, following) {
+                (Some(end), Some(f)) if end > f => Some(f),
+                (Some(end), _) if end <= known.iter().next_back().cloned().unwrap_or(0) => Some(end),
+                _ => None,
+            };
+        }
+        out
+    }
+
+    /**
+     * Produces coverage in lcov tracefile format, with line and branch
+     * counts for each source file in the source map.
+     */
+    pub fn lcov(&self, cpu: &Cpu, source_map: &SourceMap, test_name: &str) -> String {
+        let mut out = String::new();
+        for file in source_map.files() {
+            out.push_str(&format!("TN:{}\nSF:{}\n", test_name, file));
+            let mut lines: BTreeMap<usize, u64> = BTreeMap::new();
+            let mut branches: Vec<(usize, u16)> = Vec::new();
+            for (address, loc) in source_map.iter().filter(|(_, loc)| loc.file == file) {
+                let count = lines.entry(loc.line).or_insert(0);
+                *count = (*count).max(self.executed[*address as usize]);
+                if cpu.decode(*address).0.t.is_branch() {
+                    branches.push((loc.line, *address));
+                }
+            }
+            let (mut found, mut hit) = (0, 0);
+            for (block, (line, address)) in branches.iter().enumerate() {
+                let b = self.branches.get(address);
+                for (branch, count) in [b.map(|b| b.taken), b.map(|b| b.not_taken)].iter().enumerate() {
+                    let count = match count {
+                        Some(n) => {
+                            found += 1;
+                            if *n > 0 {
+                                hit += 1;
+                            }
+                            n.to_string()
+                        },
+                        None => {
+                            found += 1;
+                            "-".to_string()
+                        },
+                    };
+                    out.push_str(&format!("BRDA:{},{},{},{}\n", line, block, branch, count));
+                }
+            }
+            out.push_str(&format!("BRF:{}\nBRH:{}\n", found, hit));
+            for (line, count) in &lines {
+                out.push_str(&format!("DA:{},{}\n", line, count));
+            }
+            out.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n",
+                lines.len(), lines.values().filter(|c| **c > 0).count()));
+        }
+        out
+    }
+}
+
+impl Observer for Coverage {
+    fn before(&mut self, cpu: &Cpu) {
+        self.executed[cpu.pc as usize] += 1;
+        let (i, len) = cpu.decode(cpu.pc);
+        if i.t.is_branch() {
+            self.pending = Some((cpu.pc, cpu.pc.wrapping_add(len)));
+        }
+    }
+
+    fn after(&mut self, cpu: &Cpu) {
+        if let Some((pc, next)) = self.pending.take() {
+            let b = self.branches.entry(pc).or_default();
+            if cpu.pc == next {
+                b.not_taken += 1;
+            } else {
+                b.taken += 1;
+            }
+        }
+    }
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Coverage::new()
+    }
+}
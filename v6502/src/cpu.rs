@@ -21,21 +21,38 @@ use std::fmt;
 
 use crate::addressing::Addressing;
 use crate::addressing::Addressing::*;
+use crate::bus::{Bus, UnmappedPolicy, WritePolicy};
 use crate::device::Device;
 use crate::device::Rand;
 use crate::device::Terminal;
+use crate::history::{History, RunState};
 use crate::instruction::Instruction;
 use crate::instruction::InstructionType;
 use crate::instruction::InstructionType::*;
 use crate::opcodes::*;
-use crate::memory::Memory;
+use crate::memory::{Access, AccessKind, Memory, UnmappedAccess, WriteFault};
+use crate::symbols::Symbols;
 
 const MEMORY_SIZE: usize = 0x10000;
+const NMI_VECTOR: u16 = 0xFFFA;
 const IRQ_VECTOR: u16 = 0xFFFE;
 const RESET_VECTOR: u16 = 0xFFFC;
+/// Most writes to read-only memory the log policy records
+pub const MAX_WRITE_FAULTS: usize = 1000;
 
 const BRK: Instruction = Instruction {t: Brk, a: Implied };
 
+/** A snapshot of the CPU registers. */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Registers {
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sr: u8,
+    pub sp: u8,
+}
+
 pub struct Cpu {
     pub pc: u16,
     pub a: u8,
@@ -47,26 +64,107 @@ pub struct Cpu {
     pub opcodes: [Instruction; 256],
     pub rand: Rand,
     pub terminal: Terminal,
+    /// Devices mapped into memory in addition to the terminal and
+    /// random number generator
+    pub bus: Bus,
+    pub history: Option<History>,
+    pub symbols: Symbols,
+    /// Number of clock cycles executed since the CPU was created
+    pub cycles: u64,
+    /// Reads and writes made by the last instruction, not counting the
+    /// instruction fetch. Only recorded when set to Some.
+    pub accesses: Option<Vec<Access>>,
+    /// Set when a device asks the emulator to stop, which also sets the
+    /// break flag so that the program ends
+    pub exit_code: Option<u8>,
+    /// Writes to read-only memory with the log policy, up to MAX_WRITE_FAULTS
+    pub write_faults: Vec<WriteFault>,
+    /// Set when a write to read-only memory with the halt policy stops
+    /// the program, which also sets the break flag
+    pub fault: Option<WriteFault>,
+    /// Set when the program uses an unmapped address with the halt policy,
+    /// which also sets the break flag
+    pub unmapped_access: Option<UnmappedAccess>,
+    /// The last value read or written, which unmapped addresses return
+    pub data_bus: u8,
+    /// Address of the instruction being executed, until the devices have
+    /// transferred data after it. Read-only memory is only protected from
+    /// the program and devices, not from loaders and debuggers.
+    executing: Option<u16>,
+    /// NMI is edge triggered, so remember whether it was already asserted
+    nmi_asserted: bool,
 }
 
 impl Memory for Cpu {
     fn get(&mut self, addr: u16) -> u8 {
-        match addr {
+        if let Some(accesses) = &mut self.accesses {
+            accesses.push(Access { address: addr, kind: AccessKind::Read });
+        }
+        // The terminal and random number registers are in RAM's space, so RAM's mirrors repeat them
+        let value = match self.bus.resolve(addr) {
             0x00FDu16 => self.terminal.get(0),
             0x00FEu16 => self.terminal.get(1),
             0x00FFu16 => self.rand.get(0),
-            _ => self.memory[addr as usize],
+            _ => match self.bus.get(addr) {
+                Some(v) => v,
+                None if self.is_unmapped(addr, AccessKind::Read) => self.data_bus,
+                None => self.memory[self.bus.resolve(addr) as usize],
+            },
+        };
+        self.data_bus = value;
+        value
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match self.bus.resolve(addr) {
+            0x00FDu16 => self.terminal.peek(0),
+            0x00FEu16 => self.terminal.peek(1),
+            0x00FFu16 => self.rand.peek(0),
+            _ => match self.bus.peek(addr) {
+                Some(v) => v,
+                None if !self.bus.is_mapped(addr) => self.data_bus,
+                None => self.memory[self.bus.resolve(addr) as usize],
+            },
         }
     }
 
     fn set(&mut self, addr: u16, v: u8) {
-        match addr {
+        if let Some(accesses) = &mut self.accesses {
+            accesses.push(Access { address: addr, kind: AccessKind::Write });
+        }
+        self.data_bus = v;
+        match self.bus.resolve(addr) {
             0x00FDu16 => self.terminal.set(0, v),
             0x00FEu16 => self.terminal.set(1, v),
             0x00FFu16 => self.rand.set(0, v),
-            _ => self.memory[addr as usize] = v,
+            _ if self.write_device(addr, v) => {},
+            _ if self.is_unmapped(addr, AccessKind::Write) => {},
+            _ if self.is_write_protected(addr, v) => {},
+            _ => self.write_ram(addr, v),
+        }
+    }
+}
+
+/**
+ * Memory as a device sees it when it transfers data: RAM through its
+ * mirrors, with writes checked and recorded as the instruction's are.
+ */
+struct Dma<'a>(&'a mut Cpu);
+
+impl Memory for Dma<'_> {
+    fn get(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.0.memory[self.0.bus.resolve(addr) as usize]
+    }
+
+    fn set(&mut self, addr: u16, v: u8) {
+        let cpu = &mut *self.0;
+        if !cpu.is_unmapped(addr, AccessKind::Write) && !cpu.is_write_protected(addr, v) {
+            cpu.write_ram(addr, v);
         }
-        
     }
 }
 
@@ -87,6 +185,18 @@ impl Cpu {
             opcodes: [BRK; 256],
             rand: Rand::new(),
             terminal: Terminal::new(),
+            bus: Bus::new(),
+            history: None,
+            symbols: Symbols::new(),
+            cycles: 0,
+            accesses: None,
+            exit_code: None,
+            write_faults: Vec::new(),
+            fault: None,
+            unmapped_access: None,
+            data_bus: 0,
+            executing: None,
+            nmi_asserted: false,
         };
         cpu.load_opcodes(opcodes);
         cpu.reset();
@@ -113,9 +223,69 @@ impl Cpu {
         self.y = 0;
         self.sr = 0;
         self.sp = 0xFF;
+        self.terminal.reset();
+        self.rand.reset();
+        self.bus.reset();
+        self.exit_code = None;
+        self.write_faults.clear();
+        self.fault = None;
+        self.unmapped_access = None;
+        self.nmi_asserted = false;
         self.jump(Indirect(RESET_VECTOR));
     }
 
+    pub fn save_registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            sr: self.sr,
+            sp: self.sp,
+        }
+    }
+
+    pub fn restore_registers(&mut self, r: &Registers) {
+        self.pc = r.pc;
+        self.a = r.a;
+        self.x = r.x;
+        self.y = r.y;
+        self.sr = r.sr;
+        self.sp = r.sp;
+    }
+
+    /**
+     * Undoes the most recently executed instruction using the recorded
+     * history. Returns false if history is disabled or empty.
+     *
+     * Registers, RAM, the cycle count, and the reasons the program stopped
+     * are restored, as are banked RAM and the selected bank. Other devices
+     * are not: their registers and timers keep their current state, so
+     * running forward again may differ where the program reads a device.
+     */
+    pub fn undo(&mut self) -> bool {
+        let entry = match self.history.as_mut().and_then(|h| h.pop()) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        // Writes are undone in reverse order so that an address written
+        // twice by one instruction ends up with its original value.
+        for w in entry.writes.iter().rev() {
+            if w.device {
+                self.bus.set(w.address, w.old);
+            } else {
+                self.memory[w.address as usize] = w.old;
+            }
+        }
+        self.restore_registers(&entry.registers);
+        self.cycles = entry.state.cycles;
+        self.exit_code = entry.state.exit_code;
+        self.fault = entry.state.fault;
+        self.unmapped_access = entry.state.unmapped_access;
+        self.write_faults.truncate(entry.state.write_faults);
+        true
+    }
+
     pub fn get_status_bit(&self, bit: i8) -> bool {
         ((self.sr >> bit) & 1u8) == 1
     }
@@ -232,6 +402,14 @@ impl Cpu {
         &self.memory[0x0100..0x0200]
     }
 
+    /**
+     * Returns the values the program would read from a range of memory,
+     * including devices, without the side effects of reading them.
+     */
+    pub fn peek_range(&self, start: u16, len: usize) -> Vec<u8> {
+        (0..len).map(|i| self.peek(start.wrapping_add(i as u16))).collect()
+    }
+
     pub fn push(&mut self, v: u8) {
         self.set(0x0100 + self.sp as u16, v);
         self.sp = self.sp.overflowing_sub(1).0;
@@ -296,9 +474,214 @@ impl Cpu {
         }
     }
 
+    /**
+     * Decodes the instruction at the given address without executing it
+     * and without touching any devices. Returns the instruction and its
+     * length in bytes.
+     */
+    pub fn decode(&self, addr: u16) -> (Instruction, u16) {
+        let byte = |offset: u16| self.peek(addr.overflowing_add(offset).0);
+        let word = |offset: u16| (byte(offset + 1) as u16) << 8 | byte(offset) as u16;
+        let i = &self.opcodes[byte(0) as usize];
+        let (a, len) = match i.a {
+            Accumulator => (Accumulator, 1),
+            Absolute(_) => (Absolute(word(1)), 3),
+            AbsoluteX(_) => (AbsoluteX(word(1)), 3),
+            AbsoluteY(_) => (AbsoluteY(word(1)), 3),
+            Immediate(_) => (Immediate(byte(1)), 2),
+            Implied => (Implied, 1),
+            Indirect(_) => (Indirect(word(1)), 3),
+            IndirectX(_) => (IndirectX(byte(1)), 2),
+            IndirectY(_) => (IndirectY(byte(1)), 2),
+            Relative(_) => (Relative(byte(1) as i8), 2),
+            ZeroPage(_) => (ZeroPage(byte(1)), 2),
+            ZeroPageX(_) => (ZeroPageX(byte(1)), 2),
+            ZeroPageY(_) => (ZeroPageY(byte(1)), 2),
+        };
+        (Instruction { t: i.t, a }, len)
+    }
+
     pub fn execute_next_instruction(&mut self) {
+        if self.history.is_some() {
+            let registers = self.save_registers();
+            let state = RunState {
+                cycles: self.cycles,
+                exit_code: self.exit_code,
+                fault: self.fault,
+                unmapped_access: self.unmapped_access,
+                write_faults: self.write_faults.len(),
+            };
+            if let Some(history) = &mut self.history {
+                history.begin(registers, state);
+            }
+        }
+        self.executing = Some(self.pc);
         let i = self.next_instruction();
+        if let Some(accesses) = &mut self.accesses {
+            accesses.clear();
+        }
+        let next = self.pc;
+        let cycles = i.base_cycles() as u64 + self.page_cross_cycles(&i);
         self.execute(i);
+        let cycles = cycles + self.branch_cycles(&i, next);
+        self.cycles += cycles;
+        self.tick(cycles);
+        if let Some(history) = &mut self.history {
+            history.end();
+        }
+    }
+
+    /**
+     * Applies the write policy if the program writes to read-only memory.
+     * Returns true if the write was blocked.
+     */
+    fn is_write_protected(&mut self, address: u16, value: u8) -> bool {
+        let pc = match self.executing {
+            Some(pc) => pc,
+            None => return false,
+        };
+        let policy = match self.bus.write_policy(address) {
+            Some(policy) => policy,
+            None => return false,
+        };
+        let fault = WriteFault { pc, address, value };
+        match policy {
+            WritePolicy::Ignore => {},
+            WritePolicy::Log => {
+                if self.write_faults.len() < MAX_WRITE_FAULTS {
+                    self.write_faults.push(fault);
+                }
+            },
+            WritePolicy::Halt => {
+                // Report the first fault; the instruction may make more
+                if self.fault.is_none() {
+                    self.fault = Some(fault);
+                }
+            },
+        }
+        true
+    }
+
+    /**
+     * Applies the unmapped policy if the program uses an address that
+     * nothing is mapped at. Returns true if the address is unmapped.
+     */
+    fn is_unmapped(&mut self, address: u16, kind: AccessKind) -> bool {
+        let pc = match self.executing {
+            Some(pc) => pc,
+            None => return false,
+        };
+        if self.bus.is_mapped(address) {
+            return false;
+        }
+        if self.bus.unmapped_policy() == UnmappedPolicy::Halt && self.unmapped_access.is_none() {
+            self.unmapped_access = Some(UnmappedAccess { pc, address, kind });
+        }
+        true
+    }
+
+    /**
+     * Writes to the device mapped at an address, recording the write in
+     * history if the device can undo it. Returns false if no device is
+     * mapped there.
+     */
+    fn write_device(&mut self, addr: u16, v: u8) -> bool {
+        if let (Some(history), Some(old)) = (&mut self.history, self.bus.peek(addr)) {
+            if self.bus.undoes_writes(addr) {
+                history.record_device_write(addr, old, v);
+            }
+        }
+        self.bus.set(addr, v)
+    }
+
+    /** Writes to RAM through its mirrors, recording the write in history. */
+    fn write_ram(&mut self, addr: u16, v: u8) {
+        let addr = self.bus.resolve(addr);
+        if let Some(history) = &mut self.history {
+            history.record_write(addr, self.memory[addr as usize], v);
+        }
+        self.memory[addr as usize] = v;
+    }
+
+    /**
+     * Advances the devices by a number of cycles, lets them access
+     * memory, then services any interrupt they raised. Memory the devices
+     * write is protected as if the instruction that just ran wrote it.
+     */
+    pub fn tick(&mut self, cycles: u64) {
+        self.terminal.tick(cycles);
+        self.rand.tick(cycles);
+        self.bus.tick(cycles);
+        let mut devices = self.bus.take_devices();
+        for m in devices.iter_mut() {
+            m.device.transfer(&mut Dma(self));
+        }
+        self.bus.replace_devices(devices);
+        self.executing = None;
+        if let Some(code) = self.bus.exit_code() {
+            self.exit_code = Some(code);
+        }
+        if self.exit_code.is_some() || self.fault.is_some() || self.unmapped_access.is_some() {
+            self.set_break();
+            return;
+        }
+        let nmi = self.bus.nmi();
+        if nmi && !self.nmi_asserted {
+            self.interrupt(NMI_VECTOR);
+        } else if (self.terminal.irq() || self.bus.irq()) && !self.is_irq_disabled() {
+            self.interrupt(IRQ_VECTOR);
+        }
+        self.nmi_asserted = nmi;
+    }
+
+    /**
+     * Enters an interrupt handler. The status register is pushed with the
+     * break flag clear so the handler can tell it apart from BRK.
+     */
+    pub fn interrupt(&mut self, vector: u16) {
+        // Like BRK, push PC - 1 since RTI adds one
+        let pc = self.pc.overflowing_sub(1).0;
+        self.push((pc >> 8) as u8);
+        self.push(pc as u8);
+        self.push(self.sr & !0x10);
+        self.set_irq_disabled();
+        self.jump(Indirect(vector));
+        self.cycles += 7;
+    }
+
+    /**
+     * Returns the extra cycle taken by indexed reads that cross a page
+     * boundary. Must be called before the instruction is executed.
+     */
+    fn page_cross_cycles(&self, i: &Instruction) -> u64 {
+        if i.t.is_store() || i.t.is_read_modify_write() {
+            return 0;
+        }
+        let (base, index) = match i.a {
+            AbsoluteX(addr) => (addr, self.x),
+            AbsoluteY(addr) => (addr, self.y),
+            IndirectY(addr) => {
+                let low_byte = self.peek(addr as u16) as u16;
+                let high_byte = self.peek(addr.overflowing_add(1).0 as u16) as u16;
+                ((high_byte << 8) | low_byte, self.y)
+            },
+            _ => return 0,
+        };
+        if base & 0xFF00 != base.overflowing_add(index as u16).0 & 0xFF00 { 1 } else { 0 }
+    }
+
+    /**
+     * Returns the extra cycles taken by a branch: one if it was taken and
+     * another if the target is on a different page.
+     */
+    fn branch_cycles(&self, i: &Instruction, next: u16) -> u64 {
+        if !i.t.is_branch() || self.pc == next {
+            0
+        } else if self.pc & 0xFF00 != next & 0xFF00 {
+            2
+        } else {
+            1
+        }
     }
 
     pub fn execute(&mut self, i: Instruction) {
@@ -736,9 +1119,13 @@ impl Cpu {
     }
 
     fn registers(&self) -> String {
-        format!("A: {:02X} X: {:02X} Y: {:02X} PC: {:04X} SR: {:02X} SP: {:02X}",
-            self.a, self.x, self.y, self.pc, self.sr, self.sp)
-    } 
+        let pc = match self.symbols.nearest(self.pc) {
+            Some(_) => format!("{:04X} ({})", self.pc, self.symbols.describe(self.pc)),
+            None => format!("{:04X}", self.pc),
+        };
+        format!("A: {:02X} X: {:02X} Y: {:02X} PC: {} SR: {:02X} SP: {:02X}",
+            self.a, self.x, self.y, pc, self.sr, self.sp)
+    }
 
 }
 
@@ -746,14 +1133,14 @@ impl fmt::Debug for Cpu {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.write_fmt(format_args!("Registers: \n    {}\n", self.registers()))?;
         fmt.write_str("\nZero Page:\n")?;
-        Cpu::fmt_memory(self.zero_page(), 0x0000, fmt)?;
+        Cpu::fmt_memory(&self.peek_range(0x0000, 0x0100), 0x0000, fmt)?;
         fmt.write_str("\nStack:\n")?;
-        Cpu::fmt_memory(self.stack(), 0x0100, fmt)
+        Cpu::fmt_memory(&self.peek_range(0x0100, 0x0100), 0x0100, fmt)
     }
 }
 
 impl fmt::UpperHex for Cpu {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        Cpu::fmt_memory(&self.memory[..], 0x0000, fmt)
+        Cpu::fmt_memory(&self.peek_range(0x0000, MEMORY_SIZE), 0x0000, fmt)
     }
 }
\ No newline at end of file
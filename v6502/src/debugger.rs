@@ -0,0 +1,287 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::BTreeSet;
+
+use crate::cpu::Cpu;
+use crate::disassembler::{disassemble_line, disassemble_range};
+use crate::history::History;
+use crate::memory::Memory;
+use crate::symbols::Symbols;
+
+const HELP: &str = "\
+Commands:
+    step [n]              (s)   Execute n instructions (default 1)
+    continue              (c)   Run until a breakpoint or BRK
+    reverse-step [n]      (rs)  Undo n instructions (default 1)
+    reverse-continue      (rc)  Undo until the previous breakpoint
+    break <addr>          (b)   Set a breakpoint
+    delete <addr>         (d)   Remove a breakpoint
+    breakpoints           (bl)  List breakpoints
+    who-wrote <addr>      (w)   Show the last recorded write to an address
+    registers             (r)   Show registers
+    memory <addr> [len]   (m)   Dump memory
+    list [addr] [n]       (l)   Disassemble n instructions (default 10)
+    help                  (h)   Show this help
+    quit                  (q)   Exit the debugger
+Addresses may be written as $0432, 0x0432, 0432, or as a symbol such as SORT8 or SORT8+3.";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StopReason {
+    /// The requested number of instructions was executed or undone
+    Step,
+    /// Execution reached a breakpoint
+    Breakpoint(u16),
+    /// The program executed a BRK instruction
+    Break,
+    /// There is no more history to undo
+    HistoryExhausted,
+}
+
+pub struct Debugger {
+    pub breakpoints: BTreeSet<u16>,
+    finished: bool,
+}
+
+/** Parses an address written as $0432, 0x0432, or 0432. */
+pub fn parse_address(s: &str) -> Option<u16> {
+    let s = s.trim();
+    let hex = s.strip_prefix('$')
+        .or_else(|| s.strip_prefix("0x"))
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    u16::from_str_radix(hex, 16).ok()
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            finished: false,
+        }
+    }
+
+    /** Enables undo history on the CPU so that reverse execution works. */
+    pub fn attach(&self, cpu: &mut Cpu, history_size: usize) {
+        cpu.history = Some(History::new(history_size));
+    }
+
+    /** Returns true once the user has asked to quit. */
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    pub fn step(&mut self, cpu: &mut Cpu, count: usize) -> StopReason {
+        for _ in 0..count {
+            cpu.execute_next_instruction();
+            if cpu.is_break() {
+                return StopReason::Break;
+            }
+        }
+        StopReason::Step
+    }
+
+    /**
+     * Runs until a breakpoint is reached or the program executes BRK.
+     * At least one instruction is executed, so continuing from a
+     * breakpoint moves past it.
+     */
+    pub fn cont(&mut self, cpu: &mut Cpu) -> StopReason {
+        loop {
+            cpu.execute_next_instruction();
+            if cpu.is_break() {
+                return StopReason::Break;
+            }
+            if self.breakpoints.contains(&cpu.pc) {
+                return StopReason::Breakpoint(cpu.pc);
+            }
+        }
+    }
+
+    pub fn reverse_step(&mut self, cpu: &mut Cpu, count: usize) -> StopReason {
+        for _ in 0..count {
+            if !cpu.undo() {
+                return StopReason::HistoryExhausted;
+            }
+        }
+        StopReason::Step
+    }
+
+    /**
+     * Undoes instructions until the previous breakpoint is reached or
+     * the recorded history runs out.
+     */
+    pub fn reverse_continue(&mut self, cpu: &mut Cpu) -> StopReason {
+        loop {
+            if !cpu.undo() {
+                return StopReason::HistoryExhausted;
+            }
+            if self.breakpoints.contains(&cpu.pc) {
+                return StopReason::Breakpoint(cpu.pc);
+            }
+        }
+    }
+
+    /** Describes the last recorded write to an address. */
+    pub fn who_wrote(&self, cpu: &Cpu, addr: u16) -> String {
+        let history = match &cpu.history {
+            Some(history) => history,
+            None => return "History is not enabled".to_string(),
+        };
+        // History records the RAM a mirrored address resolves to
+        match history.last_write(cpu.bus.resolve(addr)) {
+            Some(w) => format!(
+                "${:04X} last written by instruction at ${:04X} ({}) {} instructions ago: ${:02X} -> ${:02X}",
+                addr, w.pc, cpu.symbols.describe(w.pc), w.steps_ago, w.old, w.new),
+            None => format!(
+                "No write to ${:04X} in the last {} instructions",
+                addr, history.len()),
+        }
+    }
+
+    fn location(&self, cpu: &Cpu) -> String {
+        let label = match cpu.symbols.nearest(cpu.pc) {
+            Some(_) => format!("{}:\n", cpu.symbols.describe(cpu.pc)),
+            None => String::new(),
+        };
+        format!("A: {:02X} X: {:02X} Y: {:02X} SR: {:02X} SP: {:02X}\n{}{}",
+            cpu.a, cpu.x, cpu.y, cpu.sr, cpu.sp, label, disassemble_line(cpu, cpu.pc).0)
+    }
+
+    fn report(&self, cpu: &Cpu, reason: StopReason) -> String {
+        let prefix = match reason {
+            StopReason::Step => String::new(),
+            StopReason::Breakpoint(addr) => format!("Breakpoint at ${:04X}\n", addr),
+            StopReason::Break => match (cpu.fault, cpu.unmapped_access, cpu.exit_code) {
+                (Some(fault), _, _) => format!("Program stopped by a {}\n", fault),
+                (None, Some(access), _) => format!("Program stopped by a {}\n", access),
+                (None, None, Some(code)) => format!("Program exited with code {}\n", code),
+                (None, None, None) => "Program executed BRK\n".to_string(),
+            },
+            StopReason::HistoryExhausted => "No more history\n".to_string(),
+        };
+        format!("{}{}", prefix, self.location(cpu))
+    }
+
+    fn memory(&self, cpu: &Cpu, addr: u16, len: usize) -> String {
+        let mut out = String::new();
+        for row in 0..len.div_ceil(16) {
+            let start = addr as usize + row * 16;
+            if start > 0xFFFF {
+                break;
+            }
+            out.push_str(&format!("{:04X} :", start));
+            for i in start..(start + 16).min(addr as usize + len).min(0x10000) {
+                out.push_str(&format!(" {:02X}", cpu.peek(i as u16)));
+            }
+            out.push('\n');
+        }
+        out.pop();
+        out
+    }
+
+    /**
+     * Executes a single debugger command and returns the text to display.
+     */
+    pub fn command(&mut self, cpu: &mut Cpu, line: &str) -> String {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let command = match args.first() {
+            Some(command) => *command,
+            None => return String::new(),
+        };
+        let count = |i: usize, default: usize| -> Result<usize, String> {
+            match args.get(i) {
+                Some(s) => s.parse().map_err(|_| format!("Invalid count: {}", s)),
+                None => Ok(default),
+            }
+        };
+        let address = |i: usize, symbols: &Symbols| -> Result<u16, String> {
+            match args.get(i) {
+                Some(s) => symbols.resolve(s).ok_or_else(|| format!("Invalid address: {}", s)),
+                None => Err(format!("{} requires an address", command)),
+            }
+        };
+        let result = match command {
+            "step" | "s" => count(1, 1).map(|n| {
+                let reason = self.step(cpu, n);
+                self.report(cpu, reason)
+            }),
+            "continue" | "c" => {
+                let reason = self.cont(cpu);
+                Ok(self.report(cpu, reason))
+            },
+            "reverse-step" | "rs" => count(1, 1).map(|n| {
+                let reason = self.reverse_step(cpu, n);
+                self.report(cpu, reason)
+            }),
+            "reverse-continue" | "rc" => {
+                let reason = self.reverse_continue(cpu);
+                Ok(self.report(cpu, reason))
+            },
+            "break" | "b" => address(1, &cpu.symbols).map(|addr| {
+                self.breakpoints.insert(addr);
+                format!("Breakpoint set at ${:04X}", addr)
+            }),
+            "delete" | "d" => address(1, &cpu.symbols).map(|addr| {
+                if self.breakpoints.remove(&addr) {
+                    format!("Breakpoint removed at ${:04X}", addr)
+                } else {
+                    format!("No breakpoint at ${:04X}", addr)
+                }
+            }),
+            "breakpoints" | "bl" => {
+                if self.breakpoints.is_empty() {
+                    Ok("No breakpoints".to_string())
+                } else {
+                    Ok(self.breakpoints.iter()
+                        .map(|addr| format!("${:04X}", addr))
+                        .collect::<Vec<String>>()
+                        .join("\n"))
+                }
+            },
+            "who-wrote" | "w" => address(1, &cpu.symbols).map(|addr| self.who_wrote(cpu, addr)),
+            "registers" | "r" => Ok(self.location(cpu)),
+            "memory" | "m" => address(1, &cpu.symbols).and_then(|addr| {
+                count(2, 16).map(|len| self.memory(cpu, addr, len))
+            }),
+            "list" | "l" => {
+                let addr = if args.len() > 1 { address(1, &cpu.symbols) } else { Ok(cpu.pc) };
+                addr.and_then(|addr| {
+                    count(2, 10).map(|n| disassemble_range(cpu, addr, n).join("\n"))
+                })
+            },
+            "help" | "h" | "?" => Ok(HELP.to_string()),
+            "quit" | "q" => {
+                self.finished = true;
+                Ok(String::new())
+            },
+            _ => Err(format!("Unknown command: {} (type help for a list)", command)),
+        };
+        match result {
+            Ok(s) => s,
+            Err(e) => e,
+        }
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
@@ -17,70 +17,576 @@
     along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryInto;
+use std::path::Path;
 use std::{io::{self, Read, Write}, time::{SystemTime, UNIX_EPOCH}};
- 
+
+use crate::debugger::parse_address;
 use crate::memory::Memory;
- 
- pub trait Device<T=Self>: Memory {
-    fn new() -> T;
-    fn name() -> String;
-    fn status() -> String;
+
+pub mod acia;
+pub mod bank;
+pub mod bitmap;
+pub mod block;
+pub mod exit;
+pub mod host;
+pub mod pia;
+pub mod riot;
+pub mod rtc;
+pub mod screen;
+pub mod semihost;
+pub mod timer;
+pub mod via;
+
+use acia::Acia;
+use bank::{BankKind, Banks};
+use bitmap::Bitmap;
+use block::Block;
+use exit::Exit;
+use host::{HostInput, HostStream, RawMode};
+use pia::Pia;
+use riot::Riot;
+use rtc::{Rtc, Source};
+use screen::TextScreen;
+use semihost::Semihost;
+use timer::Timer;
+use via::Via;
+
+/// Clock rate used for devices that keep time, in Hz
+pub const DEFAULT_CLOCK_HZ: u64 = 1_000_000;
+
+/**
+ * A memory mapped device. Addresses passed to get and set are offsets
+ * into the device's register window, starting at 0.
+ */
+pub trait Device: Memory {
+    fn name(&self) -> String;
+    fn status(&self) -> String;
+
+    /** Returns the number of addresses used by the device's registers. */
+    fn size(&self) -> u16;
+
+    /** Advances the device by a number of clock cycles. */
+    fn tick(&mut self, _cycles: u64) {}
+
+    /** Returns the device to its power on state. */
+    fn reset(&mut self) {}
+
+    /** Returns true while the device is asserting the IRQ line. */
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /** Returns true while the device is asserting the NMI line. */
+    fn nmi(&self) -> bool {
+        false
+    }
+
+    /** Returns the device's internal state so it can be restored later. */
+    fn save(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /** Restores state returned by save. */
+    fn restore(&mut self, _state: &[u8]) {}
+
+    /**
+     * Lets the device read and write RAM directly, for devices that take
+     * their parameters from memory. Called after each instruction. Writes
+     * go through mirrors and are checked and recorded in history like the
+     * instruction's own; other devices' registers can't be reached.
+     */
+    fn transfer(&mut self, _memory: &mut dyn Memory) {}
+
+    /**
+     * Returns true if writing a register's old value back undoes a write
+     * to it, as with plain memory. History then records writes to the
+     * device so that stepping back restores them.
+     */
+    fn undoes_writes(&self) -> bool {
+        false
+    }
+
+    /** Returns the exit code once the device has asked the emulator to stop. */
+    fn exit_code(&self) -> Option<u8> {
+        None
+    }
+}
+
+/**
+ * A device to create and where to map it, written as NAME@ADDRESS with
+ * optional comma separated key=value options, e.g. via@$6000.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceSpec {
+    pub name: String,
+    pub address: u16,
+    pub options: BTreeMap<String, String>,
+}
+
+impl DeviceSpec {
+    pub fn parse(spec: &str) -> Result<DeviceSpec, String> {
+        let mut parts = spec.split(',');
+        let device = parts.next().unwrap_or("");
+        let (name, address) = match device.find('@') {
+            Some(i) => (&device[..i], &device[i + 1..]),
+            None => return Err(format!("missing @ADDRESS in device {}", spec)),
+        };
+        let address = parse_address(address)
+            .ok_or_else(|| format!("bad address {} in device {}", address, spec))?;
+        let mut options = BTreeMap::new();
+        for option in parts {
+            let mut kv = option.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if !k.is_empty() => {
+                    options.insert(k.trim().to_string(), v.trim().to_string());
+                },
+                _ => return Err(format!("bad option {} in device {}", option, spec)),
+            }
+        }
+        Ok(DeviceSpec { name: name.trim().to_lowercase(), address, options })
+    }
+
+    /** Fails if any option is not one the device understands. */
+    pub fn check_options(&self, known: &[&str]) -> Result<(), String> {
+        match self.options.keys().find(|k| !known.contains(&k.as_str())) {
+            Some(k) => Err(format!("{} has no option {}", self.name, k)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn option(&self, key: &str) -> Option<&str> {
+        self.options.get(key).map(|v| v.as_str())
+    }
+
+    /** Returns the CPU clock rate given with clock=HZ, or the default. */
+    pub fn clock(&self) -> Result<u64, String> {
+        match self.option("clock") {
+            Some(v) => v.parse().ok().filter(|hz| *hz > 0)
+                .ok_or_else(|| format!("bad clock rate {}", v)),
+            None => Ok(DEFAULT_CLOCK_HZ),
+        }
+    }
+
+    /**
+     * Returns true if the device would read the host's standard input or
+     * write its standard output, as a serial port on stdio or an ANSI
+     * text display does.
+     */
+    pub fn uses_stdio(&self) -> bool {
+        let serial = match self.name.as_str() {
+            "acia" | "6551" => true,
+            "pia" | "6520" | "6821" => !self.options.is_empty(),
+            "text" => return self.option("display") == Some("ansi"),
+            _ => false,
+        };
+        serial && match (self.option("in"), self.option("out")) {
+            (None, None) => matches!(self.option("port").unwrap_or("stdio"), "stdio" | "-"),
+            (input, output) => input.unwrap_or("-") == "-" || output.unwrap_or("-") == "-",
+        }
+    }
+
+    /**
+     * Opens the host stream for a serial device from the port option
+     * (stdio, pty, null, or a path), or separate in and out paths.
+     */
+    pub fn host_stream(&self) -> Result<HostStream, String> {
+        let result = match (self.option("in"), self.option("out")) {
+            (None, None) => HostStream::open(self.option("port").unwrap_or("stdio")),
+            (input, output) => HostStream::open_files(input.unwrap_or("-"), output.unwrap_or("-")),
+        };
+        result.map_err(|e| format!("couldn't open serial port for {}: {}", self.name, e))
+    }
+}
+
+/** Parses a seed written in decimal or as hex with a $ or 0x prefix. */
+pub fn parse_seed(s: &str) -> Option<u32> {
+    let s = s.trim();
+    match s.strip_prefix('$').or_else(|| s.strip_prefix("0x")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/** Parses a screen dimension option, from 1 to 255. */
+fn dimension(spec: &DeviceSpec, key: &str, default: u8) -> Result<u8, String> {
+    match spec.option(key) {
+        Some(v) => v.parse().ok().filter(|n| *n > 0).ok_or_else(|| format!("bad {} {}", key, v)),
+        None => Ok(default),
+    }
 }
 
+/// Devices and the addresses to map them at
+pub type Placements = Vec<(u16, Box<dyn Device>)>;
+
+/**
+ * Creates the devices for a spec along with the addresses to map them
+ * at. Most specs make one device, but a bank window can have its select
+ * register somewhere else.
+ */
+pub fn create_all(spec: &DeviceSpec) -> Result<Placements, String> {
+    match spec.name.as_str() {
+        "bank" => create_banks(spec),
+        _ => Ok(vec![(spec.address, create(spec)?)]),
+    }
+}
+
+fn create_banks(spec: &DeviceSpec) -> Result<Placements, String> {
+    spec.check_options(&["size", "banks", "type", "file", "select"])?;
+    let size = match spec.option("size") {
+        Some(v) => parse_seed(v).filter(|n| *n > 0 && *n <= 0xFFFF)
+            .ok_or_else(|| format!("bad bank size {}", v))? as u16,
+        None => 0x4000,
+    };
+    let count = match spec.option("banks") {
+        Some(v) => Some(v.parse().ok().filter(|n| *n > 0 && *n <= bank::MAX_BANKS)
+            .ok_or_else(|| format!("bad bank count {}", v))?),
+        None => None,
+    };
+    let kind = match spec.option("type").unwrap_or("ram") {
+        "ram" => BankKind::Ram,
+        "rom" => BankKind::Rom,
+        other => return Err(format!("unknown bank type {}", other)),
+    };
+    let banks = match spec.option("file") {
+        Some(path) => bank::load(kind, path, size, count)?,
+        None if kind == BankKind::Rom => return Err("ROM banks need a file option".to_string()),
+        None => Banks::new(kind, count.unwrap_or(2), size),
+    };
+    let (window, select) = bank::banked(banks);
+    match spec.option("select") {
+        Some(v) => {
+            let address = parse_address(v).ok_or_else(|| format!("bad select address {}", v))?;
+            Ok(vec![(spec.address, Box::new(window)), (address, Box::new(select))])
+        },
+        None if kind == BankKind::Rom => Ok(vec![(spec.address, Box::new(window.select_on_write()))]),
+        None => Err("RAM banks need a select address".to_string()),
+    }
+}
+
+/** Creates the device named by a spec. */
+pub fn create(spec: &DeviceSpec) -> Result<Box<dyn Device>, String> {
+    match spec.name.as_str() {
+        "via" | "6522" => {
+            spec.check_options(&[])?;
+            Ok(Box::new(Via::new()))
+        },
+        "acia" | "6551" => {
+            spec.check_options(&["port", "in", "out", "clock"])?;
+            Ok(Box::new(Acia::new(spec.host_stream()?, spec.clock()?)))
+        },
+        "block" => {
+            spec.check_options(&["file", "block_size", "blocks"])?;
+            let file = spec.option("file").ok_or("block needs a file option")?;
+            let block_size = match spec.option("block_size") {
+                Some(v) => v.parse().ok().filter(|n: &u16| n.is_power_of_two() && (128..=4096).contains(n))
+                    .ok_or_else(|| format!("bad block size {}", v))?,
+                None => block::DEFAULT_BLOCK_SIZE,
+            };
+            let blocks = match spec.option("blocks") {
+                Some(v) => Some(v.parse().map_err(|_| format!("bad block count {}", v))?),
+                None => None,
+            };
+            let device = Block::open(file, block_size, blocks)
+                .map_err(|e| format!("couldn't open disk image {}: {}", file, e))?;
+            Ok(Box::new(device))
+        },
+        "rand" => {
+            spec.check_options(&["seed", "generator"])?;
+            let seed = match spec.option("seed") {
+                Some(v) => parse_seed(v).ok_or_else(|| format!("bad seed {}", v))?,
+                None => Rand::new().seed(),
+            };
+            let name = spec.option("generator").unwrap_or("xorshift");
+            let generator = generator(name).ok_or_else(|| format!("unknown generator {}", name))?;
+            Ok(Box::new(Rand::with_generator(seed, generator).with_size(4)))
+        },
+        "timer" => {
+            spec.check_options(&[])?;
+            Ok(Box::new(Timer::new()))
+        },
+        "rtc" => {
+            spec.check_options(&["source", "start", "clock"])?;
+            let source = match spec.option("source").unwrap_or("host") {
+                "host" if spec.option("start").is_none() => Source::Host,
+                "host" => return Err("start needs source=virtual".to_string()),
+                "virtual" => {
+                    let start = match spec.option("start") {
+                        Some(v) => rtc::parse_time(v).ok_or_else(|| format!("bad start time {}", v))?,
+                        None => rtc::DEFAULT_START,
+                    };
+                    Source::Virtual { start, clock: spec.clock()? }
+                },
+                other => return Err(format!("unknown clock source {}", other)),
+            };
+            Ok(Box::new(Rtc::new(source)?))
+        },
+        "text" => {
+            spec.check_options(&["columns", "rows", "display", "snapshot", "clock"])?;
+            let columns = dimension(spec, "columns", 40)?;
+            let rows = dimension(spec, "rows", 25)?;
+            let mut screen = TextScreen::new(columns, rows);
+            match spec.option("display").unwrap_or("none") {
+                "none" => {},
+                // Redraw at most 60 times a second
+                "ansi" => screen = screen.with_output(Box::new(io::stdout()), spec.clock()? / 60),
+                other => return Err(format!("unknown display {}", other)),
+            }
+            if let Some(path) = spec.option("snapshot") {
+                screen = screen.with_snapshot_path(path);
+            }
+            Ok(Box::new(screen))
+        },
+        "bitmap" => {
+            spec.check_options(&["width", "height", "bits", "fps", "clock", "dump", "every"])?;
+            let number = |key: &str, default: u64| match spec.option(key) {
+                Some(v) => v.parse::<u64>().ok().filter(|n| *n > 0).ok_or_else(|| format!("bad {} {}", key, v)),
+                None => Ok(default),
+            };
+            let width = number("width", 128)?.min(u16::MAX as u64) as u16;
+            let height = number("height", 128)?.min(u16::MAX as u64) as u16;
+            let bits = number("bits", 1)?.min(u8::MAX as u64) as u8;
+            let fps = number("fps", 60)?;
+            let mut bitmap = Bitmap::new(width, height, bits)?.with_frame_cycles((spec.clock()? / fps).max(1));
+            if let Some(path) = spec.option("dump") {
+                bitmap = bitmap.with_dump(path, number("every", 1)?);
+            }
+            Ok(Box::new(bitmap))
+        },
+        "exit" => {
+            spec.check_options(&[])?;
+            Ok(Box::new(Exit::new()))
+        },
+        "semihost" => {
+            spec.check_options(&["root"])?;
+            let root = spec.option("root").unwrap_or(".");
+            let device = Semihost::new(Path::new(root))
+                .map_err(|e| format!("couldn't use {} for semihosting: {}", root, e))?;
+            Ok(Box::new(device))
+        },
+        "riot" | "6532" => {
+            spec.check_options(&[])?;
+            Ok(Box::new(Riot::new()))
+        },
+        "pia" | "6520" | "6821" => {
+            spec.check_options(&["port", "in", "out"])?;
+            if spec.options.is_empty() {
+                Ok(Box::new(Pia::new()))
+            } else {
+                Ok(Box::new(Pia::with_terminal(spec.host_stream()?)))
+            }
+        },
+        _ => Err(format!("unknown device {}", spec.name)),
+    }
+}
+
+// Terminal status register bits
+pub const TERMINAL_READ: u8 = 0x01;
+pub const TERMINAL_KEY_AVAILABLE: u8 = 0x80;
+// Terminal control register bits
+pub const TERMINAL_IRQ_ENABLE: u8 = 0x01;
+
+/**
+ * A simple terminal with two registers. Reading the data register
+ * returns the next key, or 0 if there is none, without waiting. The
+ * status register has TERMINAL_READ set if the last read returned a key
+ * and TERMINAL_KEY_AVAILABLE set while a key is waiting. Writing the
+ * status register sets the control register, which can enable an IRQ
+ * while a key is waiting.
+ *
+ * Input is read on a background thread that is started the first time
+ * the program needs it, so stdin is left alone for programs that never
+ * read the terminal.
+ */
 pub struct Terminal {
-    last_bytes_read: usize,
-    pub input: Box<dyn Read>,
+    last_read: bool,
+    control: u8,
+    input: Option<Box<dyn Read + Send>>,
+    reader: Option<HostInput>,
+    keys: VecDeque<u8>,
     pub output: Box<dyn Write>,
+    raw_mode: Option<RawMode>,
+}
+
+impl Terminal {
+    pub fn new() -> Terminal {
+        Terminal {
+            last_read: false,
+            control: 0,
+            input: Some(Box::new(io::stdin())),
+            reader: None,
+            keys: VecDeque::new(),
+            output: Box::new(io::stdout()),
+            raw_mode: None,
+        }
+    }
+
+    /** Replaces the input stream. Keys already received are kept. */
+    pub fn set_input(&mut self, input: Box<dyn Read + Send>) {
+        self.input = Some(input);
+        self.reader = None;
+    }
+
+    /**
+     * Puts the host terminal into raw mode so that keys are received
+     * as soon as they are typed, without echo. The original mode is
+     * restored when the terminal is dropped or by restore_mode.
+     */
+    pub fn set_raw_mode(&mut self) -> io::Result<()> {
+        if self.raw_mode.is_none() {
+            self.raw_mode = Some(RawMode::enable()?);
+        }
+        Ok(())
+    }
+
+    pub fn restore_mode(&mut self) {
+        self.raw_mode = None;
+    }
+
+    /** Moves keys from the background reader into the queue. */
+    fn poll(&mut self) {
+        if self.reader.is_none() {
+            if let Some(input) = self.input.take() {
+                self.reader = Some(HostInput::spawn(input));
+            }
+        }
+        if let Some(reader) = &mut self.reader {
+            while let Some(b) = reader.try_read() {
+                self.keys.push_back(b);
+            }
+        }
+    }
+
+    /** Queues a key as if it had been typed. */
+    pub fn receive(&mut self, key: u8) {
+        self.keys.push_back(key);
+    }
+}
+
+impl Default for Terminal {
+    fn default() -> Self {
+        Terminal::new()
+    }
 }
 
 impl Memory for Terminal {
     fn get(&mut self, address: u16) -> u8 {
-        let mut buf = [0];
+        self.poll();
         match address {
             0 => {
-                self.last_bytes_read = self.input.read(&mut buf).unwrap();
-                if self.last_bytes_read == 0 {
-                    0
-                } else {
-                    buf[0]
+                let key = self.keys.pop_front();
+                self.last_read = key.is_some();
+                key.unwrap_or(0)
+            },
+            _ => self.peek(address),
+        }
+    }
+
+    /** Shows the next key without taking it from the queue. */
+    fn peek(&self, address: u16) -> u8 {
+        match address {
+            0 => self.keys.front().copied().unwrap_or(0),
+            1 => {
+                let mut status = 0;
+                if self.last_read {
+                    status |= TERMINAL_READ;
+                }
+                if !self.keys.is_empty() {
+                    status |= TERMINAL_KEY_AVAILABLE;
                 }
+                status
             },
-            1 => self.last_bytes_read as u8,
             _ => 0,
         }
     }
 
     fn set(&mut self, address: u16, value: u8) {
         match address {
-            0 => self.output.write(&[value]).unwrap(),
-            _ => 0,
-        };
+            0 => {
+                self.output.write_all(&[value]).unwrap();
+                self.output.flush().unwrap();
+            },
+            1 => self.control = value,
+            _ => {},
+        }
     }
 }
 
-impl Device for Terminal{
-    fn new() -> Self {
-        Terminal { 
-            last_bytes_read: 0,
-            input: Box::new(io::stdin()),
-            output: Box::new(io::stdout()),
+impl Device for Terminal {
+    fn name(&self) -> String {
+        "Terminal".to_string()
+    }
+
+    fn status(&self) -> String {
+        format!("Keys waiting: {}", self.keys.len())
+    }
+
+    fn size(&self) -> u16 {
+        2
+    }
+
+    fn tick(&mut self, _cycles: u64) {
+        if self.control & TERMINAL_IRQ_ENABLE != 0 {
+            self.poll();
         }
     }
 
-    fn name() -> String {
-        format!("Terminal")
+    fn reset(&mut self) {
+        self.last_read = false;
+        self.control = 0;
+    }
+
+    fn irq(&self) -> bool {
+        self.control & TERMINAL_IRQ_ENABLE != 0 && !self.keys.is_empty()
     }
 
-    fn status() -> String {
-        format!("Normal")
+    fn save(&self) -> Vec<u8> {
+        vec![self.last_read as u8, self.control]
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        if let [last_read, control] = state {
+            self.last_read = *last_read != 0;
+            self.control = *control;
+        }
     }
 }
 
-pub struct Rand {
+/** An algorithm that produces a stream of pseudo random numbers. */
+pub trait Generator {
+    fn name(&self) -> String;
+    fn seed(&mut self, seed: u32);
+    fn next(&mut self) -> u32;
+    fn save(&self) -> Vec<u8>;
+    fn restore(&mut self, state: &[u8]);
+}
+
+/** Looks up a generator by name: xorshift (the default) or lcg. */
+pub fn generator(name: &str) -> Option<Box<dyn Generator>> {
+    match name {
+        "xorshift" => Some(Box::new(Xorshift::new(0))),
+        "lcg" => Some(Box::new(Lcg { state: 0 })),
+        _ => None,
+    }
+}
+
+fn words(state: &[u8]) -> Vec<u32> {
+    state.chunks_exact(4)
+        .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+        .collect()
+}
+
+fn bytes(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect()
+}
+
+/** Marsaglia's xorshift128. */
+pub struct Xorshift {
     w: u32,
-    x: u32, 
-    y: u32, 
-    z: u32, 
+    x: u32,
+    y: u32,
+    z: u32,
 }
 
 const KX: u32 = 123456789;
@@ -88,25 +594,104 @@ const KY: u32 = 362436069;
 const KZ: u32 = 521288629;
 const KW: u32 = 88675123;
 
-impl Rand {
+impl Xorshift {
+    pub fn new(seed: u32) -> Xorshift {
+        Xorshift {
+            x: KX^seed, y: KY^seed,
+            z: KZ, w: KW
+        }
+    }
+}
+
+impl Generator for Xorshift {
+    fn name(&self) -> String {
+        "xorshift".to_string()
+    }
+
+    fn seed(&mut self, seed: u32) {
+        *self = Xorshift::new(seed);
+    }
+
     // Borrowed from Wikipedia
-    pub fn rand(&mut self) -> u32 {
+    fn next(&mut self) -> u32 {
         let t = self.x^self.x.wrapping_shl(11);
         self.x = self.y; self.y = self.z; self.z = self.w;
         self.w ^= self.w.wrapping_shr(19)^t^t.wrapping_shr(8);
-        return self.w;
+        self.w
     }
 
-    pub fn from_seed(seed: u32) -> Rand {
-        Rand{
-            x: KX^seed, y: KY^seed,
-            z: KZ, w: KW
+    fn save(&self) -> Vec<u8> {
+        bytes(&[self.w, self.x, self.y, self.z])
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        if let [w, x, y, z] = words(state)[..] {
+            self.w = w;
+            self.x = x;
+            self.y = y;
+            self.z = z;
         }
     }
 }
 
-impl Device for Rand {
-    fn new() -> Self {
+/**
+ * The linear congruential generator from Numerical Recipes. Only the
+ * high bits of each step are used since the low bits repeat quickly.
+ */
+pub struct Lcg {
+    state: u32,
+}
+
+impl Lcg {
+    fn step(&mut self) -> u32 {
+        self.state = self.state.wrapping_mul(1664525).wrapping_add(1013904223);
+        self.state >> 16
+    }
+}
+
+impl Generator for Lcg {
+    fn name(&self) -> String {
+        "lcg".to_string()
+    }
+
+    fn seed(&mut self, seed: u32) {
+        self.state = seed;
+    }
+
+    fn next(&mut self) -> u32 {
+        (self.step() << 16) | self.step()
+    }
+
+    fn save(&self) -> Vec<u8> {
+        bytes(&[self.state])
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        if let [s] = words(state)[..] {
+            self.state = s;
+        }
+    }
+}
+
+/**
+ * A random number device. Reading register 0 returns a random byte and
+ * latches a second one, which register 1 returns, so two reads give a
+ * 16-bit number. Registers 0 to 3 can be written with a new seed, least
+ * significant byte first; writing register 3 reseeds the generator.
+ *
+ * The built in device at $FF only has register 0.
+ */
+pub struct Rand {
+    seed: u32,
+    generator: Box<dyn Generator>,
+    high: u8,
+    seed_register: [u8; 4],
+    size: u16,
+}
+
+impl Rand {
+    /** Creates a generator seeded from the current time. */
+    pub fn new() -> Rand {
         let now = SystemTime::now();
         match now.duration_since(UNIX_EPOCH) {
             Ok(duration) => Rand::from_seed(duration.as_millis() as u32),
@@ -114,22 +699,109 @@ impl Device for Rand {
         }
     }
 
-    fn name() -> String {
+    pub fn from_seed(seed: u32) -> Rand {
+        Rand::with_generator(seed, Box::new(Xorshift::new(seed)))
+    }
+
+    pub fn with_generator(seed: u32, mut generator: Box<dyn Generator>) -> Rand {
+        generator.seed(seed);
+        Rand {
+            seed,
+            generator,
+            high: 0,
+            seed_register: seed.to_le_bytes(),
+            size: 1,
+        }
+    }
+
+    /** Sets the number of registers visible when the device is mapped. */
+    pub fn with_size(mut self, size: u16) -> Rand {
+        self.size = size;
+        self
+    }
+
+    pub fn rand(&mut self) -> u32 {
+        self.generator.next()
+    }
+
+    /** Returns the seed the generator was last started from. */
+    pub fn seed(&self) -> u32 {
+        self.seed
+    }
+
+    /** Restarts the generator from a seed. */
+    pub fn reseed(&mut self, seed: u32) {
+        self.seed = seed;
+        self.seed_register = seed.to_le_bytes();
+        self.generator.seed(seed);
+    }
+}
+
+impl Default for Rand {
+    fn default() -> Self {
+        Rand::new()
+    }
+}
+
+impl Device for Rand {
+    fn name(&self) -> String {
         "Random Number Generator".to_string()
     }
 
-    fn status() -> String {
-        "Normal".to_string()
+    fn status(&self) -> String {
+        format!("Seed: {} ({})", self.seed, self.generator.name())
+    }
+
+    fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut state = self.seed.to_le_bytes().to_vec();
+        state.push(self.high);
+        state.extend_from_slice(&self.seed_register);
+        state.extend(self.generator.save());
+        state
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        if state.len() < 9 {
+            return;
+        }
+        self.seed = u32::from_le_bytes(state[..4].try_into().unwrap());
+        self.high = state[4];
+        self.seed_register.copy_from_slice(&state[5..9]);
+        self.generator.restore(&state[9..]);
     }
 }
 
 impl Memory for Rand {
-    fn get(&mut self, _: u16) -> u8 {
-        self.rand() as u8
+    fn get(&mut self, address: u16) -> u8 {
+        match address {
+            0 => {
+                let value = self.rand();
+                self.high = (value >> 8) as u8;
+                value as u8
+            },
+            1 => self.high,
+            _ => 0,
+        }
     }
 
-    fn set(&mut self, _: u16, _: u8) {
+    /** Register 0 reads as 0, since showing the next number would use it up. */
+    fn peek(&self, address: u16) -> u8 {
+        match address {
+            1 => self.high,
+            _ => 0,
+        }
+    }
 
+    fn set(&mut self, address: u16, value: u8) {
+        if let Some(b) = self.seed_register.get_mut(address as usize) {
+            *b = value;
+        }
+        if address == 3 {
+            self.reseed(u32::from_le_bytes(self.seed_register));
+        }
     }
-    
-}
\ No newline at end of file
+}
@@ -0,0 +1,274 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+use std::io::Write;
+
+use crate::device::Device;
+use crate::device::host::{HostInput, HostStream};
+use crate::memory::Memory;
+
+// Register offsets
+const DATA: u16 = 0;
+const STATUS: u16 = 1;
+const COMMAND: u16 = 2;
+const CONTROL: u16 = 3;
+
+// Status register bits
+pub const STATUS_PARITY: u8 = 0x01;
+pub const STATUS_FRAMING: u8 = 0x02;
+pub const STATUS_OVERRUN: u8 = 0x04;
+pub const STATUS_RDRF: u8 = 0x08;
+pub const STATUS_TDRE: u8 = 0x10;
+pub const STATUS_IRQ: u8 = 0x80;
+
+/// Baud rates selected by the low four bits of the control register.
+/// 0 selects the external clock, which is treated as 115200 baud.
+const BAUD_RATES: [f64; 16] = [115200.0, 50.0, 75.0, 109.92, 134.58, 150.0, 300.0, 600.0,
+    1200.0, 1800.0, 2400.0, 3600.0, 4800.0, 7200.0, 9600.0, 19200.0];
+
+/**
+ * A MOS 6551 Asynchronous Communications Interface Adapter. Characters
+ * take as long to send and receive as they would at the programmed baud
+ * rate, based on the CPU clock rate.
+ */
+pub struct Acia {
+    input: Option<HostInput>,
+    output: Box<dyn Write + Send>,
+    description: String,
+    /// Bytes that have arrived from the host but not yet been received
+    rx_queue: VecDeque<u8>,
+    rx_remaining: Option<u64>,
+    rdr: u8,
+    tdr: Option<u8>,
+    /// Byte being transmitted and the cycles until it is sent
+    tx_shift: Option<(u8, u64)>,
+    status: u8,
+    command: u8,
+    control: u8,
+    clock: u64,
+}
+
+impl Acia {
+    /** Creates an ACIA connected to a host stream, for a CPU running at clock Hz. */
+    pub fn new(stream: HostStream, clock: u64) -> Acia {
+        Acia {
+            input: Some(HostInput::spawn(stream.input)),
+            output: stream.output,
+            description: stream.description,
+            rx_queue: VecDeque::new(),
+            rx_remaining: None,
+            rdr: 0,
+            tdr: None,
+            tx_shift: None,
+            status: STATUS_TDRE,
+            command: 0x02,
+            control: 0,
+            clock,
+        }
+    }
+
+    /** Queues a byte as if it had arrived on the serial line. */
+    pub fn receive(&mut self, byte: u8) {
+        self.rx_queue.push_back(byte);
+    }
+
+    /** Returns the number of CPU cycles needed to send one character. */
+    pub fn char_cycles(&self) -> u64 {
+        let data_bits = 8 - ((self.control >> 5) & 0x03) as u32;
+        let parity = self.command & 0x20 != 0;
+        let stop_bits = match (self.control & 0x80 != 0, data_bits, parity) {
+            (false, _, _) => 1.0,
+            (true, 8, true) => 1.0,
+            (true, 5, false) => 1.5,
+            (true, _, _) => 2.0,
+        };
+        let bits = 1.0 + data_bits as f64 + if parity { 1.0 } else { 0.0 } + stop_bits;
+        let baud = BAUD_RATES[(self.control & 0x0F) as usize];
+        ((self.clock as f64 * bits / baud) as u64).max(1)
+    }
+
+    /// DTR enables the receiver and interrupts
+    fn is_ready(&self) -> bool {
+        self.command & 0x01 != 0
+    }
+
+    fn rx_irq_enabled(&self) -> bool {
+        self.is_ready() && self.command & 0x02 == 0
+    }
+
+    fn tx_irq_enabled(&self) -> bool {
+        self.is_ready() && self.command & 0x0C == 0x04
+    }
+
+    fn send(&mut self, byte: u8) {
+        // A closed host stream just drops output, like an unplugged cable
+        let _ = self.output.write_all(&[byte]).and_then(|_| self.output.flush());
+    }
+
+    fn deliver(&mut self, byte: u8) {
+        if self.status & STATUS_RDRF != 0 {
+            self.status |= STATUS_OVERRUN;
+        } else {
+            self.rdr = byte;
+            self.status |= STATUS_RDRF;
+        }
+        if self.rx_irq_enabled() {
+            self.status |= STATUS_IRQ;
+        }
+        // Echo mode retransmits received characters
+        if self.command & 0x1C == 0x10 {
+            self.send(byte);
+        }
+    }
+}
+
+impl Memory for Acia {
+    fn get(&mut self, address: u16) -> u8 {
+        let value = self.peek(address);
+        match address & 0x03 {
+            DATA => self.status &= !(STATUS_RDRF | STATUS_OVERRUN | STATUS_FRAMING | STATUS_PARITY),
+            STATUS => self.status &= !STATUS_IRQ,
+            _ => {},
+        }
+        value
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        match address & 0x03 {
+            DATA => self.rdr,
+            STATUS => self.status,
+            COMMAND => self.command,
+            _ => self.control,
+        }
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        match address & 0x03 {
+            DATA => {
+                self.tdr = Some(value);
+                self.status &= !STATUS_TDRE;
+            },
+            STATUS => {
+                // Programmed reset
+                self.command &= 0xE0;
+                self.status &= !STATUS_OVERRUN;
+            },
+            COMMAND => self.command = value,
+            CONTROL => self.control = value,
+            _ => {},
+        }
+    }
+}
+
+impl Device for Acia {
+    fn name(&self) -> String {
+        "6551 ACIA".to_string()
+    }
+
+    fn status(&self) -> String {
+        format!("Connected to {}  Status: {:02X} Command: {:02X} Control: {:02X}",
+            self.description, self.status, self.command, self.control)
+    }
+
+    fn size(&self) -> u16 {
+        4
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if let Some(input) = &mut self.input {
+            while let Some(b) = input.try_read() {
+                self.rx_queue.push_back(b);
+            }
+        }
+        let char_cycles = self.char_cycles();
+
+        // The transmit data register empties as soon as the shift register is free
+        if self.tx_shift.is_none() {
+            if let Some(b) = self.tdr.take() {
+                self.tx_shift = Some((b, char_cycles));
+                self.status |= STATUS_TDRE;
+                if self.tx_irq_enabled() {
+                    self.status |= STATUS_IRQ;
+                }
+            }
+        }
+        if let Some((b, remaining)) = self.tx_shift {
+            if remaining <= cycles {
+                self.tx_shift = None;
+                self.send(b);
+            } else {
+                self.tx_shift = Some((b, remaining - cycles));
+            }
+        }
+
+        if self.is_ready() && !self.rx_queue.is_empty() {
+            let remaining = self.rx_remaining.unwrap_or(char_cycles);
+            if remaining <= cycles {
+                self.rx_remaining = None;
+                if let Some(b) = self.rx_queue.pop_front() {
+                    self.deliver(b);
+                }
+            } else {
+                self.rx_remaining = Some(remaining - cycles);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.status = STATUS_TDRE;
+        self.command = 0x02;
+        self.control = 0;
+        self.tdr = None;
+        self.tx_shift = None;
+        self.rx_remaining = None;
+    }
+
+    fn irq(&self) -> bool {
+        self.status & STATUS_IRQ != 0
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut state = vec![self.rdr, self.status, self.command, self.control,
+            self.tdr.is_some() as u8, self.tdr.unwrap_or(0)];
+        let (shift, shift_remaining) = self.tx_shift.unwrap_or((0, 0));
+        state.push(self.tx_shift.is_some() as u8);
+        state.push(shift);
+        state.extend_from_slice(&shift_remaining.to_le_bytes());
+        state
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        if state.len() != 16 {
+            return;
+        }
+        self.rdr = state[0];
+        self.status = state[1];
+        self.command = state[2];
+        self.control = state[3];
+        self.tdr = if state[4] != 0 { Some(state[5]) } else { None };
+        let mut remaining = [0; 8];
+        remaining.copy_from_slice(&state[8..16]);
+        self.tx_shift = if state[6] != 0 {
+            Some((state[7], u64::from_le_bytes(remaining)))
+        } else {
+            None
+        };
+    }
+}
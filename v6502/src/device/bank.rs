@@ -0,0 +1,242 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::cell::RefCell;
+use std::fs;
+use std::rc::Rc;
+
+use crate::device::Device;
+use crate::memory::Memory;
+
+/// The bank select register is one byte
+pub const MAX_BANKS: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BankKind {
+    Ram,
+    Rom,
+}
+
+/** Banks of memory that are seen one at a time through a window. */
+pub struct Banks {
+    kind: BankKind,
+    banks: Vec<Vec<u8>>,
+    selected: u8,
+    /// Writes to a ROM window select the bank, for cartridges without a separate register
+    select_on_write: bool,
+}
+
+impl Banks {
+    /** Creates banks of the given size filled with zeros. */
+    pub fn new(kind: BankKind, count: usize, size: u16) -> Banks {
+        Banks {
+            kind,
+            banks: vec![vec![0; size as usize]; count.clamp(1, MAX_BANKS)],
+            selected: 0,
+            select_on_write: false,
+        }
+    }
+
+    /**
+     * Splits an image into banks of the given size. The last bank is padded
+     * with $FF, as are any extra banks needed to make up count.
+     */
+    pub fn from_image(kind: BankKind, image: &[u8], size: u16, count: Option<usize>) -> Banks {
+        let mut banks: Vec<Vec<u8>> = image.chunks(size as usize).map(|c| c.to_vec()).collect();
+        let count = count.unwrap_or(banks.len()).clamp(1, MAX_BANKS);
+        banks.resize(count, Vec::new());
+        for bank in banks.iter_mut() {
+            bank.resize(size as usize, 0xFF);
+        }
+        Banks { kind, banks, selected: 0, select_on_write: false }
+    }
+
+    pub fn kind(&self) -> BankKind {
+        self.kind
+    }
+
+    pub fn count(&self) -> usize {
+        self.banks.len()
+    }
+
+    pub fn selected(&self) -> u8 {
+        self.selected
+    }
+
+    /** Selects a bank. Numbers past the last bank wrap around, as unused address lines would. */
+    pub fn select(&mut self, bank: u8) {
+        self.selected = (bank as usize % self.banks.len()) as u8;
+    }
+
+    pub fn bank(&self, bank: u8) -> &[u8] {
+        &self.banks[bank as usize % self.banks.len()]
+    }
+
+    fn status(&self) -> String {
+        let kind = match self.kind {
+            BankKind::Ram => "RAM",
+            BankKind::Rom => "ROM",
+        };
+        format!("{} bank {} of {} (${:04X} bytes each)", kind, self.selected, self.banks.len(),
+            self.banks[0].len())
+    }
+}
+
+/**
+ * The window through which the selected bank is seen. The window and the
+ * register that selects the bank share the banks, so they can be mapped
+ * at unrelated addresses.
+ */
+pub struct BankWindow {
+    banks: Rc<RefCell<Banks>>,
+}
+
+/** A register that selects which bank a window shows. Reading it returns the selected bank. */
+pub struct BankSelect {
+    banks: Rc<RefCell<Banks>>,
+}
+
+/**
+ * Creates a window onto the banks and the register that selects them.
+ * If the select register isn't mapped, writes to a ROM window select the
+ * bank instead.
+ */
+pub fn banked(banks: Banks) -> (BankWindow, BankSelect) {
+    let banks = Rc::new(RefCell::new(banks));
+    (BankWindow { banks: banks.clone() }, BankSelect { banks })
+}
+
+impl BankWindow {
+    /** Makes writes to a ROM window select the bank. */
+    pub fn select_on_write(self) -> BankWindow {
+        self.banks.borrow_mut().select_on_write = true;
+        self
+    }
+}
+
+/** Loads an image file into banks. */
+pub fn load(kind: BankKind, path: &str, size: u16, count: Option<usize>) -> Result<Banks, String> {
+    let image = fs::read(path).map_err(|e| format!("couldn't read bank image {}: {}", path, e))?;
+    Ok(Banks::from_image(kind, &image, size, count))
+}
+
+impl Memory for BankWindow {
+    fn get(&mut self, address: u16) -> u8 {
+        self.peek(address)
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        let banks = self.banks.borrow();
+        banks.bank(banks.selected)[address as usize]
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        let mut banks = self.banks.borrow_mut();
+        match banks.kind {
+            BankKind::Ram => {
+                let selected = banks.selected as usize;
+                banks.banks[selected][address as usize] = value;
+            },
+            BankKind::Rom if banks.select_on_write => banks.select(value),
+            BankKind::Rom => {},
+        }
+    }
+}
+
+impl Device for BankWindow {
+    fn name(&self) -> String {
+        "Bank Window".to_string()
+    }
+
+    fn status(&self) -> String {
+        self.banks.borrow().status()
+    }
+
+    fn size(&self) -> u16 {
+        self.banks.borrow().banks[0].len() as u16
+    }
+
+    fn reset(&mut self) {
+        self.banks.borrow_mut().selected = 0;
+    }
+
+    fn undoes_writes(&self) -> bool {
+        self.banks.borrow().kind == BankKind::Ram
+    }
+
+    /** Saves the selected bank and, for RAM, the contents of every bank. */
+    fn save(&self) -> Vec<u8> {
+        let banks = self.banks.borrow();
+        let mut state = vec![banks.selected];
+        if banks.kind == BankKind::Ram {
+            for bank in &banks.banks {
+                state.extend_from_slice(bank);
+            }
+        }
+        state
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        let mut banks = self.banks.borrow_mut();
+        let contents = match banks.kind {
+            BankKind::Ram => banks.banks.len() * banks.banks[0].len(),
+            BankKind::Rom => 0,
+        };
+        if state.len() != 1 + contents {
+            return;
+        }
+        banks.select(state[0]);
+        let size = banks.banks[0].len();
+        for (bank, saved) in banks.banks.iter_mut().zip(state[1..].chunks(size)) {
+            bank.copy_from_slice(saved);
+        }
+    }
+}
+
+impl Memory for BankSelect {
+    fn get(&mut self, address: u16) -> u8 {
+        self.peek(address)
+    }
+
+    fn peek(&self, _address: u16) -> u8 {
+        self.banks.borrow().selected
+    }
+
+    fn set(&mut self, _address: u16, value: u8) {
+        self.banks.borrow_mut().select(value);
+    }
+}
+
+impl Device for BankSelect {
+    fn name(&self) -> String {
+        "Bank Select".to_string()
+    }
+
+    fn status(&self) -> String {
+        self.banks.borrow().status()
+    }
+
+    fn size(&self) -> u16 {
+        1
+    }
+
+    fn undoes_writes(&self) -> bool {
+        true
+    }
+}
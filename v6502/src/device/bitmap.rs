@@ -0,0 +1,365 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fs;
+use std::io;
+
+use crate::device::Device;
+use crate::memory::Memory;
+use crate::png::{self, ColorType};
+
+// Register offsets, after the pixel data
+const PALETTE_INDEX: u16 = 0;
+const PALETTE_DATA: u16 = 1;
+const FLIP: u16 = 2;
+const CONTROL: u16 = 3;
+const STATUS: u16 = 4;
+const FRAME: u16 = 5;
+const REGISTERS: u16 = 6;
+
+// Control register bits
+pub const CONTROL_DOUBLE_BUFFER: u8 = 0x01;
+pub const CONTROL_IRQ_ENABLE: u8 = 0x80;
+
+// Status register bits
+pub const STATUS_FRAME: u8 = 0x80;
+
+/// The 16 CGA colors, used as the start of the default palettes
+const CGA: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00], [0x00, 0x00, 0xAA], [0x00, 0xAA, 0x00], [0x00, 0xAA, 0xAA],
+    [0xAA, 0x00, 0x00], [0xAA, 0x00, 0xAA], [0xAA, 0x55, 0x00], [0xAA, 0xAA, 0xAA],
+    [0x55, 0x55, 0x55], [0x55, 0x55, 0xFF], [0x55, 0xFF, 0x55], [0x55, 0xFF, 0xFF],
+    [0xFF, 0x55, 0x55], [0xFF, 0x55, 0xFF], [0xFF, 0xFF, 0x55], [0xFF, 0xFF, 0xFF],
+];
+
+/**
+ * A bitmap screen with 1, 2, 4, or 8 bits per pixel. The device's window
+ * starts with the pixel data, row by row, with the leftmost pixel in the
+ * most significant bits of each byte and each row starting on a new byte.
+ * The registers follow.
+ *
+ * | Offset | Register |
+ * |--------|----------|
+ * | 0 | Palette index. Writing it starts at the red component of that color. |
+ * | 1 | Palette data: red, green, then blue, moving to the next color after blue. |
+ * | 2 | Writing any value swaps the displayed and drawing buffers when double buffering. |
+ * | 3 | Control: bit 0 enables double buffering and bit 7 enables an IRQ at the start of each frame. |
+ * | 4 | Status: bit 7 is set at the start of each frame. Writing any value acknowledges it. |
+ * | 5 | Frame count, low byte. |
+ *
+ * With double buffering the program draws into a buffer that isn't shown
+ * until it is flipped. Frames are counted at a fixed rate in CPU cycles,
+ * and the displayed image can be saved every few frames.
+ */
+pub struct Bitmap {
+    width: u16,
+    height: u16,
+    bits: u8,
+    /// Displayed buffer, then drawing buffer
+    buffers: [Vec<u8>; 2],
+    palette: Vec<[u8; 3]>,
+    palette_index: u8,
+    palette_component: u8,
+    control: u8,
+    status: u8,
+    frames: u64,
+    frame_cycles: u64,
+    since_frame: u64,
+    dump_path: Option<String>,
+    dump_every: u64,
+}
+
+impl Bitmap {
+    /** Creates a screen of width x height pixels with 1, 2, 4, or 8 bits per pixel. */
+    pub fn new(width: u16, height: u16, bits: u8) -> Result<Bitmap, String> {
+        if ![1, 2, 4, 8].contains(&bits) {
+            return Err(format!("bitmap can't have {} bits per pixel", bits));
+        }
+        let stride = (width as u64 * bits as u64).div_ceil(8);
+        let size = stride * height as u64;
+        if width == 0 || height == 0 || size + REGISTERS as u64 > 0xFFFF {
+            return Err(format!("bitmap of {}x{} with {} bits per pixel doesn't fit in memory",
+                width, height, bits));
+        }
+        Ok(Bitmap {
+            width,
+            height,
+            bits,
+            buffers: [vec![0; size as usize], vec![0; size as usize]],
+            palette: default_palette(bits),
+            palette_index: 0,
+            palette_component: 0,
+            control: 0,
+            status: 0,
+            frames: 0,
+            frame_cycles: 0,
+            since_frame: 0,
+            dump_path: None,
+            dump_every: 1,
+        })
+    }
+
+    /** Starts a new frame every frame_cycles CPU cycles. */
+    pub fn with_frame_cycles(mut self, frame_cycles: u64) -> Bitmap {
+        self.frame_cycles = frame_cycles;
+        self
+    }
+
+    /**
+     * Saves the displayed image every few frames, as a PNG image if the
+     * path ends in .png and as a PPM image otherwise. {} in the path is
+     * replaced by the frame number.
+     */
+    pub fn with_dump(mut self, path: &str, every: u64) -> Bitmap {
+        self.dump_path = Some(path.to_string());
+        self.dump_every = every.max(1);
+        self
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    fn stride(&self) -> usize {
+        (self.width as usize * self.bits as usize).div_ceil(8)
+    }
+
+    fn is_double_buffered(&self) -> bool {
+        self.control & CONTROL_DOUBLE_BUFFER != 0
+    }
+
+    /** Returns the buffer the program reads and writes. */
+    fn drawing(&mut self) -> &mut Vec<u8> {
+        let i = if self.is_double_buffered() { 1 } else { 0 };
+        &mut self.buffers[i]
+    }
+
+    /** Returns the palette index of a displayed pixel. */
+    pub fn pixel(&self, x: u16, y: u16) -> u8 {
+        let bit = x as usize * self.bits as usize;
+        let byte = self.buffers[0][y as usize * self.stride() + bit / 8];
+        let shift = 8 - self.bits as usize - bit % 8;
+        (byte >> shift) & (0xFF >> (8 - self.bits))
+    }
+
+    pub fn palette(&self, index: u8) -> [u8; 3] {
+        self.palette[index as usize % self.palette.len()]
+    }
+
+    pub fn set_palette(&mut self, index: u8, color: [u8; 3]) {
+        let len = self.palette.len();
+        self.palette[index as usize % len] = color;
+    }
+
+    /** Returns the displayed image as RGB bytes, row by row. */
+    pub fn rgb(&self) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(self.width as usize * self.height as usize * 3);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                rgb.extend_from_slice(&self.palette(self.pixel(x, y)));
+            }
+        }
+        rgb
+    }
+
+    pub fn ppm(&self) -> Vec<u8> {
+        let mut image = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        image.extend_from_slice(&self.rgb());
+        image
+    }
+
+    pub fn png(&self) -> Vec<u8> {
+        png::encode(self.width as u32, self.height as u32, ColorType::Rgb, &self.rgb())
+    }
+
+    /** Saves the displayed image as a PNG image if the path ends in .png, or as a PPM image. */
+    pub fn save_image(&self, path: &str) -> io::Result<()> {
+        if path.to_lowercase().ends_with(".png") {
+            fs::write(path, self.png())
+        } else {
+            fs::write(path, self.ppm())
+        }
+    }
+
+    fn next_component(&mut self) {
+        self.palette_component += 1;
+        if self.palette_component == 3 {
+            self.palette_component = 0;
+            self.palette_index = self.palette_index.wrapping_add(1);
+        }
+    }
+
+    fn frame(&mut self) {
+        self.frames += 1;
+        self.status |= STATUS_FRAME;
+        if self.frames.is_multiple_of(self.dump_every) {
+            if let Some(path) = &self.dump_path {
+                let path = path.replace("{}", &self.frames.to_string());
+                // A failed dump shouldn't stop the program
+                let _ = self.save_image(&path);
+            }
+        }
+    }
+}
+
+/** Gray levels for 1 and 2 bits per pixel, the CGA colors for 4, and a color cube and gray ramp for 8. */
+fn default_palette(bits: u8) -> Vec<[u8; 3]> {
+    match bits {
+        1 | 2 => {
+            let max = (1u16 << bits) - 1;
+            (0..=max).map(|i| [(i * 255 / max) as u8; 3]).collect()
+        },
+        4 => CGA.to_vec(),
+        _ => {
+            let mut palette = CGA.to_vec();
+            let levels = [0x00, 0x5F, 0x87, 0xAF, 0xD7, 0xFF];
+            for r in levels.iter() {
+                for g in levels.iter() {
+                    for b in levels.iter() {
+                        palette.push([*r, *g, *b]);
+                    }
+                }
+            }
+            palette.extend((0..24).map(|i| [8 + i * 10; 3]));
+            palette
+        },
+    }
+}
+
+impl Memory for Bitmap {
+    fn get(&mut self, address: u16) -> u8 {
+        let value = self.peek(address);
+        if address == self.buffers[0].len() as u16 + PALETTE_DATA {
+            self.next_component();
+        }
+        value
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        let size = self.buffers[0].len();
+        if (address as usize) < size {
+            let i = if self.is_double_buffered() { 1 } else { 0 };
+            return self.buffers[i][address as usize];
+        }
+        match address - size as u16 {
+            PALETTE_INDEX => self.palette_index,
+            PALETTE_DATA => self.palette(self.palette_index)[self.palette_component as usize],
+            CONTROL => self.control,
+            STATUS => self.status,
+            FRAME => self.frames as u8,
+            _ => 0,
+        }
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        let size = self.buffers[0].len();
+        if (address as usize) < size {
+            self.drawing()[address as usize] = value;
+            return;
+        }
+        match address - size as u16 {
+            PALETTE_INDEX => {
+                self.palette_index = value;
+                self.palette_component = 0;
+            },
+            PALETTE_DATA => {
+                let mut color = self.palette(self.palette_index);
+                color[self.palette_component as usize] = value;
+                self.set_palette(self.palette_index, color);
+                self.next_component();
+            },
+            FLIP if self.is_double_buffered() => self.buffers.swap(0, 1),
+            CONTROL => self.control = value,
+            STATUS => self.status = 0,
+            _ => {},
+        }
+    }
+}
+
+impl Device for Bitmap {
+    fn name(&self) -> String {
+        "Bitmap Screen".to_string()
+    }
+
+    fn status(&self) -> String {
+        format!("{}x{} with {} bits per pixel, Frame: {}", self.width, self.height, self.bits, self.frames)
+    }
+
+    fn size(&self) -> u16 {
+        (self.buffers[0].len() + REGISTERS as usize) as u16
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if self.frame_cycles == 0 {
+            return;
+        }
+        self.since_frame += cycles;
+        while self.since_frame >= self.frame_cycles {
+            self.since_frame -= self.frame_cycles;
+            self.frame();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.palette = default_palette(self.bits);
+        self.palette_index = 0;
+        self.palette_component = 0;
+        self.control = 0;
+        self.status = 0;
+    }
+
+    fn irq(&self) -> bool {
+        self.control & CONTROL_IRQ_ENABLE != 0 && self.status & STATUS_FRAME != 0
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut state = vec![self.palette_index, self.palette_component, self.control, self.status];
+        state.extend_from_slice(&self.frames.to_le_bytes());
+        state.extend_from_slice(&self.since_frame.to_le_bytes());
+        for color in &self.palette {
+            state.extend_from_slice(color);
+        }
+        state.extend_from_slice(&self.buffers[0]);
+        state.extend_from_slice(&self.buffers[1]);
+        state
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        let size = self.buffers[0].len();
+        let palette = self.palette.len() * 3;
+        if state.len() != 20 + palette + 2 * size {
+            return;
+        }
+        self.palette_index = state[0];
+        self.palette_component = state[1];
+        self.control = state[2];
+        self.status = state[3];
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&state[4..12]);
+        self.frames = u64::from_le_bytes(bytes);
+        bytes.copy_from_slice(&state[12..20]);
+        self.since_frame = u64::from_le_bytes(bytes);
+        for (i, color) in state[20..20 + palette].chunks(3).enumerate() {
+            self.palette[i].copy_from_slice(color);
+        }
+        let pixels = &state[20 + palette..];
+        self.buffers[0].copy_from_slice(&pixels[..size]);
+        self.buffers[1].copy_from_slice(&pixels[size..]);
+    }
+}
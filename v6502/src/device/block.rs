@@ -0,0 +1,251 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::device::Device;
+use crate::memory::Memory;
+
+// Register offsets, after the buffer
+const COMMAND: u16 = 0;
+const STATUS: u16 = 1;
+const CONTROL: u16 = 2;
+const BLOCK: u16 = 4;
+const BLOCK_COUNT: u16 = 8;
+const REGISTERS: u16 = 12;
+
+// Commands
+pub const COMMAND_READ: u8 = 0x01;
+pub const COMMAND_WRITE: u8 = 0x02;
+pub const COMMAND_FLUSH: u8 = 0x03;
+
+// Status register bits
+pub const STATUS_RANGE_ERROR: u8 = 0x01;
+pub const STATUS_IO_ERROR: u8 = 0x02;
+pub const STATUS_BAD_COMMAND: u8 = 0x04;
+pub const STATUS_DONE: u8 = 0x80;
+
+// Control register bits
+pub const CONTROL_IRQ_ENABLE: u8 = 0x80;
+
+pub const DEFAULT_BLOCK_SIZE: u16 = 512;
+
+/** Storage that a block device can read and write. */
+pub trait Image: Read + Write + Seek + Send {}
+
+impl<T: Read + Write + Seek + Send> Image for T {}
+
+/**
+ * A block storage device backed by a disk image. The device's window
+ * starts with a buffer of one block, followed by the registers.
+ *
+ * | Offset | Register |
+ * |--------|----------|
+ * | 0 | Command: 1 reads the block into the buffer, 2 writes the buffer to the block, and 3 flushes the image. |
+ * | 1 | Status: bit 7 is set when a command finishes and bits 0-2 report an out of range block, an I/O error, or an unknown command. Writing any value acknowledges it. |
+ * | 2 | Control: bit 7 enables an IRQ when a command finishes. |
+ * | 4-7 | Block number, least significant byte first. |
+ * | 8-11 | Number of blocks in the image (read only). |
+ *
+ * Commands finish immediately, so the status is ready for the next
+ * instruction.
+ */
+pub struct Block {
+    image: Box<dyn Image>,
+    description: String,
+    buffer: Vec<u8>,
+    blocks: u32,
+    block: u32,
+    command: u8,
+    status: u8,
+    control: u8,
+}
+
+impl Block {
+    /**
+     * Creates a block device for an image. The number of blocks is taken
+     * from the image's length unless it is given.
+     */
+    pub fn new(mut image: Box<dyn Image>, description: &str, block_size: u16, blocks: Option<u32>)
+        -> io::Result<Block>
+    {
+        let size = block_size as u64;
+        let blocks = match blocks {
+            Some(blocks) => blocks,
+            None => {
+                let length = image.seek(SeekFrom::End(0))?;
+                length.div_ceil(size).min(u32::MAX as u64) as u32
+            },
+        };
+        Ok(Block {
+            image,
+            description: description.to_string(),
+            buffer: vec![0; block_size as usize],
+            blocks,
+            block: 0,
+            command: 0,
+            status: 0,
+            control: 0,
+        })
+    }
+
+    /**
+     * Opens an image file for reading and writing. If the number of
+     * blocks is given the file is created when it doesn't exist.
+     */
+    pub fn open(path: &str, block_size: u16, blocks: Option<u32>) -> io::Result<Block> {
+        let file = OpenOptions::new().read(true).write(true).create(blocks.is_some()).open(path)?;
+        Block::new(Box::new(file), path, block_size, blocks)
+    }
+
+    pub fn block_size(&self) -> u16 {
+        self.buffer.len() as u16
+    }
+
+    fn offset(&self) -> u64 {
+        self.block as u64 * self.buffer.len() as u64
+    }
+
+    /** Fills the buffer from the current block. Bytes past the end of the image read as 0. */
+    fn read_block(&mut self) -> io::Result<()> {
+        self.image.seek(SeekFrom::Start(self.offset()))?;
+        let mut filled = 0;
+        while filled < self.buffer.len() {
+            match self.image.read(&mut self.buffer[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+        self.buffer[filled..].iter_mut().for_each(|b| *b = 0);
+        Ok(())
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        self.image.seek(SeekFrom::Start(self.offset()))?;
+        self.image.write_all(&self.buffer)
+    }
+
+    fn execute(&mut self, command: u8) {
+        self.command = command;
+        let result = match command {
+            COMMAND_READ | COMMAND_WRITE if self.block >= self.blocks => {
+                self.status = STATUS_DONE | STATUS_RANGE_ERROR;
+                return;
+            },
+            COMMAND_READ => self.read_block(),
+            COMMAND_WRITE => self.write_block(),
+            COMMAND_FLUSH => self.image.flush(),
+            _ => {
+                self.status = STATUS_DONE | STATUS_BAD_COMMAND;
+                return;
+            },
+        };
+        self.status = match result {
+            Ok(()) => STATUS_DONE,
+            Err(_) => STATUS_DONE | STATUS_IO_ERROR,
+        };
+    }
+}
+
+impl Memory for Block {
+    fn get(&mut self, address: u16) -> u8 {
+        self.peek(address)
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        let size = self.block_size();
+        if address < size {
+            return self.buffer[address as usize];
+        }
+        match address - size {
+            COMMAND => self.command,
+            STATUS => self.status,
+            CONTROL => self.control,
+            r @ BLOCK..=7 => self.block.to_le_bytes()[(r - BLOCK) as usize],
+            r @ BLOCK_COUNT..=11 => self.blocks.to_le_bytes()[(r - BLOCK_COUNT) as usize],
+            _ => 0,
+        }
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        let size = self.block_size();
+        if address < size {
+            self.buffer[address as usize] = value;
+            return;
+        }
+        match address - size {
+            COMMAND => self.execute(value),
+            STATUS => self.status = 0,
+            CONTROL => self.control = value,
+            r @ BLOCK..=7 => {
+                let mut bytes = self.block.to_le_bytes();
+                bytes[(r - BLOCK) as usize] = value;
+                self.block = u32::from_le_bytes(bytes);
+            },
+            _ => {},
+        }
+    }
+}
+
+impl Device for Block {
+    fn name(&self) -> String {
+        "Block Storage".to_string()
+    }
+
+    fn status(&self) -> String {
+        format!("{}: {} blocks of {} bytes, Block: {} Status: {:02X}",
+            self.description, self.blocks, self.buffer.len(), self.block, self.status)
+    }
+
+    fn size(&self) -> u16 {
+        self.block_size() + REGISTERS
+    }
+
+    fn reset(&mut self) {
+        self.block = 0;
+        self.command = 0;
+        self.status = 0;
+        self.control = 0;
+    }
+
+    fn irq(&self) -> bool {
+        self.control & CONTROL_IRQ_ENABLE != 0 && self.status & STATUS_DONE != 0
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut state = self.block.to_le_bytes().to_vec();
+        state.extend_from_slice(&[self.command, self.status, self.control]);
+        state.extend_from_slice(&self.buffer);
+        state
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        if state.len() != 7 + self.buffer.len() {
+            return;
+        }
+        self.block = u32::from_le_bytes([state[0], state[1], state[2], state[3]]);
+        self.command = state[4];
+        self.status = state[5];
+        self.control = state[6];
+        self.buffer.copy_from_slice(&state[7..]);
+    }
+}
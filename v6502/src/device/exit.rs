@@ -0,0 +1,81 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::device::Device;
+use crate::memory::Memory;
+
+/**
+ * Lets a program stop the emulator. Writing a byte to the device's only
+ * register ends the program after the current instruction, with that
+ * byte as the exit code.
+ */
+pub struct Exit {
+    code: Option<u8>,
+}
+
+impl Exit {
+    pub fn new() -> Exit {
+        Exit { code: None }
+    }
+}
+
+impl Default for Exit {
+    fn default() -> Self {
+        Exit::new()
+    }
+}
+
+impl Memory for Exit {
+    fn get(&mut self, address: u16) -> u8 {
+        self.peek(address)
+    }
+
+    fn peek(&self, _address: u16) -> u8 {
+        0
+    }
+
+    fn set(&mut self, _address: u16, value: u8) {
+        self.code = Some(value);
+    }
+}
+
+impl Device for Exit {
+    fn name(&self) -> String {
+        "Exit".to_string()
+    }
+
+    fn status(&self) -> String {
+        match self.code {
+            Some(code) => format!("Exit code: {}", code),
+            None => "Running".to_string(),
+        }
+    }
+
+    fn size(&self) -> u16 {
+        1
+    }
+
+    fn reset(&mut self) {
+        self.code = None;
+    }
+
+    fn exit_code(&self) -> Option<u8> {
+        self.code
+    }
+}
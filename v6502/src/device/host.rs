@@ -0,0 +1,221 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+/**
+ * Reads a host stream on a background thread so that devices can poll
+ * for input without blocking the CPU.
+ */
+pub struct HostInput {
+    receiver: Receiver<u8>,
+    closed: bool,
+}
+
+impl HostInput {
+    pub fn spawn(mut input: Box<dyn Read + Send>) -> HostInput {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 256];
+            loop {
+                match input.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if buf[..n].iter().any(|b| sender.send(*b).is_err()) {
+                            break;
+                        }
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                }
+            }
+        });
+        HostInput { receiver, closed: false }
+    }
+
+    /** Returns the next byte if one has arrived. */
+    pub fn try_read(&mut self) -> Option<u8> {
+        match self.receiver.try_recv() {
+            Ok(b) => Some(b),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.closed = true;
+                None
+            },
+        }
+    }
+
+    /** Returns true once the stream has ended and all input has been read. */
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+/**
+ * The host side of a serial line: a byte source and a byte sink.
+ */
+pub struct HostStream {
+    pub input: Box<dyn Read + Send>,
+    pub output: Box<dyn Write + Send>,
+    /// Describes where the stream is connected, e.g. the name of a pseudo-terminal
+    pub description: String,
+}
+
+impl HostStream {
+    pub fn stdio() -> HostStream {
+        HostStream {
+            input: Box::new(io::stdin()),
+            output: Box::new(io::stdout()),
+            description: "stdio".to_string(),
+        }
+    }
+
+    /**
+     * Opens a stream described as stdio, pty, or a path. A path can be
+     * a named pipe or character device, which is opened for reading and
+     * writing, or a regular file, which is read from while output is
+     * discarded. Use open_files to read and write separate files.
+     */
+    pub fn open(name: &str) -> io::Result<HostStream> {
+        match name {
+            "stdio" | "-" => Ok(HostStream::stdio()),
+            "pty" => HostStream::pty(),
+            "null" => Ok(HostStream {
+                input: Box::new(io::empty()),
+                output: Box::new(io::sink()),
+                description: "null".to_string(),
+            }),
+            path if fs::metadata(path)?.is_file() => Ok(HostStream {
+                // Writing through a clone would overwrite the file at the read position
+                input: Box::new(File::open(path)?),
+                output: Box::new(io::sink()),
+                description: path.to_string(),
+            }),
+            path => {
+                let file = OpenOptions::new().read(true).write(true).open(path)
+                    .or_else(|_| File::open(path))?;
+                let output = file.try_clone()?;
+                Ok(HostStream {
+                    input: Box::new(file),
+                    output: Box::new(output),
+                    description: path.to_string(),
+                })
+            },
+        }
+    }
+
+    /** Uses separate paths for input and output. */
+    pub fn open_files(input: &str, output: &str) -> io::Result<HostStream> {
+        let input: Box<dyn Read + Send> = match input {
+            "-" => Box::new(io::stdin()),
+            path => Box::new(File::open(path)?),
+        };
+        let output: Box<dyn Write + Send> = match output {
+            "-" => Box::new(io::stdout()),
+            path => Box::new(OpenOptions::new().append(true).create(true).open(path)?),
+        };
+        Ok(HostStream { input, output, description: "files".to_string() })
+    }
+
+    /** Creates a pseudo-terminal that a terminal program can connect to. */
+    #[cfg(unix)]
+    pub fn pty() -> io::Result<HostStream> {
+        use std::ffi::CStr;
+        use std::os::unix::io::FromRawFd;
+
+        // SAFETY: the descriptor is checked before use and owned by the File
+        let (file, name) = unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let file = File::from_raw_fd(fd);
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let name = libc::ptsname(fd);
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            (file, CStr::from_ptr(name).to_string_lossy().into_owned())
+        };
+        let output = file.try_clone()?;
+        Ok(HostStream { input: Box::new(file), output: Box::new(output), description: name })
+    }
+
+    #[cfg(not(unix))]
+    pub fn pty() -> io::Result<HostStream> {
+        Err(io::Error::new(io::ErrorKind::Other, "pseudo-terminals are not supported on this platform"))
+    }
+}
+
+/**
+ * Keeps stdin in raw mode: keys are delivered as they are typed, without
+ * echo, and Enter is read as a carriage return. Ctrl-C still interrupts
+ * the emulator. The original mode is restored when this is dropped.
+ */
+#[cfg(unix)]
+pub struct RawMode {
+    original: libc::termios,
+}
+
+#[cfg(unix)]
+impl RawMode {
+    pub fn enable() -> io::Result<RawMode> {
+        // SAFETY: termios is plain data filled in by tcgetattr
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let original = termios;
+            termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+            termios.c_iflag &= !(libc::ICRNL | libc::IXON);
+            termios.c_cc[libc::VMIN] = 1;
+            termios.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(RawMode { original })
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // SAFETY: restores the settings read in enable
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub struct RawMode;
+
+#[cfg(not(unix))]
+impl RawMode {
+    pub fn enable() -> io::Result<RawMode> {
+        Err(io::Error::new(io::ErrorKind::Other, "raw mode is not supported on this platform"))
+    }
+}
@@ -0,0 +1,361 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+use std::io::Write;
+
+use crate::device::Device;
+use crate::device::host::{HostInput, HostStream};
+use crate::memory::Memory;
+
+// Control register bits
+pub const CR_C1_IRQ_ENABLE: u8 = 0x01;
+pub const CR_C1_POSITIVE: u8 = 0x02;
+pub const CR_PORT: u8 = 0x04;
+pub const CR_IRQ2: u8 = 0x40;
+pub const CR_IRQ1: u8 = 0x80;
+
+/** One side of the PIA: a port, its control register, and its two control lines. */
+struct Side {
+    or: u8,
+    ddr: u8,
+    cr: u8,
+    /// Levels driven onto the port by external hardware
+    input: u8,
+    c1: bool,
+    c2: bool,
+    c2_out: bool,
+    /// Instruction boundaries left before a C2 pulse ends
+    pulse: u8,
+}
+
+impl Side {
+    fn new() -> Side {
+        Side {
+            or: 0,
+            ddr: 0,
+            cr: 0,
+            input: 0xFF,
+            c1: true,
+            c2: true,
+            c2_out: true,
+            pulse: 0,
+        }
+    }
+
+    fn pins(&self) -> u8 {
+        (self.or & self.ddr) | (self.input & !self.ddr)
+    }
+
+    fn c2_is_input(&self) -> bool {
+        self.cr & 0x20 == 0
+    }
+
+    fn set_c1(&mut self, level: bool) {
+        let positive = self.cr & CR_C1_POSITIVE != 0;
+        if (positive && !self.c1 && level) || (!positive && self.c1 && !level) {
+            self.cr |= CR_IRQ1;
+            // Handshake mode: C2 returns high on the active C1 edge
+            if self.cr & 0x38 == 0x20 {
+                self.c2_out = true;
+            }
+        }
+        self.c1 = level;
+    }
+
+    fn set_c2(&mut self, level: bool) {
+        let positive = self.cr & 0x10 != 0;
+        if self.c2_is_input() && ((positive && !self.c2 && level) || (!positive && self.c2 && !level)) {
+            self.cr |= CR_IRQ2;
+        }
+        self.c2 = level;
+    }
+
+    fn write_cr(&mut self, value: u8) {
+        self.cr = (self.cr & 0xC0) | (value & 0x3F);
+        // Manual output mode drives C2 from bit 3, other modes start high
+        self.c2_out = self.cr & 0x38 != 0x30;
+    }
+
+    /** Starts a handshake or pulse on C2 after the port is accessed. */
+    fn handshake(&mut self) {
+        match self.cr & 0x38 {
+            0x20 => self.c2_out = false,
+            0x28 => {
+                self.c2_out = false;
+                self.pulse = 2;
+            },
+            _ => {},
+        }
+    }
+
+    fn irq(&self) -> bool {
+        (self.cr & CR_IRQ1 != 0 && self.cr & CR_C1_IRQ_ENABLE != 0)
+            || (self.cr & CR_IRQ2 != 0 && self.c2_is_input() && self.cr & 0x08 != 0)
+    }
+
+    fn save(&self) -> [u8; 8] {
+        [self.or, self.ddr, self.cr, self.input, self.c1 as u8, self.c2 as u8,
+            self.c2_out as u8, self.pulse]
+    }
+
+    fn restore(&mut self, s: &[u8]) {
+        self.or = s[0];
+        self.ddr = s[1];
+        self.cr = s[2];
+        self.input = s[3];
+        self.c1 = s[4] != 0;
+        self.c2 = s[5] != 0;
+        self.c2_out = s[6] != 0;
+        self.pulse = s[7];
+    }
+}
+
+/** Keyboard and display connected the way they are on the Apple I. */
+struct HostTerminal {
+    input: HostInput,
+    output: Box<dyn Write + Send>,
+    description: String,
+    keys: VecDeque<u8>,
+}
+
+/**
+ * A MOS 6520 / Motorola 6821 Peripheral Interface Adapter with two 8-bit
+ * ports, each with a control register and two control lines.
+ *
+ * It can be connected to a host stream like the Apple I keyboard and
+ * display: keys appear on port A with bit 7 set and strobe CA1, and
+ * characters written to port B are displayed. PB7 reads low so the
+ * display is always ready.
+ */
+pub struct Pia {
+    a: Side,
+    b: Side,
+    terminal: Option<HostTerminal>,
+}
+
+impl Pia {
+    pub fn new() -> Pia {
+        Pia {
+            a: Side::new(),
+            b: Side::new(),
+            terminal: None,
+        }
+    }
+
+    /** Creates a PIA with an Apple I style keyboard and display. */
+    pub fn with_terminal(stream: HostStream) -> Pia {
+        let mut pia = Pia::new();
+        pia.b.input = 0x7F;
+        pia.terminal = Some(HostTerminal {
+            input: HostInput::spawn(stream.input),
+            output: stream.output,
+            description: stream.description,
+            keys: VecDeque::new(),
+        });
+        pia
+    }
+
+    pub fn port_a(&self) -> u8 {
+        self.a.pins()
+    }
+
+    pub fn port_b(&self) -> u8 {
+        self.b.pins()
+    }
+
+    pub fn set_input_a(&mut self, value: u8) {
+        self.a.input = value;
+    }
+
+    pub fn set_input_b(&mut self, value: u8) {
+        self.b.input = value;
+    }
+
+    pub fn set_ca1(&mut self, level: bool) {
+        self.a.set_c1(level);
+    }
+
+    pub fn set_ca2(&mut self, level: bool) {
+        self.a.set_c2(level);
+    }
+
+    pub fn set_cb1(&mut self, level: bool) {
+        self.b.set_c1(level);
+    }
+
+    pub fn set_cb2(&mut self, level: bool) {
+        self.b.set_c2(level);
+    }
+
+    pub fn ca2_output(&self) -> bool {
+        self.a.c2_out
+    }
+
+    pub fn cb2_output(&self) -> bool {
+        self.b.c2_out
+    }
+
+    /** Returns true while the IRQA output is asserted. */
+    pub fn irq_a(&self) -> bool {
+        self.a.irq()
+    }
+
+    /** Returns true while the IRQB output is asserted. */
+    pub fn irq_b(&self) -> bool {
+        self.b.irq()
+    }
+
+    /** Presents the next key once the last one has been read. */
+    fn poll_keyboard(&mut self) {
+        let terminal = match &mut self.terminal {
+            Some(terminal) => terminal,
+            None => return,
+        };
+        while let Some(b) = terminal.input.try_read() {
+            let key = match b {
+                b'\n' => 0x0D,
+                b => b.to_ascii_uppercase(),
+            };
+            terminal.keys.push_back(key);
+        }
+        if self.a.cr & CR_IRQ1 == 0 {
+            if let Some(key) = terminal.keys.pop_front() {
+                self.a.input = key | 0x80;
+                let positive = self.a.cr & CR_C1_POSITIVE != 0;
+                self.a.set_c1(!positive);
+                self.a.set_c1(positive);
+            }
+        }
+    }
+
+    fn display(&mut self, value: u8) {
+        if let Some(terminal) = &mut self.terminal {
+            let c = match value & 0x7F {
+                0x0D => b'\n',
+                c => c,
+            };
+            // A closed host stream just drops output
+            let _ = terminal.output.write_all(&[c]).and_then(|_| terminal.output.flush());
+        }
+    }
+}
+
+impl Default for Pia {
+    fn default() -> Self {
+        Pia::new()
+    }
+}
+
+impl Memory for Pia {
+    fn get(&mut self, address: u16) -> u8 {
+        let value = self.peek(address);
+        match address & 0x03 {
+            0 if self.a.cr & CR_PORT != 0 => {
+                self.a.cr &= !(CR_IRQ1 | CR_IRQ2);
+                self.a.handshake();
+            },
+            2 if self.b.cr & CR_PORT != 0 => self.b.cr &= !(CR_IRQ1 | CR_IRQ2),
+            _ => {},
+        }
+        value
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        match address & 0x03 {
+            0 if self.a.cr & CR_PORT != 0 => self.a.pins(),
+            0 => self.a.ddr,
+            1 => self.a.cr,
+            2 if self.b.cr & CR_PORT != 0 => self.b.pins(),
+            2 => self.b.ddr,
+            _ => self.b.cr,
+        }
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        match address & 0x03 {
+            0 if self.a.cr & CR_PORT != 0 => self.a.or = value,
+            0 => self.a.ddr = value,
+            1 => self.a.write_cr(value),
+            2 if self.b.cr & CR_PORT != 0 => {
+                self.b.or = value;
+                self.b.handshake();
+                self.display(value);
+            },
+            2 => self.b.ddr = value,
+            _ => self.b.write_cr(value),
+        }
+    }
+}
+
+impl Device for Pia {
+    fn name(&self) -> String {
+        "6821 PIA".to_string()
+    }
+
+    fn status(&self) -> String {
+        let mut status = format!("CRA: {:02X} CRB: {:02X} PA: {:02X} PB: {:02X}",
+            self.a.cr, self.b.cr, self.a.pins(), self.b.pins());
+        if let Some(terminal) = &self.terminal {
+            status.push_str(&format!("  Connected to {}", terminal.description));
+        }
+        status
+    }
+
+    fn size(&self) -> u16 {
+        4
+    }
+
+    fn tick(&mut self, _cycles: u64) {
+        for side in [&mut self.a, &mut self.b].iter_mut() {
+            if side.pulse > 0 {
+                side.pulse -= 1;
+                if side.pulse == 0 {
+                    side.c2_out = true;
+                }
+            }
+        }
+        self.poll_keyboard();
+    }
+
+    fn reset(&mut self) {
+        let (input_a, input_b) = (self.a.input, self.b.input);
+        self.a = Side::new();
+        self.b = Side::new();
+        self.a.input = input_a;
+        self.b.input = input_b;
+    }
+
+    fn irq(&self) -> bool {
+        self.a.irq() || self.b.irq()
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut state = self.a.save().to_vec();
+        state.extend_from_slice(&self.b.save());
+        state
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        if state.len() == 16 {
+            self.a.restore(&state[..8]);
+            self.b.restore(&state[8..]);
+        }
+    }
+}
@@ -0,0 +1,264 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::device::Device;
+use crate::memory::Memory;
+
+pub const IRQ_TIMER: u8 = 0x80;
+pub const IRQ_PA7: u8 = 0x40;
+
+const RAM_SIZE: usize = 128;
+
+/// Timer clock dividers selected by A1 and A0
+const PRESCALERS: [u16; 4] = [1, 8, 64, 1024];
+
+/**
+ * A MOS 6532 RAM-I/O-Timer with 128 bytes of RAM, two 8-bit ports, an
+ * interval timer, and an edge detector on PA7.
+ *
+ * The device uses 256 addresses. A7 is wired to the RAM select input,
+ * so RAM is at offsets $00-$7F and the I/O and timer registers are at
+ * $80-$FF.
+ */
+pub struct Riot {
+    ram: [u8; RAM_SIZE],
+    /// Levels driven onto port A by external hardware
+    input_a: u8,
+    /// Levels driven onto port B by external hardware
+    pub input_b: u8,
+    ora: u8,
+    orb: u8,
+    ddra: u8,
+    ddrb: u8,
+    timer: u8,
+    prescaler: u16,
+    /// Cycles until the timer next decrements
+    prescale_left: u16,
+    timer_irq_enabled: bool,
+    pa7_irq_enabled: bool,
+    pa7_positive: bool,
+    flags: u8,
+    fresh: bool,
+}
+
+impl Riot {
+    pub fn new() -> Riot {
+        Riot {
+            ram: [0; RAM_SIZE],
+            input_a: 0xFF,
+            input_b: 0xFF,
+            ora: 0,
+            orb: 0,
+            ddra: 0,
+            ddrb: 0,
+            timer: 0xFF,
+            prescaler: 1024,
+            prescale_left: 1024,
+            timer_irq_enabled: false,
+            pa7_irq_enabled: false,
+            pa7_positive: false,
+            flags: 0,
+            fresh: false,
+        }
+    }
+
+    pub fn port_a(&self) -> u8 {
+        (self.ora & self.ddra) | (self.input_a & !self.ddra)
+    }
+
+    pub fn port_b(&self) -> u8 {
+        (self.orb & self.ddrb) | (self.input_b & !self.ddrb)
+    }
+
+    /** Sets the levels driven onto port A, watching PA7 for the selected edge. */
+    pub fn set_input_a(&mut self, value: u8) {
+        let old = self.port_a() & 0x80 != 0;
+        self.input_a = value;
+        let new = self.port_a() & 0x80 != 0;
+        if (self.pa7_positive && !old && new) || (!self.pa7_positive && old && !new) {
+            self.flags |= IRQ_PA7;
+        }
+    }
+
+    /** Returns the interval timer value. */
+    pub fn timer(&self) -> u8 {
+        self.timer
+    }
+
+    fn write_timer(&mut self, address: u16, value: u8) {
+        self.timer = value;
+        self.prescaler = PRESCALERS[(address & 0x03) as usize];
+        // The first decrement happens on the cycle after the write
+        self.prescale_left = 1;
+        self.timer_irq_enabled = address & 0x08 != 0;
+        self.flags &= !IRQ_TIMER;
+        self.fresh = true;
+    }
+}
+
+impl Default for Riot {
+    fn default() -> Self {
+        Riot::new()
+    }
+}
+
+impl Memory for Riot {
+    fn get(&mut self, address: u16) -> u8 {
+        let value = self.peek(address);
+        if address & 0x80 != 0 && address & 0x04 != 0 {
+            if address & 0x01 == 0 {
+                self.timer_irq_enabled = address & 0x08 != 0;
+                // Reading the timer after it has expired returns to the programmed rate
+                self.flags &= !IRQ_TIMER;
+            } else {
+                self.flags &= !IRQ_PA7;
+            }
+        }
+        value
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        if address & 0x80 == 0 {
+            return self.ram[(address & 0x7F) as usize];
+        }
+        if address & 0x04 == 0 {
+            return match address & 0x03 {
+                0 => self.port_a(),
+                1 => self.ddra,
+                2 => self.port_b(),
+                _ => self.ddrb,
+            };
+        }
+        if address & 0x01 == 0 {
+            self.timer
+        } else {
+            self.flags
+        }
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        if address & 0x80 == 0 {
+            self.ram[(address & 0x7F) as usize] = value;
+        } else if address & 0x04 == 0 {
+            match address & 0x03 {
+                0 => self.ora = value,
+                1 => self.ddra = value,
+                2 => self.orb = value,
+                _ => self.ddrb = value,
+            }
+        } else if address & 0x10 != 0 {
+            self.write_timer(address, value);
+        } else {
+            // Edge detect control
+            self.pa7_positive = address & 0x01 != 0;
+            self.pa7_irq_enabled = address & 0x02 != 0;
+        }
+    }
+}
+
+impl Device for Riot {
+    fn name(&self) -> String {
+        "6532 RIOT".to_string()
+    }
+
+    fn status(&self) -> String {
+        format!("Timer: {:02X} /{} Flags: {:02X} PA: {:02X} PB: {:02X}",
+            self.timer, self.prescaler, self.flags, self.port_a(), self.port_b())
+    }
+
+    fn size(&self) -> u16 {
+        0x100
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if self.fresh {
+            self.fresh = false;
+            return;
+        }
+        for _ in 0..cycles {
+            self.prescale_left -= 1;
+            if self.prescale_left > 0 {
+                continue;
+            }
+            if self.timer == 0 {
+                // After expiring, the timer counts down once per cycle
+                self.flags |= IRQ_TIMER;
+                self.timer = 0xFF;
+                self.prescale_left = 1;
+            } else {
+                self.timer -= 1;
+                self.prescale_left = if self.flags & IRQ_TIMER != 0 { 1 } else { self.prescaler };
+            }
+        }
+    }
+
+    /** Reset clears the I/O registers and disables interrupts. RAM is unchanged. */
+    fn reset(&mut self) {
+        self.ora = 0;
+        self.orb = 0;
+        self.ddra = 0;
+        self.ddrb = 0;
+        self.timer_irq_enabled = false;
+        self.pa7_irq_enabled = false;
+        self.pa7_positive = false;
+        self.flags = 0;
+    }
+
+    fn irq(&self) -> bool {
+        (self.timer_irq_enabled && self.flags & IRQ_TIMER != 0)
+            || (self.pa7_irq_enabled && self.flags & IRQ_PA7 != 0)
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut state = self.ram.to_vec();
+        state.extend_from_slice(&[self.input_a, self.input_b, self.ora, self.orb, self.ddra,
+            self.ddrb, self.timer, self.flags, self.timer_irq_enabled as u8,
+            self.pa7_irq_enabled as u8, self.pa7_positive as u8, self.fresh as u8]);
+        state.extend_from_slice(&self.prescaler.to_le_bytes());
+        state.extend_from_slice(&self.prescale_left.to_le_bytes());
+        state
+    }
+
+    /** Ignores a state with an unknown prescaler, and keeps the prescaler count in range. */
+    fn restore(&mut self, state: &[u8]) {
+        if state.len() != RAM_SIZE + 16 {
+            return;
+        }
+        let prescaler = u16::from_le_bytes([state[RAM_SIZE + 12], state[RAM_SIZE + 13]]);
+        if !PRESCALERS.contains(&prescaler) {
+            return;
+        }
+        self.ram.copy_from_slice(&state[..RAM_SIZE]);
+        let s = &state[RAM_SIZE..];
+        self.input_a = s[0];
+        self.input_b = s[1];
+        self.ora = s[2];
+        self.orb = s[3];
+        self.ddra = s[4];
+        self.ddrb = s[5];
+        self.timer = s[6];
+        self.flags = s[7];
+        self.timer_irq_enabled = s[8] != 0;
+        self.pa7_irq_enabled = s[9] != 0;
+        self.pa7_positive = s[10] != 0;
+        self.fresh = s[11] != 0;
+        self.prescaler = prescaler;
+        self.prescale_left = u16::from_le_bytes([s[14], s[15]]).clamp(1, prescaler);
+    }
+}
@@ -0,0 +1,326 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::device::Device;
+use crate::memory::Memory;
+
+// Register offsets
+const SECONDS: u16 = 0;
+const WEEKDAY: u16 = 7;
+const CONTROL: u16 = 8;
+const COMMAND: u16 = 9;
+const ALARM_SECONDS: u16 = 10;
+const ALARM_HOURS: u16 = 12;
+const STATUS: u16 = 13;
+
+// Control register bits
+pub const CONTROL_BCD: u8 = 0x01;
+pub const CONTROL_ALARM_IRQ: u8 = 0x80;
+
+// Command register bits
+pub const COMMAND_LATCH: u8 = 0x01;
+pub const COMMAND_SET: u8 = 0x80;
+
+// Status register bits
+pub const STATUS_ALARM: u8 = 0x80;
+
+/// 2000-01-01 00:00:00, where the virtual clock starts by default
+pub const DEFAULT_START: u64 = 946_684_800;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/** Where the clock gets the time from. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    /// The host's clock, in UTC
+    Host,
+    /// A clock that starts at a given Unix time and advances with the CPU cycles
+    Virtual { start: u64, clock: u64 },
+}
+
+/**
+ * A real-time clock.
+ *
+ * | Offset | Register |
+ * |--------|----------|
+ * | 0-7 | Seconds, minutes, hours, day, month, year (00-99), century, and day of the week (0 is Sunday), as latched by the command register. |
+ * | 8 | Control: bit 0 selects BCD rather than binary values and bit 7 enables the alarm IRQ. |
+ * | 9 | Command: writing bit 0 latches the time into registers 0-7 and writing bit 7 sets the clock from them. |
+ * | 10-12 | Alarm seconds, minutes, and hours. |
+ * | 13 | Status: bit 7 is set when the time of day reaches the alarm. Writing any value acknowledges it. |
+ *
+ * Hours use the 24 hour clock. Setting the clock doesn't change the host's
+ * clock; the difference is kept as an offset.
+ */
+pub struct Rtc {
+    source: Source,
+    /// Seconds added to the source's time when the clock has been set
+    offset: i64,
+    /// Cycles since the virtual clock started
+    cycles: u64,
+    /// Latched time registers, always in binary
+    time: [u8; 8],
+    control: u8,
+    /// Alarm hours, minutes, and seconds, in binary
+    alarm: [u8; 3],
+    status: u8,
+    /// The last time checked for the alarm
+    last_checked: Option<u64>,
+}
+
+impl Rtc {
+    /** Creates a clock. Fails if a virtual clock's rate is 0. */
+    pub fn new(source: Source) -> Result<Rtc, String> {
+        if let Source::Virtual { clock: 0, .. } = source {
+            return Err("the clock rate must be more than 0".to_string());
+        }
+        let mut rtc = Rtc {
+            source,
+            offset: 0,
+            cycles: 0,
+            time: [0; 8],
+            control: 0,
+            alarm: [0; 3],
+            status: 0,
+            last_checked: None,
+        };
+        rtc.latch();
+        Ok(rtc)
+    }
+
+    /** Returns the current time as seconds since 1970-01-01 00:00:00 UTC. */
+    pub fn now(&self) -> u64 {
+        let seconds = match self.source {
+            Source::Host => SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs()).unwrap_or(0),
+            Source::Virtual { start, clock } => start + self.cycles / clock,
+        };
+        (seconds as i64 + self.offset).max(0) as u64
+    }
+
+    /** Copies the current time into the time registers. */
+    pub fn latch(&mut self) {
+        let now = self.now();
+        let days = now / SECONDS_PER_DAY;
+        let seconds = now % SECONDS_PER_DAY;
+        let (year, month, day) = civil_from_days(days);
+        self.time = [
+            (seconds % 60) as u8,
+            (seconds / 60 % 60) as u8,
+            (seconds / 3600) as u8,
+            day,
+            month,
+            (year % 100) as u8,
+            (year / 100) as u8,
+            ((days + 4) % 7) as u8,
+        ];
+    }
+
+    /** Sets the clock from the time registers. The day of the week is ignored. */
+    fn set_clock(&mut self) {
+        let t = &self.time;
+        let year = t[6] as i64 * 100 + t[5] as i64;
+        let seconds = days_from_civil(year, t[4].clamp(1, 12), t[3].clamp(1, 31)) * SECONDS_PER_DAY as i64
+            + t[2] as i64 * 3600 + t[1] as i64 * 60 + t[0] as i64;
+        self.offset += seconds - self.now() as i64;
+        self.last_checked = None;
+    }
+
+    fn is_bcd(&self) -> bool {
+        self.control & CONTROL_BCD != 0
+    }
+
+    fn encode(&self, value: u8) -> u8 {
+        if self.is_bcd() { ((value / 10) << 4) | (value % 10) } else { value }
+    }
+
+    fn decode(&self, value: u8) -> u8 {
+        if self.is_bcd() { (value >> 4) * 10 + (value & 0x0F) } else { value }
+    }
+
+    fn alarm_seconds(&self) -> u64 {
+        self.alarm[2] as u64 * 3600 + self.alarm[1] as u64 * 60 + self.alarm[0] as u64
+    }
+}
+
+/** Converts days since 1970-01-01 to a year, month, and day. */
+fn civil_from_days(days: u64) -> (u64, u8, u8) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/** Converts a year, month, and day to days since 1970-01-01, negative before then. */
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = month as i64;
+    let day_of_year = (153 * if month > 2 { month - 3 } else { month + 9 } + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/**
+ * Parses a start time given as Unix seconds or as YYYY-MM-DD with an
+ * optional THH:MM:SS.
+ */
+pub fn parse_time(s: &str) -> Option<u64> {
+    let s = s.trim();
+    if let Ok(seconds) = s.parse() {
+        return Some(seconds);
+    }
+    let (date, time) = match s.find('T') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, "00:00:00"),
+    };
+    let date: Vec<u64> = date.split('-').map(|n| n.parse().ok()).collect::<Option<_>>()?;
+    let time: Vec<u64> = time.split(':').map(|n| n.parse().ok()).collect::<Option<_>>()?;
+    match (date.as_slice(), time.as_slice()) {
+        (&[year, month, day], &[hours, minutes, seconds])
+            if year >= 1970 && (1..=12).contains(&month) && (1..=31).contains(&day)
+                && hours < 24 && minutes < 60 && seconds < 60 =>
+        {
+            Some(days_from_civil(year as i64, month as u8, day as u8) as u64 * SECONDS_PER_DAY
+                + hours * 3600 + minutes * 60 + seconds)
+        },
+        _ => None,
+    }
+}
+
+impl Memory for Rtc {
+    fn get(&mut self, address: u16) -> u8 {
+        self.peek(address)
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        match address {
+            SECONDS..=WEEKDAY => self.encode(self.time[address as usize]),
+            CONTROL => self.control,
+            ALARM_SECONDS..=ALARM_HOURS => self.encode(self.alarm[(address - ALARM_SECONDS) as usize]),
+            STATUS => self.status,
+            _ => 0,
+        }
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        match address {
+            SECONDS..=WEEKDAY => self.time[address as usize] = self.decode(value),
+            CONTROL => self.control = value,
+            COMMAND => {
+                if value & COMMAND_SET != 0 {
+                    self.set_clock();
+                }
+                if value & COMMAND_LATCH != 0 {
+                    self.latch();
+                }
+            },
+            ALARM_SECONDS..=ALARM_HOURS => {
+                self.alarm[(address - ALARM_SECONDS) as usize] = self.decode(value);
+                self.last_checked = None;
+            },
+            STATUS => self.status = 0,
+            _ => {},
+        }
+    }
+}
+
+impl Device for Rtc {
+    fn name(&self) -> String {
+        "Real-Time Clock".to_string()
+    }
+
+    fn status(&self) -> String {
+        let now = self.now();
+        let (year, month, day) = civil_from_days(now / SECONDS_PER_DAY);
+        let seconds = now % SECONDS_PER_DAY;
+        let source = match self.source {
+            Source::Host => "host",
+            Source::Virtual { .. } => "virtual",
+        };
+        format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} ({})", year, month, day,
+            seconds / 3600, seconds / 60 % 60, seconds % 60, source)
+    }
+
+    fn size(&self) -> u16 {
+        14
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.cycles += cycles;
+        let now = self.now();
+        if let Some(last) = self.last_checked {
+            // Did the time of day pass the alarm since the last check?
+            if now > last {
+                let until_alarm = (self.alarm_seconds() + SECONDS_PER_DAY - (last + 1) % SECONDS_PER_DAY)
+                    % SECONDS_PER_DAY;
+                if until_alarm < now - last {
+                    self.status |= STATUS_ALARM;
+                }
+            }
+        }
+        self.last_checked = Some(now);
+    }
+
+    fn reset(&mut self) {
+        self.cycles = 0;
+        self.control = 0;
+        self.status = 0;
+        self.last_checked = None;
+        self.latch();
+    }
+
+    fn irq(&self) -> bool {
+        self.control & CONTROL_ALARM_IRQ != 0 && self.status & STATUS_ALARM != 0
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut state = self.offset.to_le_bytes().to_vec();
+        state.extend_from_slice(&self.cycles.to_le_bytes());
+        state.extend_from_slice(&self.time);
+        state.extend_from_slice(&self.alarm);
+        state.extend_from_slice(&[self.control, self.status]);
+        state
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        if state.len() != 29 {
+            return;
+        }
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&state[0..8]);
+        self.offset = i64::from_le_bytes(bytes);
+        bytes.copy_from_slice(&state[8..16]);
+        self.cycles = u64::from_le_bytes(bytes);
+        self.time.copy_from_slice(&state[16..24]);
+        self.alarm.copy_from_slice(&state[24..27]);
+        self.control = state[27];
+        self.status = state[28];
+        self.last_checked = None;
+    }
+}
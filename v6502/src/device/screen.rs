@@ -0,0 +1,348 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fs;
+use std::io::{self, Write};
+
+use crate::device::Device;
+use crate::font;
+use crate::memory::Memory;
+use crate::png::{self, ColorType};
+
+// Register offsets, after the character memory
+const CURSOR_COLUMN: u16 = 0;
+const CURSOR_ROW: u16 = 1;
+const CONTROL: u16 = 2;
+const PUT: u16 = 3;
+const SNAPSHOT: u16 = 4;
+const REGISTERS: u16 = 5;
+
+// Control register bits
+pub const CONTROL_CURSOR: u8 = 0x01;
+
+// Snapshot register bits
+pub const SNAPSHOT_ERROR: u8 = 0x80;
+
+/// Characters with bit 7 set are shown in inverse video
+pub const INVERSE: u8 = 0x80;
+
+/**
+ * A text mode screen. The device's window starts with one byte per
+ * character, row by row, followed by the registers.
+ *
+ * | Offset | Register |
+ * |--------|----------|
+ * | 0 | Cursor column. |
+ * | 1 | Cursor row. |
+ * | 2 | Control: bit 0 shows the cursor. |
+ * | 3 | Writing a character puts it at the cursor and advances the cursor, scrolling at the bottom. Carriage return moves to the start of the line, line feed to the start of the next line, and backspace moves back one column. |
+ * | 4 | Writing any value saves a snapshot. Reading it returns bit 7 set if the last snapshot failed. |
+ *
+ * The screen can be drawn on the host terminal with ANSI escapes, and
+ * snapshots are saved as text, or as PNG images if the file name ends
+ * in .png.
+ */
+pub struct TextScreen {
+    columns: u8,
+    rows: u8,
+    characters: Vec<u8>,
+    column: u8,
+    row: u8,
+    control: u8,
+    snapshot_path: Option<String>,
+    snapshots: u32,
+    snapshot_status: u8,
+    output: Option<Box<dyn Write + Send>>,
+    /// Cycles between frames drawn on the output
+    refresh: u64,
+    since_refresh: u64,
+    dirty: bool,
+    cleared: bool,
+}
+
+impl TextScreen {
+    pub fn new(columns: u8, rows: u8) -> TextScreen {
+        TextScreen {
+            columns,
+            rows,
+            characters: vec![b' '; columns as usize * rows as usize],
+            column: 0,
+            row: 0,
+            control: CONTROL_CURSOR,
+            snapshot_path: None,
+            snapshots: 0,
+            snapshot_status: 0,
+            output: None,
+            refresh: 0,
+            since_refresh: 0,
+            dirty: false,
+            cleared: false,
+        }
+    }
+
+    /** Draws the screen on an ANSI terminal whenever it changes, at most once every refresh cycles. */
+    pub fn with_output(mut self, output: Box<dyn Write + Send>, refresh: u64) -> TextScreen {
+        self.output = Some(output);
+        self.refresh = refresh;
+        self.dirty = true;
+        self
+    }
+
+    /**
+     * Sets where snapshots requested by the program are saved. {} in the
+     * path is replaced by the snapshot's number, starting at 1.
+     */
+    pub fn with_snapshot_path(mut self, path: &str) -> TextScreen {
+        self.snapshot_path = Some(path.to_string());
+        self
+    }
+
+    pub fn cursor(&self) -> (u8, u8) {
+        (self.column, self.row)
+    }
+
+    fn is_cursor_visible(&self) -> bool {
+        self.control & CONTROL_CURSOR != 0
+    }
+
+    fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        self.characters.chunks(self.columns as usize)
+    }
+
+    /** Returns the screen as text, one line per row without trailing spaces. */
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for row in self.rows() {
+            let line: String = row.iter().map(|c| printable(*c)).collect();
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text
+    }
+
+    /** Returns escape sequences that draw the whole screen on an ANSI terminal. */
+    pub fn ansi(&self) -> String {
+        let mut out = "\x1b[?25l\x1b[H".to_string();
+        for (i, row) in self.rows().enumerate() {
+            let mut inverse = false;
+            for c in row {
+                if (c & INVERSE != 0) != inverse {
+                    inverse = !inverse;
+                    out.push_str(if inverse { "\x1b[7m" } else { "\x1b[27m" });
+                }
+                out.push(printable(*c));
+            }
+            if inverse {
+                out.push_str("\x1b[27m");
+            }
+            out.push_str("\x1b[K");
+            if i + 1 < self.rows as usize {
+                out.push_str("\r\n");
+            }
+        }
+        out.push_str(&format!("\x1b[{};{}H", self.row as u32 + 1, self.column as u32 + 1));
+        if self.is_cursor_visible() {
+            out.push_str("\x1b[?25h");
+        }
+        out
+    }
+
+    /** Returns the screen as a grayscale PNG image, 8x8 pixels per character. */
+    pub fn png(&self) -> Vec<u8> {
+        let width = self.columns as u32 * font::WIDTH;
+        let height = self.rows as u32 * font::HEIGHT;
+        let mut pixels = vec![0; (width * height) as usize];
+        for (i, c) in self.characters.iter().enumerate() {
+            let column = (i % self.columns as usize) as u8;
+            let row = (i / self.columns as usize) as u8;
+            let cursor = self.is_cursor_visible() && (column, row) == (self.column, self.row);
+            let inverse = (c & INVERSE != 0) != cursor;
+            let glyph = font::glyph(c & !INVERSE);
+            for (y, bits) in glyph.iter().enumerate() {
+                let py = row as usize * font::HEIGHT as usize + y;
+                for x in 0..font::WIDTH as usize {
+                    let on = (bits >> x) & 1 != 0;
+                    let px = column as usize * font::WIDTH as usize + x;
+                    pixels[py * width as usize + px] = if on != inverse { 255 } else { 0 };
+                }
+            }
+        }
+        png::encode(width, height, ColorType::Gray, &pixels)
+    }
+
+    /** Saves a snapshot as a PNG image if the path ends in .png, or as text. */
+    pub fn save_snapshot(&self, path: &str) -> io::Result<()> {
+        if path.to_lowercase().ends_with(".png") {
+            fs::write(path, self.png())
+        } else {
+            fs::write(path, self.text())
+        }
+    }
+
+    fn snapshot(&mut self) {
+        self.snapshots += 1;
+        if let Some(path) = &self.snapshot_path {
+            let path = path.replace("{}", &self.snapshots.to_string());
+            self.snapshot_status = match self.save_snapshot(&path) {
+                Ok(()) => 0,
+                Err(_) => SNAPSHOT_ERROR,
+            };
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.column = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            let columns = self.columns as usize;
+            self.characters.drain(..columns);
+            self.characters.resize(columns * self.rows as usize, b' ');
+        }
+    }
+
+    fn put(&mut self, c: u8) {
+        match c {
+            0x0D => self.column = 0,
+            0x0A => self.new_line(),
+            0x08 => self.column = self.column.saturating_sub(1),
+            _ => {
+                let i = self.row as usize * self.columns as usize + self.column as usize;
+                self.characters[i] = c;
+                if self.column + 1 < self.columns {
+                    self.column += 1;
+                } else {
+                    self.new_line();
+                }
+            },
+        }
+        self.dirty = true;
+    }
+
+    fn draw(&mut self) {
+        let frame = self.ansi();
+        let clear = !self.cleared;
+        if let Some(output) = &mut self.output {
+            if clear {
+                let _ = output.write_all(b"\x1b[2J");
+            }
+            let _ = output.write_all(frame.as_bytes());
+            let _ = output.flush();
+        }
+        self.cleared = true;
+        self.dirty = false;
+        self.since_refresh = 0;
+    }
+}
+
+fn printable(c: u8) -> char {
+    match c & !INVERSE {
+        c @ 0x20..=0x7E => c as char,
+        _ => ' ',
+    }
+}
+
+impl Memory for TextScreen {
+    fn get(&mut self, address: u16) -> u8 {
+        self.peek(address)
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        let size = self.characters.len() as u16;
+        if address < size {
+            return self.characters[address as usize];
+        }
+        match address - size {
+            CURSOR_COLUMN => self.column,
+            CURSOR_ROW => self.row,
+            CONTROL => self.control,
+            SNAPSHOT => self.snapshot_status,
+            _ => 0,
+        }
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        let size = self.characters.len() as u16;
+        if address < size {
+            self.characters[address as usize] = value;
+            self.dirty = true;
+            return;
+        }
+        match address - size {
+            CURSOR_COLUMN => self.column = value.min(self.columns - 1),
+            CURSOR_ROW => self.row = value.min(self.rows - 1),
+            CONTROL => self.control = value,
+            PUT => self.put(value),
+            SNAPSHOT => self.snapshot(),
+            _ => return,
+        }
+        self.dirty = true;
+    }
+}
+
+impl Device for TextScreen {
+    fn name(&self) -> String {
+        "Text Screen".to_string()
+    }
+
+    fn status(&self) -> String {
+        format!("{}x{} Cursor: {},{}", self.columns, self.rows, self.column, self.row)
+    }
+
+    fn size(&self) -> u16 {
+        self.characters.len() as u16 + REGISTERS
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if self.output.is_none() {
+            return;
+        }
+        self.since_refresh += cycles;
+        if self.dirty && self.since_refresh >= self.refresh {
+            self.draw();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.characters.iter_mut().for_each(|c| *c = b' ');
+        self.column = 0;
+        self.row = 0;
+        self.control = CONTROL_CURSOR;
+        self.snapshot_status = 0;
+        self.dirty = true;
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut state = vec![self.column, self.row, self.control, self.snapshot_status];
+        state.extend_from_slice(&self.characters);
+        state
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        if state.len() != 4 + self.characters.len() {
+            return;
+        }
+        self.column = state[0];
+        self.row = state[1];
+        self.control = state[2];
+        self.snapshot_status = state[3];
+        self.characters.copy_from_slice(&state[4..]);
+        self.dirty = true;
+    }
+}
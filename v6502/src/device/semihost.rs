@@ -0,0 +1,350 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::device::Device;
+use crate::memory::Memory;
+
+// Register offsets
+const OPERATION: u16 = 0;
+const BLOCK_LOW: u16 = 1;
+const BLOCK_HIGH: u16 = 2;
+const RESULT: u16 = 3;
+
+// Operations
+pub const OP_OPEN: u8 = 1;
+pub const OP_READ: u8 = 2;
+pub const OP_WRITE: u8 = 3;
+pub const OP_CLOSE: u8 = 4;
+pub const OP_SEEK: u8 = 5;
+pub const OP_EXIT: u8 = 6;
+
+// Open modes
+pub const MODE_READ: u8 = 0;
+pub const MODE_WRITE: u8 = 1;
+pub const MODE_APPEND: u8 = 2;
+pub const MODE_READ_WRITE: u8 = 3;
+
+// Results
+pub const RESULT_OK: u8 = 0;
+pub const RESULT_NOT_FOUND: u8 = 1;
+pub const RESULT_DENIED: u8 = 2;
+pub const RESULT_BAD_HANDLE: u8 = 3;
+pub const RESULT_IO_ERROR: u8 = 4;
+pub const RESULT_BAD_OPERATION: u8 = 5;
+pub const RESULT_TOO_MANY_FILES: u8 = 6;
+/// Set in the result register while an operation is waiting to run
+pub const RESULT_BUSY: u8 = 0x80;
+
+// Parameter block offsets
+const PARAM_RESULT: u16 = 0;
+const PARAM_HANDLE: u16 = 1;
+const PARAM_BUFFER: u16 = 2;
+const PARAM_LENGTH: u16 = 4;
+const PARAM_MODE: u16 = 6;
+const PARAM_OFFSET: u16 = 7;
+
+/// Handles are numbered from 1 so that 0 is never a valid handle
+const MAX_FILES: u8 = 16;
+
+/**
+ * Gives programs access to files on the host, limited to one directory.
+ * The program stores a parameter block in RAM, writes its address to
+ * registers 1 and 2, then writes the operation to register 0. The
+ * operation runs after the instruction that wrote it, and its result is
+ * stored in the first byte of the parameter block and in register 3.
+ *
+ * | Offset | Parameter |
+ * |--------|-----------|
+ * | 0 | Result, set by the emulator. |
+ * | 1 | File handle. Set by open, and used by read, write, close, and seek. Exit uses it as the exit code. |
+ * | 2-3 | Buffer address for read and write, or the address of the NUL terminated file name for open. |
+ * | 4-5 | Number of bytes to read or write. Set to the number actually transferred. |
+ * | 6 | Mode for open (0 read, 1 write, 2 append, 3 read and write), or where seek starts from (0 the start, 1 the current position, 2 the end). |
+ * | 7-10 | Signed offset for seek. Set to the new position. |
+ */
+pub struct Semihost {
+    root: PathBuf,
+    files: BTreeMap<u8, File>,
+    block: u16,
+    pending: Option<u8>,
+    result: u8,
+    exit_code: Option<u8>,
+}
+
+impl Semihost {
+    /** Creates a device that can only use files inside the root directory. */
+    pub fn new(root: &Path) -> io::Result<Semihost> {
+        Ok(Semihost {
+            root: root.canonicalize()?,
+            files: BTreeMap::new(),
+            block: 0,
+            pending: None,
+            result: RESULT_OK,
+            exit_code: None,
+        })
+    }
+
+    /**
+     * Finds a file inside the root directory. Absolute paths, .., and
+     * directories that lead outside it are refused, as are symbolic links
+     * to the file itself, since opening one to write would follow it even
+     * if its target doesn't exist yet.
+     */
+    fn resolve(&self, name: &str) -> Result<PathBuf, u8> {
+        let relative = Path::new(name);
+        if name.is_empty() || relative.components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir)) {
+            return Err(RESULT_DENIED);
+        }
+        let path = self.root.join(relative);
+        // The file may not exist yet, so check the directory it will be in
+        let parent = path.parent().ok_or(RESULT_DENIED)?;
+        let parent = parent.canonicalize().map_err(|_| RESULT_NOT_FOUND)?;
+        if !parent.starts_with(&self.root) {
+            return Err(RESULT_DENIED);
+        }
+        if matches!(fs::symlink_metadata(&path), Ok(m) if m.file_type().is_symlink()) {
+            return Err(RESULT_DENIED);
+        }
+        Ok(path)
+    }
+
+    fn open(&mut self, memory: &mut dyn Memory, block: u16) -> u8 {
+        let handle = match (1..=MAX_FILES).find(|h| !self.files.contains_key(h)) {
+            Some(handle) => handle,
+            None => return RESULT_TOO_MANY_FILES,
+        };
+        let mut address = word(memory, block.wrapping_add(PARAM_BUFFER));
+        let mut name = Vec::new();
+        while name.len() < 256 {
+            match byte(memory, address) {
+                0 => break,
+                b => name.push(b),
+            }
+            address = address.wrapping_add(1);
+        }
+        let name = String::from_utf8_lossy(&name).to_string();
+        let path = match self.resolve(&name) {
+            Ok(path) => path,
+            Err(result) => return result,
+        };
+        let mut options = OpenOptions::new();
+        match byte(memory, block.wrapping_add(PARAM_MODE)) {
+            MODE_READ => options.read(true),
+            MODE_WRITE => options.write(true).create(true).truncate(true),
+            MODE_APPEND => options.append(true).create(true),
+            MODE_READ_WRITE => options.read(true).write(true),
+            _ => return RESULT_BAD_OPERATION,
+        };
+        match options.open(path) {
+            Ok(file) => {
+                self.files.insert(handle, file);
+                set_byte(memory, block.wrapping_add(PARAM_HANDLE), handle);
+                RESULT_OK
+            },
+            Err(e) => error_result(&e),
+        }
+    }
+
+    fn read_write(&mut self, memory: &mut dyn Memory, block: u16, write: bool) -> u8 {
+        let handle = byte(memory, block.wrapping_add(PARAM_HANDLE));
+        let file = match self.files.get_mut(&handle) {
+            Some(file) => file,
+            None => return RESULT_BAD_HANDLE,
+        };
+        let buffer = word(memory, block.wrapping_add(PARAM_BUFFER)) as usize;
+        let length = word(memory, block.wrapping_add(PARAM_LENGTH)) as usize;
+        // Transfers stop at the end of memory rather than wrapping
+        let end = (buffer + length).min(0x10000);
+        let result = if write {
+            let data: Vec<u8> = (buffer..end).map(|a| byte(memory, a as u16)).collect();
+            file.write_all(&data).map(|_| data.len())
+        } else {
+            let mut data = vec![0; end - buffer];
+            let result = read_fully(file, &mut data);
+            if let Ok(n) = result {
+                for (i, b) in data[..n].iter().enumerate() {
+                    set_byte(memory, (buffer + i) as u16, *b);
+                }
+            }
+            result
+        };
+        match result {
+            Ok(n) => {
+                set_word(memory, block.wrapping_add(PARAM_LENGTH), n as u16);
+                RESULT_OK
+            },
+            Err(e) => error_result(&e),
+        }
+    }
+
+    fn seek(&mut self, memory: &mut dyn Memory, block: u16) -> u8 {
+        let handle = byte(memory, block.wrapping_add(PARAM_HANDLE));
+        let file = match self.files.get_mut(&handle) {
+            Some(file) => file,
+            None => return RESULT_BAD_HANDLE,
+        };
+        let mut offset = [0; 4];
+        for (i, b) in offset.iter_mut().enumerate() {
+            *b = byte(memory, block.wrapping_add(PARAM_OFFSET + i as u16));
+        }
+        let offset = i32::from_le_bytes(offset) as i64;
+        let from = match byte(memory, block.wrapping_add(PARAM_MODE)) {
+            0 if offset >= 0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return RESULT_BAD_OPERATION,
+        };
+        match file.seek(from) {
+            Ok(position) => {
+                let position = (position.min(u32::MAX as u64) as u32).to_le_bytes();
+                for (i, b) in position.iter().enumerate() {
+                    set_byte(memory, block.wrapping_add(PARAM_OFFSET + i as u16), *b);
+                }
+                RESULT_OK
+            },
+            Err(e) => error_result(&e),
+        }
+    }
+
+    fn execute(&mut self, memory: &mut dyn Memory, operation: u8) -> u8 {
+        let block = self.block;
+        match operation {
+            OP_OPEN => self.open(memory, block),
+            OP_READ => self.read_write(memory, block, false),
+            OP_WRITE => self.read_write(memory, block, true),
+            OP_CLOSE => {
+                let handle = byte(memory, block.wrapping_add(PARAM_HANDLE));
+                match self.files.remove(&handle) {
+                    Some(_) => RESULT_OK,
+                    None => RESULT_BAD_HANDLE,
+                }
+            },
+            OP_SEEK => self.seek(memory, block),
+            OP_EXIT => {
+                self.exit_code = Some(byte(memory, block.wrapping_add(PARAM_HANDLE)));
+                RESULT_OK
+            },
+            _ => RESULT_BAD_OPERATION,
+        }
+    }
+}
+
+fn byte(memory: &mut dyn Memory, address: u16) -> u8 {
+    memory.get(address)
+}
+
+fn word(memory: &mut dyn Memory, address: u16) -> u16 {
+    u16::from_le_bytes([byte(memory, address), byte(memory, address.wrapping_add(1))])
+}
+
+fn set_byte(memory: &mut dyn Memory, address: u16, value: u8) {
+    memory.set(address, value);
+}
+
+fn set_word(memory: &mut dyn Memory, address: u16, value: u16) {
+    let [low, high] = value.to_le_bytes();
+    set_byte(memory, address, low);
+    set_byte(memory, address.wrapping_add(1), high);
+}
+
+/** Reads until the buffer is full or the end of the file. */
+fn read_fully(file: &mut File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+fn error_result(e: &io::Error) -> u8 {
+    match e.kind() {
+        io::ErrorKind::NotFound => RESULT_NOT_FOUND,
+        io::ErrorKind::PermissionDenied => RESULT_DENIED,
+        _ => RESULT_IO_ERROR,
+    }
+}
+
+impl Memory for Semihost {
+    fn get(&mut self, address: u16) -> u8 {
+        self.peek(address)
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        match address {
+            OPERATION => self.pending.unwrap_or(0),
+            BLOCK_LOW => self.block as u8,
+            BLOCK_HIGH => (self.block >> 8) as u8,
+            RESULT if self.pending.is_some() => RESULT_BUSY,
+            RESULT => self.result,
+            _ => 0,
+        }
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        match address {
+            OPERATION => self.pending = Some(value),
+            BLOCK_LOW => self.block = (self.block & 0xFF00) | value as u16,
+            BLOCK_HIGH => self.block = (self.block & 0x00FF) | (value as u16) << 8,
+            _ => {},
+        }
+    }
+}
+
+impl Device for Semihost {
+    fn name(&self) -> String {
+        "Semihosting".to_string()
+    }
+
+    fn status(&self) -> String {
+        format!("{}, {} open files", self.root.display(), self.files.len())
+    }
+
+    fn size(&self) -> u16 {
+        4
+    }
+
+    fn reset(&mut self) {
+        self.files.clear();
+        self.block = 0;
+        self.pending = None;
+        self.result = RESULT_OK;
+        self.exit_code = None;
+    }
+
+    fn transfer(&mut self, memory: &mut dyn Memory) {
+        if let Some(operation) = self.pending.take() {
+            self.result = self.execute(memory, operation);
+            set_byte(memory, self.block.wrapping_add(PARAM_RESULT), self.result);
+        }
+    }
+
+    fn exit_code(&self) -> Option<u8> {
+        self.exit_code
+    }
+}
@@ -0,0 +1,221 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::device::Device;
+use crate::memory::Memory;
+
+// Register offsets
+const COUNTER_LOW: u16 = 0;
+const COUNTER_HIGH: u16 = 1;
+const PERIOD_LOW: u16 = 2;
+const PERIOD_HIGH: u16 = 3;
+const CONTROL: u16 = 4;
+const STATUS: u16 = 5;
+
+// Control register bits
+pub const CONTROL_ENABLE: u8 = 0x01;
+pub const CONTROL_PERIODIC: u8 = 0x02;
+pub const CONTROL_IRQ_ENABLE: u8 = 0x80;
+
+// Status register bits
+pub const STATUS_EXPIRED: u8 = 0x80;
+
+/// Cycles per count selected by bits 2 and 3 of the control register
+const PRESCALERS: [u64; 4] = [1, 8, 64, 1024];
+
+/**
+ * A programmable interval timer.
+ *
+ * | Offset | Register |
+ * |--------|----------|
+ * | 0 | Counter low byte. Reading it latches the high byte. |
+ * | 1 | Counter high byte, as latched by the last read of the low byte. |
+ * | 2, 3 | Period, low byte first. 0 means 65536. |
+ * | 4 | Control: bit 0 starts the timer, bit 1 selects periodic mode, bits 2-3 select a prescaler of 1, 8, 64, or 1024 cycles per count, and bit 7 enables the IRQ. |
+ * | 5 | Status: bit 7 is set when the timer expires. Writing any value acknowledges it. |
+ *
+ * Writing the control register with bit 0 set loads the counter from the
+ * period. In one-shot mode bit 0 is cleared when the timer expires; in
+ * periodic mode the counter is reloaded.
+ */
+pub struct Timer {
+    counter: u32,
+    latched_high: u8,
+    period: u16,
+    control: u8,
+    status: u8,
+    /// Cycles counted toward the next decrement
+    prescale_count: u64,
+    fresh: bool,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            counter: 0,
+            latched_high: 0,
+            period: 0,
+            control: 0,
+            status: 0,
+            prescale_count: 0,
+            fresh: false,
+        }
+    }
+
+    fn period_counts(&self) -> u32 {
+        if self.period == 0 { 0x10000 } else { self.period as u32 }
+    }
+
+    fn prescaler(&self) -> u64 {
+        PRESCALERS[((self.control >> 2) & 0x03) as usize]
+    }
+
+    fn is_running(&self) -> bool {
+        self.control & CONTROL_ENABLE != 0
+    }
+
+    /** Returns the number of cycles until the timer next expires, if it is running. */
+    pub fn cycles_remaining(&self) -> Option<u64> {
+        if self.is_running() {
+            Some(self.counter as u64 * self.prescaler() - self.prescale_count)
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer::new()
+    }
+}
+
+impl Memory for Timer {
+    fn get(&mut self, address: u16) -> u8 {
+        if address == COUNTER_LOW {
+            self.latched_high = (self.counter >> 8) as u8;
+        }
+        self.peek(address)
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        match address {
+            COUNTER_LOW => self.counter as u8,
+            COUNTER_HIGH => self.latched_high,
+            PERIOD_LOW => self.period as u8,
+            PERIOD_HIGH => (self.period >> 8) as u8,
+            CONTROL => self.control,
+            STATUS => self.status,
+            _ => 0,
+        }
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        match address {
+            PERIOD_LOW => self.period = (self.period & 0xFF00) | value as u16,
+            PERIOD_HIGH => self.period = (self.period & 0x00FF) | (value as u16) << 8,
+            CONTROL => {
+                self.control = value;
+                if self.is_running() {
+                    self.counter = self.period_counts();
+                    self.prescale_count = 0;
+                    self.fresh = true;
+                }
+            },
+            STATUS => self.status = 0,
+            _ => {},
+        }
+    }
+}
+
+impl Device for Timer {
+    fn name(&self) -> String {
+        "Interval Timer".to_string()
+    }
+
+    fn status(&self) -> String {
+        format!("Counter: {} Period: {} Control: {:02X} Status: {:02X}",
+            self.counter, self.period_counts(), self.control, self.status)
+    }
+
+    fn size(&self) -> u16 {
+        6
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if self.fresh {
+            // Counting starts after the instruction that started the timer
+            self.fresh = false;
+            return;
+        }
+        if !self.is_running() {
+            return;
+        }
+        let prescaler = self.prescaler();
+        let total = self.prescale_count + cycles;
+        let mut counts = total / prescaler;
+        self.prescale_count = total % prescaler;
+        while counts > 0 && self.is_running() {
+            if counts < self.counter as u64 {
+                self.counter -= counts as u32;
+                counts = 0;
+            } else {
+                counts -= self.counter as u64;
+                self.status |= STATUS_EXPIRED;
+                if self.control & CONTROL_PERIODIC != 0 {
+                    self.counter = self.period_counts();
+                } else {
+                    self.counter = 0;
+                    self.control &= !CONTROL_ENABLE;
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Timer::new();
+    }
+
+    fn irq(&self) -> bool {
+        self.control & CONTROL_IRQ_ENABLE != 0 && self.status & STATUS_EXPIRED != 0
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut state = self.counter.to_le_bytes().to_vec();
+        state.extend_from_slice(&self.period.to_le_bytes());
+        state.extend_from_slice(&[self.latched_high, self.control, self.status, self.fresh as u8]);
+        state.extend_from_slice(&self.prescale_count.to_le_bytes());
+        state
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        if state.len() != 18 {
+            return;
+        }
+        self.counter = u32::from_le_bytes([state[0], state[1], state[2], state[3]]);
+        self.period = u16::from_le_bytes([state[4], state[5]]);
+        self.latched_high = state[6];
+        self.control = state[7];
+        self.status = state[8];
+        self.fresh = state[9] != 0;
+        let mut count = [0; 8];
+        count.copy_from_slice(&state[10..18]);
+        self.prescale_count = u64::from_le_bytes(count);
+    }
+}
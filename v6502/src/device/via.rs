@@ -0,0 +1,603 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::device::Device;
+use crate::memory::Memory;
+
+// Register offsets
+const ORB: u16 = 0x0;
+const ORA: u16 = 0x1;
+const DDRB: u16 = 0x2;
+const DDRA: u16 = 0x3;
+const T1C_L: u16 = 0x4;
+const T1C_H: u16 = 0x5;
+const T1L_L: u16 = 0x6;
+const T1L_H: u16 = 0x7;
+const T2C_L: u16 = 0x8;
+const T2C_H: u16 = 0x9;
+const SR: u16 = 0xA;
+const ACR: u16 = 0xB;
+const PCR: u16 = 0xC;
+const IFR: u16 = 0xD;
+const IER: u16 = 0xE;
+const ORA_NO_HANDSHAKE: u16 = 0xF;
+
+// Interrupt flag bits
+pub const IRQ_CA2: u8 = 0x01;
+pub const IRQ_CA1: u8 = 0x02;
+pub const IRQ_SR: u8 = 0x04;
+pub const IRQ_CB2: u8 = 0x08;
+pub const IRQ_CB1: u8 = 0x10;
+pub const IRQ_T2: u8 = 0x20;
+pub const IRQ_T1: u8 = 0x40;
+
+// Registers and outputs written by the current instruction. They take
+// effect on the cycle after the write, so the rest of the instruction's
+// cycles are not counted against them.
+const FRESH_T1: u8 = 0x01;
+const FRESH_T2: u8 = 0x02;
+const FRESH_SR: u8 = 0x04;
+const FRESH_CA2: u8 = 0x08;
+const FRESH_CB2: u8 = 0x10;
+
+/**
+ * A MOS 6522 Versatile Interface Adapter with two 8-bit ports, two
+ * 16-bit timers, a shift register, and four handshake lines.
+ *
+ * External hardware drives the inputs through input_a, input_b, and the
+ * set_* methods, and reads the outputs with port_a, port_b, ca2_output,
+ * and cb2_output.
+ */
+pub struct Via {
+    /// Levels driven onto port A by external hardware
+    pub input_a: u8,
+    /// Levels driven onto port B by external hardware
+    input_b: u8,
+    ca1: bool,
+    ca2: bool,
+    cb1: bool,
+    cb2: bool,
+    ora: u8,
+    orb: u8,
+    ddra: u8,
+    ddrb: u8,
+    ira_latch: u8,
+    irb_latch: u8,
+    t1_counter: u16,
+    t1_latch: u16,
+    /// T1 interrupts once after being started in one-shot mode
+    t1_armed: bool,
+    t1_reload: bool,
+    t2_counter: u16,
+    t2_latch_low: u8,
+    t2_armed: bool,
+    sr: u8,
+    /// Bits shifted since the shift register was last accessed
+    sr_bits: u8,
+    /// Cycles until the next shift
+    sr_timer: u16,
+    sr_out: bool,
+    acr: u8,
+    pcr: u8,
+    ifr: u8,
+    ier: u8,
+    pb7: bool,
+    ca2_out: bool,
+    cb2_out: bool,
+    ca2_pulse: bool,
+    cb2_pulse: bool,
+    fresh: u8,
+}
+
+impl Via {
+    pub fn new() -> Via {
+        Via {
+            input_a: 0xFF,
+            input_b: 0xFF,
+            ca1: true,
+            ca2: true,
+            cb1: true,
+            cb2: true,
+            ora: 0,
+            orb: 0,
+            ddra: 0,
+            ddrb: 0,
+            ira_latch: 0,
+            irb_latch: 0,
+            t1_counter: 0xFFFF,
+            t1_latch: 0xFFFF,
+            t1_armed: false,
+            t1_reload: false,
+            t2_counter: 0xFFFF,
+            t2_latch_low: 0xFF,
+            t2_armed: false,
+            sr: 0,
+            sr_bits: 8,
+            sr_timer: 0,
+            sr_out: true,
+            acr: 0,
+            pcr: 0,
+            ifr: 0,
+            ier: 0,
+            pb7: true,
+            ca2_out: true,
+            cb2_out: true,
+            ca2_pulse: false,
+            cb2_pulse: false,
+            fresh: 0,
+        }
+    }
+
+    /** Returns the levels on the port A pins. */
+    pub fn port_a(&self) -> u8 {
+        (self.ora & self.ddra) | (self.input_a & !self.ddra)
+    }
+
+    /**
+     * Returns the levels on the port B pins. PB7 follows timer 1 when
+     * that is enabled in the ACR.
+     */
+    pub fn port_b(&self) -> u8 {
+        let value = (self.orb & self.ddrb) | (self.input_b & !self.ddrb);
+        if self.acr & 0x80 != 0 {
+            (value & 0x7F) | if self.pb7 { 0x80 } else { 0 }
+        } else {
+            value
+        }
+    }
+
+    /**
+     * Sets the levels driven onto port B. A falling edge on PB6 counts
+     * down timer 2 when it is in pulse counting mode.
+     */
+    pub fn set_input_b(&mut self, value: u8) {
+        let falling = self.input_b & 0x40 != 0 && value & 0x40 == 0;
+        self.input_b = value;
+        if falling && self.acr & 0x20 != 0 {
+            self.t2_counter = self.t2_counter.wrapping_sub(1);
+            if self.t2_counter == 0 && self.t2_armed {
+                self.t2_armed = false;
+                self.ifr |= IRQ_T2;
+            }
+        }
+    }
+
+    pub fn ca2_output(&self) -> bool {
+        self.ca2_out
+    }
+
+    /** Returns the CB2 level, which carries shift register data when shifting out. */
+    pub fn cb2_output(&self) -> bool {
+        if self.acr & 0x10 != 0 && self.shift_mode() != 0 {
+            self.sr_out
+        } else {
+            self.cb2_out
+        }
+    }
+
+    /**
+     * Sets the CA1 input. The active edge chosen in the PCR sets the CA1
+     * interrupt flag, latches port A if enabled, and ends a CA2 handshake.
+     */
+    pub fn set_ca1(&mut self, level: bool) {
+        let active = if self.pcr & 0x01 != 0 { !self.ca1 && level } else { self.ca1 && !level };
+        self.ca1 = level;
+        if active {
+            self.ifr |= IRQ_CA1;
+            if self.acr & 0x01 != 0 {
+                self.ira_latch = self.input_a;
+            }
+            if self.pcr & 0x0E == 0x08 {
+                self.ca2_out = true;
+            }
+        }
+    }
+
+    pub fn set_ca2(&mut self, level: bool) {
+        let positive = self.pcr & 0x04 != 0;
+        if self.pcr & 0x08 == 0 && Via::is_edge(self.ca2, level, positive) {
+            self.ifr |= IRQ_CA2;
+        }
+        self.ca2 = level;
+    }
+
+    /**
+     * Sets the CB1 input. Besides its interrupt, CB1 clocks the shift
+     * register when it is under external control.
+     */
+    pub fn set_cb1(&mut self, level: bool) {
+        let active = if self.pcr & 0x10 != 0 { !self.cb1 && level } else { self.cb1 && !level };
+        let mode = self.shift_mode();
+        // External clock: shift in on the rising edge, out on the falling edge
+        let edge = (mode == 3 && !self.cb1 && level) || (mode == 7 && self.cb1 && !level);
+        if edge && self.sr_bits < 8 {
+            self.shift();
+        }
+        self.cb1 = level;
+        if active {
+            self.ifr |= IRQ_CB1;
+            if self.acr & 0x02 != 0 {
+                self.irb_latch = self.input_b;
+            }
+            if self.pcr & 0xE0 == 0x80 {
+                self.cb2_out = true;
+            }
+        }
+    }
+
+    pub fn set_cb2(&mut self, level: bool) {
+        let positive = self.pcr & 0x40 != 0;
+        if self.pcr & 0x80 == 0 && Via::is_edge(self.cb2, level, positive) {
+            self.ifr |= IRQ_CB2;
+        }
+        self.cb2 = level;
+    }
+
+    fn is_edge(old: bool, new: bool, positive: bool) -> bool {
+        if positive { !old && new } else { old && !new }
+    }
+
+    fn shift_mode(&self) -> u8 {
+        (self.acr >> 2) & 0x07
+    }
+
+    /** Returns the number of cycles between shifts for the current mode. */
+    fn shift_period(&self) -> u16 {
+        match self.shift_mode() {
+            2 | 6 => 2,
+            _ => 2 * (self.t2_latch_low as u16 + 2),
+        }
+    }
+
+    fn shift(&mut self) {
+        let mode = self.shift_mode();
+        if mode & 0x04 != 0 {
+            // Shifting out rotates, so free running mode repeats the byte
+            let msb = self.sr >> 7;
+            self.sr = (self.sr << 1) | msb;
+            self.sr_out = msb != 0;
+        } else {
+            self.sr = (self.sr << 1) | self.cb2 as u8;
+        }
+        if mode != 4 {
+            self.sr_bits += 1;
+            if self.sr_bits == 8 {
+                self.ifr |= IRQ_SR;
+            }
+        }
+    }
+
+    fn start_shift(&mut self) {
+        self.ifr &= !IRQ_SR;
+        self.sr_bits = 0;
+        self.sr_timer = self.shift_period();
+        self.fresh |= FRESH_SR;
+    }
+
+    /** CA2 and CB2 flags are not cleared by port access in independent mode. */
+    fn clear_port_a_flags(&mut self) {
+        let independent = self.pcr & 0x0A == 0x02;
+        self.ifr &= !(IRQ_CA1 | if independent { 0 } else { IRQ_CA2 });
+    }
+
+    fn clear_port_b_flags(&mut self) {
+        let independent = self.pcr & 0xA0 == 0x20;
+        self.ifr &= !(IRQ_CB1 | if independent { 0 } else { IRQ_CB2 });
+    }
+
+    /** Starts a handshake or pulse on CA2 after port A is accessed. */
+    fn port_a_handshake(&mut self) {
+        match self.pcr & 0x0E {
+            0x08 => self.ca2_out = false,
+            0x0A => {
+                self.ca2_out = false;
+                self.ca2_pulse = true;
+                self.fresh |= FRESH_CA2;
+            },
+            _ => {},
+        }
+    }
+
+    fn port_b_handshake(&mut self) {
+        match self.pcr & 0xE0 {
+            0x80 => self.cb2_out = false,
+            0xA0 => {
+                self.cb2_out = false;
+                self.cb2_pulse = true;
+                self.fresh |= FRESH_CB2;
+            },
+            _ => {},
+        }
+    }
+
+    fn read_port_a(&self) -> u8 {
+        if self.acr & 0x01 != 0 {
+            (self.ora & self.ddra) | (self.ira_latch & !self.ddra)
+        } else {
+            self.port_a()
+        }
+    }
+
+    /** Output bits of port B read back from ORB rather than the pins. */
+    fn read_port_b(&self) -> u8 {
+        let input = if self.acr & 0x02 != 0 { self.irb_latch } else { self.input_b };
+        let value = (self.orb & self.ddrb) | (input & !self.ddrb);
+        if self.acr & 0x80 != 0 {
+            (value & 0x7F) | if self.pb7 { 0x80 } else { 0 }
+        } else {
+            value
+        }
+    }
+
+    fn step_t1(&mut self) {
+        if self.t1_reload {
+            self.t1_counter = self.t1_latch;
+            self.t1_reload = false;
+        } else if self.t1_counter == 0 {
+            self.t1_counter = 0xFFFF;
+            if self.acr & 0x40 != 0 {
+                // Free running: interrupt every time and reload from the latch
+                self.t1_reload = true;
+                self.ifr |= IRQ_T1;
+                self.pb7 = !self.pb7;
+            } else if self.t1_armed {
+                self.t1_armed = false;
+                self.ifr |= IRQ_T1;
+                self.pb7 = true;
+            }
+        } else {
+            self.t1_counter -= 1;
+        }
+    }
+
+    fn step_t2(&mut self) {
+        if self.t2_counter == 0 && self.t2_armed {
+            self.t2_armed = false;
+            self.ifr |= IRQ_T2;
+        }
+        self.t2_counter = self.t2_counter.wrapping_sub(1);
+    }
+
+    fn step_shift(&mut self) {
+        let mode = self.shift_mode();
+        if mode == 0 || mode == 3 || mode == 7 || (self.sr_bits >= 8 && mode != 4) {
+            return;
+        }
+        self.sr_timer = self.sr_timer.saturating_sub(1);
+        if self.sr_timer == 0 {
+            self.shift();
+            self.sr_timer = self.shift_period();
+        }
+    }
+}
+
+impl Default for Via {
+    fn default() -> Self {
+        Via::new()
+    }
+}
+
+impl Memory for Via {
+    fn get(&mut self, address: u16) -> u8 {
+        let value = self.peek(address);
+        match address & 0x0F {
+            ORB => self.clear_port_b_flags(),
+            ORA => {
+                self.clear_port_a_flags();
+                self.port_a_handshake();
+            },
+            T1C_L => self.ifr &= !IRQ_T1,
+            T2C_L => self.ifr &= !IRQ_T2,
+            SR => self.start_shift(),
+            _ => {},
+        }
+        value
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        match address & 0x0F {
+            ORB => self.read_port_b(),
+            ORA => self.read_port_a(),
+            DDRB => self.ddrb,
+            DDRA => self.ddra,
+            T1C_L => self.t1_counter as u8,
+            T1C_H => (self.t1_counter >> 8) as u8,
+            T1L_L => self.t1_latch as u8,
+            T1L_H => (self.t1_latch >> 8) as u8,
+            T2C_L => self.t2_counter as u8,
+            T2C_H => (self.t2_counter >> 8) as u8,
+            SR => self.sr,
+            ACR => self.acr,
+            PCR => self.pcr,
+            IFR => self.ifr | if self.irq() { 0x80 } else { 0 },
+            IER => self.ier | 0x80,
+            ORA_NO_HANDSHAKE => self.read_port_a(),
+            _ => 0,
+        }
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        match address & 0x0F {
+            ORB => {
+                self.orb = value;
+                self.clear_port_b_flags();
+                self.port_b_handshake();
+            },
+            ORA => {
+                self.ora = value;
+                self.clear_port_a_flags();
+                self.port_a_handshake();
+            },
+            DDRB => self.ddrb = value,
+            DDRA => self.ddra = value,
+            T1C_L | T1L_L => self.t1_latch = (self.t1_latch & 0xFF00) | value as u16,
+            T1C_H => {
+                self.t1_latch = (self.t1_latch & 0x00FF) | (value as u16) << 8;
+                self.t1_counter = self.t1_latch;
+                self.t1_armed = true;
+                self.t1_reload = false;
+                self.ifr &= !IRQ_T1;
+                self.pb7 = false;
+                self.fresh |= FRESH_T1;
+            },
+            T1L_H => {
+                self.t1_latch = (self.t1_latch & 0x00FF) | (value as u16) << 8;
+                self.ifr &= !IRQ_T1;
+            },
+            T2C_L => self.t2_latch_low = value,
+            T2C_H => {
+                self.t2_counter = (value as u16) << 8 | self.t2_latch_low as u16;
+                self.t2_armed = true;
+                self.ifr &= !IRQ_T2;
+                self.fresh |= FRESH_T2;
+            },
+            SR => {
+                self.sr = value;
+                self.start_shift();
+            },
+            ACR => self.acr = value,
+            PCR => {
+                self.pcr = value;
+                self.ca2_out = self.pcr & 0x0E != 0x0C;
+                self.cb2_out = self.pcr & 0xE0 != 0xC0;
+            },
+            IFR => self.ifr &= !(value & 0x7F),
+            IER => if value & 0x80 != 0 {
+                self.ier |= value & 0x7F;
+            } else {
+                self.ier &= !(value & 0x7F);
+            },
+            ORA_NO_HANDSHAKE => self.ora = value,
+            _ => {},
+        }
+    }
+}
+
+impl Device for Via {
+    fn name(&self) -> String {
+        "6522 VIA".to_string()
+    }
+
+    fn status(&self) -> String {
+        format!("IFR: {:02X} IER: {:02X} T1: {:04X} T2: {:04X} PA: {:02X} PB: {:02X}",
+            self.ifr, self.ier, self.t1_counter, self.t2_counter, self.port_a(), self.port_b())
+    }
+
+    fn size(&self) -> u16 {
+        16
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        let fresh = self.fresh;
+        self.fresh = 0;
+        for _ in 0..cycles {
+            if fresh & FRESH_CA2 == 0 && self.ca2_pulse {
+                self.ca2_pulse = false;
+                self.ca2_out = true;
+            }
+            if fresh & FRESH_CB2 == 0 && self.cb2_pulse {
+                self.cb2_pulse = false;
+                self.cb2_out = true;
+            }
+            if fresh & FRESH_T1 == 0 {
+                self.step_t1();
+            }
+            if fresh & FRESH_T2 == 0 && self.acr & 0x20 == 0 {
+                self.step_t2();
+            }
+            if fresh & FRESH_SR == 0 {
+                self.step_shift();
+            }
+        }
+    }
+
+    /** Reset clears the I/O registers but not the timers or shift register. */
+    fn reset(&mut self) {
+        let t1 = (self.t1_counter, self.t1_latch);
+        let t2 = (self.t2_counter, self.t2_latch_low);
+        let (sr, input_a, input_b) = (self.sr, self.input_a, self.input_b);
+        *self = Via::new();
+        self.t1_counter = t1.0;
+        self.t1_latch = t1.1;
+        self.t2_counter = t2.0;
+        self.t2_latch_low = t2.1;
+        self.sr = sr;
+        self.input_a = input_a;
+        self.input_b = input_b;
+    }
+
+    fn irq(&self) -> bool {
+        self.ifr & self.ier & 0x7F != 0
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let flags = [self.t1_armed, self.t1_reload, self.t2_armed, self.pb7, self.ca2_out,
+            self.cb2_out, self.ca2_pulse, self.cb2_pulse, self.sr_out, self.ca1, self.ca2,
+            self.cb1, self.cb2];
+        let mut state = vec![self.ora, self.orb, self.ddra, self.ddrb, self.ira_latch,
+            self.irb_latch, self.t2_latch_low, self.sr, self.sr_bits, self.acr, self.pcr,
+            self.ifr, self.ier, self.input_a, self.input_b, self.fresh];
+        for word in [self.t1_counter, self.t1_latch, self.t2_counter, self.sr_timer].iter() {
+            state.extend_from_slice(&word.to_le_bytes());
+        }
+        state.extend(flags.iter().map(|f| *f as u8));
+        state
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        if state.len() != 37 {
+            return;
+        }
+        let word = |i: usize| u16::from_le_bytes([state[i], state[i + 1]]);
+        self.ora = state[0];
+        self.orb = state[1];
+        self.ddra = state[2];
+        self.ddrb = state[3];
+        self.ira_latch = state[4];
+        self.irb_latch = state[5];
+        self.t2_latch_low = state[6];
+        self.sr = state[7];
+        self.sr_bits = state[8];
+        self.acr = state[9];
+        self.pcr = state[10];
+        self.ifr = state[11];
+        self.ier = state[12];
+        self.input_a = state[13];
+        self.input_b = state[14];
+        self.fresh = state[15];
+        self.t1_counter = word(16);
+        self.t1_latch = word(18);
+        self.t2_counter = word(20);
+        self.sr_timer = word(22);
+        let flag = |i: usize| state[24 + i] != 0;
+        self.t1_armed = flag(0);
+        self.t1_reload = flag(1);
+        self.t2_armed = flag(2);
+        self.pb7 = flag(3);
+        self.ca2_out = flag(4);
+        self.cb2_out = flag(5);
+        self.ca2_pulse = flag(6);
+        self.cb2_pulse = flag(7);
+        self.sr_out = flag(8);
+        self.ca1 = flag(9);
+        self.ca2 = flag(10);
+        self.cb1 = flag(11);
+        self.cb2 = flag(12);
+    }
+}
@@ -0,0 +1,113 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::addressing::Addressing::*;
+use crate::cpu::Cpu;
+use crate::instruction::Instruction;
+use crate::instruction::InstructionType;
+use crate::memory::Memory;
+use crate::symbols::Symbols;
+
+pub fn mnemonic(t: InstructionType) -> String {
+    format!("{:?}", t).to_uppercase()
+}
+
+/** Returns the target of a relative branch located at the given address. */
+pub fn branch_target(addr: u16, offset: i8) -> u16 {
+    addr.overflowing_add(2).0.overflowing_add(offset as u16).0
+}
+
+fn word(v: u16, symbols: &Symbols) -> String {
+    match symbols.name(v) {
+        Some(name) => name.to_string(),
+        None => format!("${:04X}", v),
+    }
+}
+
+fn byte(v: u8, symbols: &Symbols) -> String {
+    match symbols.name(v as u16) {
+        Some(name) => name.to_string(),
+        None => format!("${:02X}", v),
+    }
+}
+
+/**
+ * Formats an instruction in assembler syntax, replacing addresses with
+ * symbol names where there is one. The address of the instruction is
+ * needed to resolve relative branch targets.
+ */
+pub fn format_instruction(i: &Instruction, addr: u16, symbols: &Symbols) -> String {
+    let m = mnemonic(i.t);
+    match i.a {
+        Accumulator => format!("{} A", m),
+        Absolute(v) => format!("{} {}", m, word(v, symbols)),
+        AbsoluteX(v) => format!("{} {},X", m, word(v, symbols)),
+        AbsoluteY(v) => format!("{} {},Y", m, word(v, symbols)),
+        Immediate(v) => format!("{} #${:02X}", m, v),
+        Implied => m,
+        Indirect(v) => format!("{} ({})", m, word(v, symbols)),
+        IndirectX(v) => format!("{} ({},X)", m, byte(v, symbols)),
+        IndirectY(v) => format!("{} ({}),Y", m, byte(v, symbols)),
+        Relative(o) => format!("{} {}", m, word(branch_target(addr, o), symbols)),
+        ZeroPage(v) => format!("{} {}", m, byte(v, symbols)),
+        ZeroPageX(v) => format!("{} {},X", m, byte(v, symbols)),
+        ZeroPageY(v) => format!("{} {},Y", m, byte(v, symbols)),
+    }
+}
+
+/**
+ * Disassembles the instruction at the given address.
+ * Returns the text and the length of the instruction in bytes.
+ */
+pub fn disassemble(cpu: &Cpu, addr: u16) -> (String, u16) {
+    let (i, len) = cpu.decode(addr);
+    (format_instruction(&i, addr, &cpu.symbols), len)
+}
+
+/**
+ * Disassembles the instruction at the given address as a listing line
+ * containing the address, the raw bytes, and the instruction.
+ */
+pub fn disassemble_line(cpu: &Cpu, addr: u16) -> (String, u16) {
+    let (text, len) = disassemble(cpu, addr);
+    let mut bytes = String::new();
+    for offset in 0..len {
+        let b = cpu.peek(addr.overflowing_add(offset).0);
+        bytes.push_str(&format!("{:02X} ", b));
+    }
+    (format!("{:04X}  {:9} {}", addr, bytes, text), len)
+}
+
+/**
+ * Disassembles count instructions starting at the given address.
+ * Addresses with a symbol are preceded by a label line.
+ */
+pub fn disassemble_range(cpu: &Cpu, addr: u16, count: usize) -> Vec<String> {
+    let mut lines = Vec::with_capacity(count);
+    let mut pc = addr;
+    for _ in 0..count {
+        if let Some(name) = cpu.symbols.name(pc) {
+            lines.push(format!("{}:", name));
+        }
+        let (line, len) = disassemble_line(cpu, pc);
+        lines.push(line);
+        pc = pc.overflowing_add(len).0;
+    }
+    lines
+}
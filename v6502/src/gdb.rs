@@ -0,0 +1,497 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! A GDB remote serial protocol stub.
+//!
+//! Registers are numbered A, X, Y, P, SP, PC. All of them are 8 bits wide
+//! except for PC, which is 16 bits and sent little endian.
+
+use std::collections::BTreeSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::cpu::Cpu;
+use crate::memory::Memory;
+
+const TARGET_XML: &str = "<?xml version=\"1.0\"?>\
+<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+<target version=\"1.0\">\
+<feature name=\"org.vaelen.v6502.cpu\">\
+<reg name=\"a\" bitsize=\"8\" regnum=\"0\"/>\
+<reg name=\"x\" bitsize=\"8\" regnum=\"1\"/>\
+<reg name=\"y\" bitsize=\"8\" regnum=\"2\"/>\
+<reg name=\"p\" bitsize=\"8\" regnum=\"3\"/>\
+<reg name=\"sp\" bitsize=\"8\" regnum=\"4\"/>\
+<reg name=\"pc\" bitsize=\"16\" regnum=\"5\" type=\"code_ptr\"/>\
+</feature>\
+</target>";
+
+/** How often the client is polled for an interrupt while running. */
+const INTERRUPT_POLL_INTERVAL: usize = 1000;
+
+/** The largest memory read, so that the reply fits in the advertised PacketSize. */
+const MAX_MEMORY_READ: usize = 0x2000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/** A connection to a GDB client. */
+pub trait Connection: Read + Write {
+    /**
+     * Returns true if the client has sent an interrupt request (Ctrl-C).
+     * Must not block.
+     */
+    fn interrupted(&mut self) -> io::Result<bool>;
+}
+
+impl Connection for TcpStream {
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let mut buf = [0];
+        let result = match self.peek(&mut buf) {
+            Ok(1) if buf[0] == 0x03 => self.read(&mut buf).map(|_| true),
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        };
+        self.set_nonblocking(false)?;
+        result
+    }
+}
+
+#[cfg(unix)]
+impl Connection for std::os::unix::net::UnixStream {
+    fn interrupted(&mut self) -> io::Result<bool> {
+        use std::os::unix::io::AsRawFd;
+        // UnixStream::peek isn't stable, so peek without blocking using recv
+        let mut buf = [0u8];
+        // SAFETY: the descriptor is owned by the open stream, and buf is a
+        // live one-byte buffer, matching the length passed to recv
+        let n = unsafe {
+            libc::recv(self.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, 1,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT)
+        };
+        match n {
+            1 if buf[0] == 0x03 => self.read(&mut buf).map(|_| true),
+            n if n >= 0 => Ok(false),
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.kind() == ErrorKind::WouldBlock { Ok(false) } else { Err(e) }
+            },
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Packet {
+    Command(String),
+    Interrupt,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Stop {
+    Step,
+    SoftwareBreakpoint,
+    HardwareBreakpoint,
+    Break,
+    Interrupt,
+    /// A device stopped the program with an exit code
+    Exited(u8),
+}
+
+pub struct GdbServer {
+    pub sw_breakpoints: BTreeSet<u16>,
+    pub hw_breakpoints: BTreeSet<u16>,
+    no_ack: bool,
+    last_stop: Stop,
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() & 1 != 0 {
+        return None;
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|h| u8::from_str_radix(h, 16).ok()))
+        .collect()
+}
+
+fn parse_hex(s: &str) -> Option<usize> {
+    usize::from_str_radix(s, 16).ok()
+}
+
+/** Parses "addr,len" into an address and a length. */
+fn parse_range(s: &str) -> Option<(u16, usize)> {
+    let mut parts = s.splitn(2, ',');
+    let addr = parse_hex(parts.next()?)?;
+    let len = parse_hex(parts.next()?)?;
+    if addr > 0xFFFF {
+        return None;
+    }
+    Some((addr as u16, len))
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b))
+}
+
+impl GdbServer {
+    pub fn new() -> GdbServer {
+        GdbServer {
+            sw_breakpoints: BTreeSet::new(),
+            hw_breakpoints: BTreeSet::new(),
+            no_ack: false,
+            last_stop: Stop::Step,
+        }
+    }
+
+    /** Accepts a single client on a TCP address and serves it. */
+    pub fn listen_tcp(&mut self, cpu: &mut Cpu, address: &str) -> io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        eprintln!("Waiting for GDB on {}", listener.local_addr()?);
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        self.serve(cpu, stream)
+    }
+
+    /** Accepts a single client on a Unix domain socket and serves it. */
+    #[cfg(unix)]
+    pub fn listen_unix(&mut self, cpu: &mut Cpu, path: &str) -> io::Result<()> {
+        use std::os::unix::net::UnixListener;
+        let listener = UnixListener::bind(path)?;
+        eprintln!("Waiting for GDB on {}", path);
+        let result = listener.accept().and_then(|(stream, _)| self.serve(cpu, stream));
+        std::fs::remove_file(path)?;
+        result
+    }
+
+    /** Serves a connected client until it detaches or disconnects. */
+    pub fn serve<C: Connection>(&mut self, cpu: &mut Cpu, mut conn: C) -> io::Result<()> {
+        self.no_ack = false;
+        loop {
+            let packet = match self.read_packet(&mut conn)? {
+                Some(packet) => packet,
+                None => return Ok(()),
+            };
+            let command = match packet {
+                Packet::Command(command) => command,
+                Packet::Interrupt => {
+                    self.last_stop = Stop::Interrupt;
+                    self.send(&mut conn, &self.stop_reply())?;
+                    continue;
+                },
+            };
+            match command.as_bytes().first() {
+                Some(b'D') => {
+                    self.send(&mut conn, "OK")?;
+                    return Ok(());
+                },
+                Some(b'k') => return Ok(()),
+                Some(b'c') | Some(b's') => {
+                    if let Some(addr) = command.get(1..).and_then(parse_hex) {
+                        cpu.pc = addr as u16;
+                    }
+                    self.last_stop = if command.starts_with('c') {
+                        self.cont(cpu, &mut conn)?
+                    } else {
+                        self.step(cpu)
+                    };
+                    self.send(&mut conn, &self.stop_reply())?;
+                },
+                _ => {
+                    let reply = self.handle(cpu, &command);
+                    self.send(&mut conn, &reply)?;
+                },
+            }
+        }
+    }
+
+    fn step(&mut self, cpu: &mut Cpu) -> Stop {
+        cpu.execute_next_instruction();
+        if cpu.is_break() {
+            GdbServer::break_stop(cpu)
+        } else {
+            Stop::Step
+        }
+    }
+
+    /** Returns why the program stopped on the break flag. */
+    fn break_stop(cpu: &Cpu) -> Stop {
+        match cpu.exit_code {
+            Some(code) => Stop::Exited(code),
+            None => Stop::Break,
+        }
+    }
+
+    fn cont<C: Connection>(&mut self, cpu: &mut Cpu, conn: &mut C) -> io::Result<Stop> {
+        let mut count = 0;
+        loop {
+            cpu.execute_next_instruction();
+            if cpu.is_break() {
+                return Ok(GdbServer::break_stop(cpu));
+            }
+            if self.hw_breakpoints.contains(&cpu.pc) {
+                return Ok(Stop::HardwareBreakpoint);
+            }
+            if self.sw_breakpoints.contains(&cpu.pc) {
+                return Ok(Stop::SoftwareBreakpoint);
+            }
+            count += 1;
+            if count % INTERRUPT_POLL_INTERVAL == 0 && conn.interrupted()? {
+                return Ok(Stop::Interrupt);
+            }
+        }
+    }
+
+    fn stop_reply(&self) -> String {
+        match self.last_stop {
+            Stop::Step | Stop::Break => format!("S{:02x}", SIGTRAP),
+            Stop::SoftwareBreakpoint => format!("T{:02x}swbreak:;", SIGTRAP),
+            Stop::HardwareBreakpoint => format!("T{:02x}hwbreak:;", SIGTRAP),
+            Stop::Interrupt => format!("S{:02x}", SIGINT),
+            Stop::Exited(code) => format!("W{:02x}", code),
+        }
+    }
+
+    fn registers(cpu: &Cpu) -> Vec<u8> {
+        vec![cpu.a, cpu.x, cpu.y, cpu.sr, cpu.sp, cpu.pc as u8, (cpu.pc >> 8) as u8]
+    }
+
+    fn set_register(cpu: &mut Cpu, n: usize, value: &[u8]) -> bool {
+        match (n, value) {
+            (0, [v]) => cpu.a = *v,
+            (1, [v]) => cpu.x = *v,
+            (2, [v]) => cpu.y = *v,
+            (3, [v]) => cpu.sr = *v,
+            (4, [v]) => cpu.sp = *v,
+            (5, [l, h]) => cpu.pc = (*h as u16) << 8 | *l as u16,
+            _ => return false,
+        }
+        true
+    }
+
+    fn set_breakpoint(&mut self, args: &str, insert: bool) -> String {
+        let mut parts = args.splitn(3, ',');
+        let kind = parts.next();
+        let addr = match parts.next().and_then(parse_hex) {
+            Some(addr) if addr <= 0xFFFF => addr as u16,
+            _ => return "E01".to_string(),
+        };
+        let set = match kind {
+            Some("0") => &mut self.sw_breakpoints,
+            Some("1") => &mut self.hw_breakpoints,
+            // Watchpoints are not supported
+            _ => return String::new(),
+        };
+        if insert {
+            set.insert(addr);
+        } else {
+            set.remove(&addr);
+        }
+        "OK".to_string()
+    }
+
+    fn handle(&mut self, cpu: &mut Cpu, command: &str) -> String {
+        let head = match command.chars().next() {
+            Some(c) => c,
+            None => return String::new(),
+        };
+        let args = &command[head.len_utf8()..];
+        match head {
+            '?' => self.stop_reply(),
+            'g' => to_hex(&GdbServer::registers(cpu)),
+            'G' => match from_hex(args) {
+                Some(data) if data.len() == 7 => {
+                    for (n, value) in data[..5].iter().enumerate() {
+                        GdbServer::set_register(cpu, n, &[*value]);
+                    }
+                    GdbServer::set_register(cpu, 5, &data[5..]);
+                    "OK".to_string()
+                },
+                _ => "E01".to_string(),
+            },
+            'p' => {
+                let regs = GdbServer::registers(cpu);
+                match parse_hex(args) {
+                    Some(n) if n < 5 => to_hex(&regs[n..n + 1]),
+                    Some(5) => to_hex(&regs[5..7]),
+                    _ => "E01".to_string(),
+                }
+            },
+            'P' => {
+                let mut parts = args.splitn(2, '=');
+                let n = parts.next().and_then(parse_hex);
+                let value = parts.next().and_then(from_hex);
+                match (n, value) {
+                    (Some(n), Some(value)) if GdbServer::set_register(cpu, n, &value) => "OK".to_string(),
+                    _ => "E01".to_string(),
+                }
+            },
+            'm' => match parse_range(args) {
+                Some((addr, len)) => {
+                    // Stop at the end of memory and at the largest reply
+                    let len = len.min(0x10000 - addr as usize).min(MAX_MEMORY_READ);
+                    let data: Vec<u8> = (0..len)
+                        .map(|i| cpu.peek(addr + i as u16))
+                        .collect();
+                    to_hex(&data)
+                },
+                None => "E01".to_string(),
+            },
+            'M' => {
+                let mut parts = args.splitn(2, ':');
+                let range = parts.next().and_then(parse_range);
+                let data = parts.next().and_then(from_hex);
+                match (range, data) {
+                    (Some((addr, len)), Some(data)) if data.len() == len => {
+                        for (i, b) in data.iter().enumerate() {
+                            cpu.set(addr.wrapping_add(i as u16), *b);
+                        }
+                        "OK".to_string()
+                    },
+                    _ => "E01".to_string(),
+                }
+            },
+            'Z' => self.set_breakpoint(args, true),
+            'z' => self.set_breakpoint(args, false),
+            'H' => "OK".to_string(),
+            'T' => "OK".to_string(),
+            'q' | 'Q' => self.query(command),
+            _ => String::new(),
+        }
+    }
+
+    fn query(&mut self, command: &str) -> String {
+        if command.starts_with("qSupported") {
+            "PacketSize=4000;QStartNoAckMode+;qXfer:features:read+;swbreak+;hwbreak+".to_string()
+        } else if command == "QStartNoAckMode" {
+            self.no_ack = true;
+            "OK".to_string()
+        } else if command == "qAttached" {
+            "1".to_string()
+        } else if command == "qC" {
+            "QC1".to_string()
+        } else if command == "qfThreadInfo" {
+            "m1".to_string()
+        } else if command == "qsThreadInfo" {
+            "l".to_string()
+        } else if let Some(args) = command.strip_prefix("qXfer:features:read:target.xml:") {
+            match parse_range(args) {
+                Some((offset, len)) => {
+                    let start = (offset as usize).min(TARGET_XML.len());
+                    let end = (start + len).min(TARGET_XML.len());
+                    let prefix = if end < TARGET_XML.len() { "m" } else { "l" };
+                    format!("{}{}", prefix, &TARGET_XML[start..end])
+                },
+                None => "E01".to_string(),
+            }
+        } else {
+            String::new()
+        }
+    }
+
+    fn read_byte<C: Connection>(conn: &mut C) -> io::Result<Option<u8>> {
+        let mut buf = [0];
+        loop {
+            match conn.read(&mut buf) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(buf[0])),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /** Reads the next packet, returning None when the client disconnects. */
+    fn read_packet<C: Connection>(&mut self, conn: &mut C) -> io::Result<Option<Packet>> {
+        loop {
+            match GdbServer::read_byte(conn)? {
+                None => return Ok(None),
+                Some(0x03) => return Ok(Some(Packet::Interrupt)),
+                Some(b'$') => {},
+                // Acknowledgements and line noise
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match GdbServer::read_byte(conn)? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                }
+            }
+            let mut sum = [0u8; 2];
+            for b in sum.iter_mut() {
+                *b = match GdbServer::read_byte(conn)? {
+                    Some(b) => b,
+                    None => return Ok(None),
+                };
+            }
+            let data = String::from_utf8_lossy(&data).into_owned();
+            let expected = std::str::from_utf8(&sum).ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+            if self.no_ack {
+                return Ok(Some(Packet::Command(data)));
+            }
+            if expected == Some(checksum(&data)) {
+                conn.write_all(b"+")?;
+                return Ok(Some(Packet::Command(data)));
+            }
+            conn.write_all(b"-")?;
+        }
+    }
+
+    fn send<C: Connection>(&self, conn: &mut C, data: &str) -> io::Result<()> {
+        let mut escaped = String::with_capacity(data.len());
+        for c in data.chars() {
+            match c {
+                '$' | '#' | '}' | '*' => {
+                    escaped.push('}');
+                    escaped.push(((c as u8) ^ 0x20) as char);
+                },
+                _ => escaped.push(c),
+            }
+        }
+        write!(conn, "${}#{:02x}", escaped, checksum(&escaped))?;
+        conn.flush()?;
+        if !self.no_ack {
+            // Wait for the acknowledgement, resending if asked to
+            loop {
+                match GdbServer::read_byte(conn)? {
+                    Some(b'+') | None => break,
+                    Some(b'-') => {
+                        write!(conn, "${}#{:02x}", escaped, checksum(&escaped))?;
+                        conn.flush()?;
+                    },
+                    Some(_) => {},
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for GdbServer {
+    fn default() -> Self {
+        GdbServer::new()
+    }
+}
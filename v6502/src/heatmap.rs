@@ -0,0 +1,250 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::BTreeMap;
+
+use crate::cpu::Cpu;
+use crate::memory::AccessKind;
+use crate::observer::Observer;
+
+/// Bytes shown by each character of the text map
+const BYTES_PER_CHAR: usize = 4;
+/// Characters in each line of the text map
+const CHARS_PER_LINE: usize = 64;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Usage {
+    pub reads: u64,
+    pub writes: u64,
+    /// Number of times an instruction started at this address
+    pub executions: u64,
+    /// True if the byte was fetched as part of an instruction
+    pub code: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RegionKind {
+    Code,
+    Data,
+}
+
+/** A run of addresses that were all used as code or all used as data. */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Region {
+    pub start: u16,
+    pub end: u16,
+    pub kind: RegionKind,
+}
+
+/**
+ * Records how often each address is read, written, and executed, and
+ * which instructions modify code that is executed.
+ */
+pub struct Heatmap {
+    pub usage: Vec<Usage>,
+    /// Modified code addresses and the last instruction that wrote them
+    pub self_modifying: BTreeMap<u16, u16>,
+    last_writer: Vec<Option<u16>>,
+    pending: Option<u16>,
+}
+
+/** Scales a count to 0-255 on a log scale so that rare accesses remain visible. */
+fn intensity(count: u64, max: u64) -> u8 {
+    if count == 0 || max == 0 {
+        return 0;
+    }
+    let scaled = ((count as f64).ln_1p() / (max as f64).ln_1p() * 191.0) as u8;
+    64 + scaled
+}
+
+impl Heatmap {
+    pub fn new() -> Heatmap {
+        Heatmap {
+            usage: vec![Usage::default(); 0x10000],
+            self_modifying: BTreeMap::new(),
+            last_writer: vec![None; 0x10000],
+            pending: None,
+        }
+    }
+
+    /** Turns on the memory access log that the heatmap reads. */
+    pub fn attach(&self, cpu: &mut Cpu) {
+        cpu.accesses = Some(Vec::new());
+    }
+
+    fn is_used(&self, address: usize) -> bool {
+        let u = &self.usage[address];
+        u.reads > 0 || u.writes > 0 || u.code
+    }
+
+    /**
+     * Returns the character for a group of bytes in the text map, in order
+     * of precedence: M for self-modified code, X for code, W for written,
+     * R for read, and . for unused.
+     */
+    fn symbol(&self, start: usize, len: usize) -> char {
+        let range = start..start + len;
+        if range.clone().any(|a| self.self_modifying.contains_key(&(a as u16))) {
+            'M'
+        } else if range.clone().any(|a| self.usage[a].code) {
+            'X'
+        } else if range.clone().any(|a| self.usage[a].writes > 0) {
+            'W'
+        } else if range.clone().any(|a| self.usage[a].reads > 0) {
+            'R'
+        } else {
+            '.'
+        }
+    }
+
+    /** Splits the used addresses into contiguous code and data regions. */
+    pub fn regions(&self) -> Vec<Region> {
+        let mut regions: Vec<Region> = Vec::new();
+        for address in (0..0x10000).filter(|a| self.is_used(*a)) {
+            let kind = if self.usage[address].code { RegionKind::Code } else { RegionKind::Data };
+            match regions.last_mut() {
+                Some(r) if r.kind == kind && r.end as usize + 1 == address => r.end = address as u16,
+                _ => regions.push(Region { start: address as u16, end: address as u16, kind }),
+            }
+        }
+        regions
+    }
+
+    /**
+     * Produces a text map of memory with one character per four bytes and
+     * one line per 256 bytes, followed by the code and data regions and
+     * any self-modifying code. Unused lines are skipped.
+     */
+    pub fn text_map(&self) -> String {
+        let mut out = String::from("M = self-modified code, X = code, W = written, R = read, . = unused\n\n");
+        let line_bytes = BYTES_PER_CHAR * CHARS_PER_LINE;
+        let mut skipped = false;
+        for line in (0..0x10000).step_by(line_bytes) {
+            if !(line..line + line_bytes).any(|a| self.is_used(a)) {
+                if !skipped {
+                    out.push_str("...\n");
+                    skipped = true;
+                }
+                continue;
+            }
+            skipped = false;
+            out.push_str(&format!("{:04X}  ", line));
+            for c in 0..CHARS_PER_LINE {
+                out.push(self.symbol(line + c * BYTES_PER_CHAR, BYTES_PER_CHAR));
+            }
+            out.push('\n');
+        }
+
+        out.push_str("\nRegions:\n");
+        for r in self.regions() {
+            let range = r.start as usize..=r.end as usize;
+            let reads: u64 = range.clone().map(|a| self.usage[a].reads).sum();
+            let writes: u64 = range.clone().map(|a| self.usage[a].writes).sum();
+            let executions: u64 = range.map(|a| self.usage[a].executions).sum();
+            let kind = match r.kind {
+                RegionKind::Code => "code",
+                RegionKind::Data => "data",
+            };
+            out.push_str(&format!("{:04X}-{:04X}  {}  reads: {}  writes: {}  executions: {}\n",
+                r.start, r.end, kind, reads, writes, executions));
+        }
+
+        if !self.self_modifying.is_empty() {
+            out.push_str("\nSelf-modifying code:\n");
+            for (address, writer) in &self.self_modifying {
+                out.push_str(&format!("{:04X}  written by {:04X}\n", address, writer));
+            }
+        }
+        out
+    }
+
+    /**
+     * Produces a 256x256 grayscale PGM image of how often each address was
+     * used. Each row is one page of memory.
+     */
+    pub fn pgm(&self) -> Vec<u8> {
+        let total = |u: &Usage| u.reads + u.writes + u.executions;
+        let max = self.usage.iter().map(total).max().unwrap_or(0);
+        let mut image = b"P5\n256 256\n255\n".to_vec();
+        image.extend(self.usage.iter().map(|u| intensity(total(u), max)));
+        image
+    }
+
+    /**
+     * Produces a 256x256 PPM image where red shows writes, green shows
+     * reads, and blue shows executions. Each row is one page of memory.
+     */
+    pub fn ppm(&self) -> Vec<u8> {
+        let max_reads = self.usage.iter().map(|u| u.reads).max().unwrap_or(0);
+        let max_writes = self.usage.iter().map(|u| u.writes).max().unwrap_or(0);
+        let max_executions = self.usage.iter().map(|u| u.executions).max().unwrap_or(0);
+        let mut image = b"P6\n256 256\n255\n".to_vec();
+        for u in &self.usage {
+            image.push(intensity(u.writes, max_writes));
+            image.push(intensity(u.reads, max_reads));
+            // Operand bytes are shown dimly so whole instructions are visible
+            image.push(match (u.executions, u.code) {
+                (0, true) => 64,
+                (n, _) => intensity(n, max_executions),
+            });
+        }
+        image
+    }
+}
+
+impl Observer for Heatmap {
+    fn before(&mut self, cpu: &Cpu) {
+        let (_, len) = cpu.decode(cpu.pc);
+        self.usage[cpu.pc as usize].executions += 1;
+        for offset in 0..len {
+            let address = cpu.pc.wrapping_add(offset);
+            self.usage[address as usize].code = true;
+            if let Some(writer) = self.last_writer[address as usize] {
+                self.self_modifying.insert(address, writer);
+            }
+        }
+        self.pending = Some(cpu.pc);
+    }
+
+    fn after(&mut self, cpu: &Cpu) {
+        let pc = match self.pending.take() {
+            Some(pc) => pc,
+            None => return,
+        };
+        for access in cpu.accesses.iter().flatten() {
+            let usage = &mut self.usage[access.address as usize];
+            match access.kind {
+                AccessKind::Read => usage.reads += 1,
+                AccessKind::Write => {
+                    usage.writes += 1;
+                    self.last_writer[access.address as usize] = Some(pc);
+                    if usage.code {
+                        self.self_modifying.insert(access.address, pc);
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl Default for Heatmap {
+    fn default() -> Self {
+        Heatmap::new()
+    }
+}
@@ -0,0 +1,174 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+
+use crate::cpu::Registers;
+use crate::memory::{UnmappedAccess, WriteFault};
+
+/** A single memory write made by an instruction. */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Write {
+    pub address: u16,
+    pub old: u8,
+    pub new: u8,
+    /// True if the write went to a device's register rather than RAM
+    pub device: bool,
+}
+
+/** CPU state besides registers and memory that an instruction can change. */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RunState {
+    pub cycles: u64,
+    pub exit_code: Option<u8>,
+    pub fault: Option<WriteFault>,
+    pub unmapped_access: Option<UnmappedAccess>,
+    /// Number of write faults logged so far
+    pub write_faults: usize,
+}
+
+/** Everything needed to undo one instruction. */
+#[derive(Clone, Debug, PartialEq)]
+pub struct UndoEntry {
+    /// Register state before the instruction was executed
+    pub registers: Registers,
+    /// Cycle count and stop reasons before the instruction was executed
+    pub state: RunState,
+    /// Memory writes made by the instruction, in the order they happened
+    pub writes: Vec<Write>,
+}
+
+/** The result of asking who last wrote to an address. */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LastWrite {
+    /// Address of the instruction that made the write
+    pub pc: u16,
+    pub old: u8,
+    pub new: u8,
+    /// How many instructions ago the write happened (0 is the most recent)
+    pub steps_ago: usize,
+}
+
+/**
+ * A bounded ring buffer of undo entries, one per executed instruction.
+ * When the buffer is full the oldest entry is dropped.
+ */
+pub struct History {
+    entries: VecDeque<UndoEntry>,
+    capacity: usize,
+    /// True between begin and end, while an instruction is executing
+    recording: bool,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+            recording: false,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /** Starts a new entry. Called before each instruction is executed. */
+    pub fn begin(&mut self, registers: Registers, state: RunState) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(UndoEntry {
+            registers,
+            state,
+            writes: Vec::new(),
+        });
+        self.recording = true;
+    }
+
+    /** Ends the current entry. Called once an instruction and its interrupts are done. */
+    pub fn end(&mut self) {
+        self.recording = false;
+    }
+
+    /**
+     * Records a memory write against the current entry. Writes made
+     * outside an instruction, such as by a debugger, aren't recorded.
+     */
+    pub fn record_write(&mut self, address: u16, old: u8, new: u8) {
+        self.push(Write { address, old, new, device: false });
+    }
+
+    /**
+     * Records a write to a device that can undo it by having the old value
+     * written back, such as banked RAM.
+     */
+    pub fn record_device_write(&mut self, address: u16, old: u8, new: u8) {
+        self.push(Write { address, old, new, device: true });
+    }
+
+    fn push(&mut self, write: Write) {
+        if !self.recording {
+            return;
+        }
+        if let Some(entry) = self.entries.back_mut() {
+            entry.writes.push(write);
+        }
+    }
+
+    /** Removes and returns the most recent entry. */
+    pub fn pop(&mut self) -> Option<UndoEntry> {
+        self.entries.pop_back()
+    }
+
+    /** Returns the most recent entry without removing it. */
+    pub fn last(&self) -> Option<&UndoEntry> {
+        self.entries.back()
+    }
+
+    /** Finds the most recent recorded write to the given address. */
+    pub fn last_write(&self, address: u16) -> Option<LastWrite> {
+        for (steps_ago, entry) in self.entries.iter().rev().enumerate() {
+            if let Some(w) = entry.writes.iter().rev().find(|w| w.address == address) {
+                return Some(LastWrite {
+                    pc: entry.registers.pc,
+                    old: w.old,
+                    new: w.new,
+                    steps_ago,
+                });
+            }
+        }
+        None
+    }
+}
@@ -18,6 +18,7 @@
  */
 
 use crate::addressing::Addressing;
+use crate::instruction::InstructionType::*;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum InstructionType {
@@ -83,4 +84,55 @@ pub enum InstructionType {
 pub struct Instruction {
     pub t: InstructionType,
     pub a: Addressing,
-}
\ No newline at end of file
+}
+
+impl InstructionType {
+    /** Returns true for instructions that read, modify, and write memory. */
+    pub fn is_read_modify_write(&self) -> bool {
+        matches!(self, Asl | Dec | Inc | Lsr | Rol | Ror)
+    }
+
+    /** Returns true for instructions that only write memory. */
+    pub fn is_store(&self) -> bool {
+        matches!(self, Sta | Stx | Sty)
+    }
+
+    pub fn is_branch(&self) -> bool {
+        matches!(self, Bcc | Bcs | Beq | Bmi | Bne | Bpl | Bvc | Bvs)
+    }
+}
+
+impl Instruction {
+    /**
+     * Returns the number of cycles the instruction takes, not counting
+     * the extra cycles for taken branches and for crossing a page
+     * boundary when indexing.
+     */
+    pub fn base_cycles(&self) -> u8 {
+        let rmw = self.t.is_read_modify_write();
+        let store = self.t.is_store();
+        match self.a {
+            Addressing::Accumulator | Addressing::Immediate(_) | Addressing::Relative(_) => 2,
+            Addressing::Implied => match self.t {
+                Pha | Php => 3,
+                Pla | Plp => 4,
+                Rts | Rti | Jsr => 6,
+                Brk => 7,
+                _ => 2,
+            },
+            Addressing::ZeroPage(_) => if rmw { 5 } else { 3 },
+            Addressing::ZeroPageX(_) | Addressing::ZeroPageY(_) => if rmw { 6 } else { 4 },
+            Addressing::Absolute(_) => match self.t {
+                Jmp => 3,
+                Jsr => 6,
+                _ => if rmw { 6 } else { 4 },
+            },
+            Addressing::AbsoluteX(_) | Addressing::AbsoluteY(_) => {
+                if rmw { 7 } else if store { 5 } else { 4 }
+            },
+            Addressing::Indirect(_) => 5,
+            Addressing::IndirectX(_) => 6,
+            Addressing::IndirectY(_) => if store { 6 } else { 5 },
+        }
+    }
+}
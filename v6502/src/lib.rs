@@ -23,7 +23,22 @@ mod tests;
 pub mod addressing;
 pub mod instruction;
 pub mod opcodes;
+pub mod bus;
+pub mod callstack;
+pub mod coverage;
 pub mod cpu;
+pub mod debugger;
 pub mod device;
+pub mod disassembler;
+pub mod font;
+pub mod gdb;
+pub mod heatmap;
+pub mod history;
 pub mod memory;
+pub mod observer;
+pub mod png;
+pub mod profiler;
+pub mod source_map;
+pub mod symbols;
+pub mod trace;
 pub mod util;
\ No newline at end of file
@@ -17,7 +17,61 @@
     along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use std::fmt;
+
 pub trait Memory {
     fn get(&mut self, address: u16) -> u8;
     fn set(&mut self, address: u16, value: u8);
+    /**
+     * Returns the value a read would return, without the side effects
+     * of a read such as consuming input or clearing status flags. Used
+     * by debuggers and memory dumps.
+     */
+    fn peek(&self, address: u16) -> u8;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/** A memory read or write made by an instruction. */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Access {
+    pub address: u16,
+    pub kind: AccessKind,
+}
+
+/** A write to read-only memory made by the instruction at pc. */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WriteFault {
+    pub pc: u16,
+    pub address: u16,
+    pub value: u8,
+}
+
+impl fmt::Display for WriteFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "write of ${:02X} to read-only ${:04X} by the instruction at ${:04X}",
+            self.value, self.address, self.pc)
+    }
+}
+
+/** A read or write of an address that nothing is mapped at, made by the instruction at pc. */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct UnmappedAccess {
+    pub pc: u16,
+    pub address: u16,
+    pub kind: AccessKind,
+}
+
+impl fmt::Display for UnmappedAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            AccessKind::Read => "read",
+            AccessKind::Write => "write",
+        };
+        write!(f, "{} of unmapped ${:04X} by the instruction at ${:04X}", kind, self.address, self.pc)
+    }
 }
@@ -0,0 +1,47 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::cpu::Cpu;
+
+/** Something that watches instructions as they execute. */
+pub trait Observer {
+    /** Called before the instruction at PC is executed. */
+    fn before(&mut self, _cpu: &Cpu) {}
+
+    /** Called after the instruction has executed. */
+    fn after(&mut self, _cpu: &Cpu) {}
+}
+
+/** Executes one instruction, notifying each observer. */
+pub fn step(cpu: &mut Cpu, observers: &mut [&mut dyn Observer]) {
+    for o in observers.iter_mut() {
+        o.before(cpu);
+    }
+    cpu.execute_next_instruction();
+    for o in observers.iter_mut() {
+        o.after(cpu);
+    }
+}
+
+/** Like Cpu::run, but notifies each observer of every instruction. */
+pub fn run(cpu: &mut Cpu, observers: &mut [&mut dyn Observer]) {
+    while !cpu.is_break() {
+        step(cpu, observers);
+    }
+}
@@ -0,0 +1,191 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::callstack::CallStack;
+use crate::cpu::Cpu;
+use crate::instruction::InstructionType;
+use crate::observer::Observer;
+use crate::symbols::Symbols;
+
+/** Number of rows shown in each section of the report. */
+const REPORT_ROWS: usize = 20;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SubroutineStats {
+    pub calls: u64,
+    /// Instructions and cycles spent in the subroutine itself
+    pub self_instructions: u64,
+    pub self_cycles: u64,
+    /// Instructions and cycles including the subroutines it called
+    pub total_instructions: u64,
+    pub total_cycles: u64,
+}
+
+/**
+ * Counts instructions and cycles per address and per subroutine.
+ * Subroutines are found by tracking JSR and RTS.
+ */
+pub struct Profiler {
+    pub counts: Vec<u64>,
+    pub cycles: Vec<u64>,
+    pub subroutines: BTreeMap<u16, SubroutineStats>,
+    /// Cycles spent with each call stack, for flame graphs
+    pub stacks: HashMap<Vec<u16>, u64>,
+    pub total_instructions: u64,
+    pub total_cycles: u64,
+    call_stack: CallStack,
+    pending: Option<(u16, u64, InstructionType)>,
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            counts: vec![0; 0x10000],
+            cycles: vec![0; 0x10000],
+            subroutines: BTreeMap::new(),
+            stacks: HashMap::new(),
+            total_instructions: 0,
+            total_cycles: 0,
+            call_stack: CallStack::new(),
+            pending: None,
+        }
+    }
+
+    fn record(&mut self, pc: u16, cycles: u64) {
+        self.counts[pc as usize] += 1;
+        self.cycles[pc as usize] += cycles;
+        self.total_instructions += 1;
+        self.total_cycles += cycles;
+
+        // The call stack has not been updated yet, so JSR is charged to
+        // the caller and RTS to the subroutine that is returning.
+        let entries: Vec<u16> = self.call_stack.frames.iter().map(|f| f.entry).collect();
+        if let Some(entry) = entries.last() {
+            let stats = self.subroutines.entry(*entry).or_default();
+            stats.self_instructions += 1;
+            stats.self_cycles += cycles;
+        }
+        // Recursive subroutines appear more than once but are only
+        // charged once.
+        let mut seen = Vec::with_capacity(entries.len());
+        for entry in &entries {
+            if !seen.contains(entry) {
+                seen.push(*entry);
+                let stats = self.subroutines.entry(*entry).or_default();
+                stats.total_instructions += 1;
+                stats.total_cycles += cycles;
+            }
+        }
+        *self.stacks.entry(entries).or_insert(0) += cycles;
+    }
+
+    fn name(symbols: &Symbols, address: u16) -> String {
+        match symbols.name(address) {
+            Some(name) => name.to_string(),
+            None => format!("${:04X}", address),
+        }
+    }
+
+    /** Produces a report of the hottest addresses and subroutines. */
+    pub fn report(&self, symbols: &Symbols) -> String {
+        let mut out = format!("Instructions: {}  Cycles: {}\n\n",
+            self.total_instructions, self.total_cycles);
+
+        let mut addresses: Vec<usize> = (0..self.counts.len())
+            .filter(|a| self.counts[*a] > 0)
+            .collect();
+        addresses.sort_by(|a, b| self.cycles[*b].cmp(&self.cycles[*a]).then(a.cmp(b)));
+        out.push_str("Hot spots:\n");
+        out.push_str(&format!("{:<6} {:<20} {:>12} {:>12} {:>7}\n",
+            "Addr", "Location", "Count", "Cycles", "%"));
+        for a in addresses.iter().take(REPORT_ROWS) {
+            out.push_str(&format!("{:04X}   {:<20} {:>12} {:>12} {:>6.2}%\n",
+                a, symbols.describe(*a as u16), self.counts[*a], self.cycles[*a],
+                percent(self.cycles[*a], self.total_cycles)));
+        }
+
+        let mut subroutines: Vec<(&u16, &SubroutineStats)> = self.subroutines.iter().collect();
+        subroutines.sort_by(|a, b| b.1.total_cycles.cmp(&a.1.total_cycles).then(a.0.cmp(b.0)));
+        out.push_str("\nSubroutines:\n");
+        out.push_str(&format!("{:<6} {:<20} {:>8} {:>12} {:>12} {:>7}\n",
+            "Entry", "Name", "Calls", "Self", "Total", "%"));
+        for (entry, stats) in subroutines.iter().take(REPORT_ROWS) {
+            out.push_str(&format!("{:04X}   {:<20} {:>8} {:>12} {:>12} {:>6.2}%\n",
+                entry, Profiler::name(symbols, **entry), stats.calls, stats.self_cycles,
+                stats.total_cycles, percent(stats.total_cycles, self.total_cycles)));
+        }
+        out
+    }
+
+    /**
+     * Produces cycle counts per call stack in the folded format read by
+     * flame graph tools, one "main;OUTER;INNER cycles" line per stack.
+     */
+    pub fn folded(&self, symbols: &Symbols) -> String {
+        let mut lines: Vec<String> = self.stacks.iter()
+            .map(|(stack, cycles)| {
+                let mut names = vec!["main".to_string()];
+                names.extend(stack.iter().map(|a| Profiler::name(symbols, *a)));
+                format!("{} {}", names.join(";"), cycles)
+            })
+            .collect();
+        lines.sort();
+        let mut out = lines.join("\n");
+        out.push('\n');
+        out
+    }
+}
+
+impl Observer for Profiler {
+    fn before(&mut self, cpu: &Cpu) {
+        let (i, _) = cpu.decode(cpu.pc);
+        self.pending = Some((cpu.pc, cpu.cycles, i.t));
+        self.call_stack.before(cpu);
+    }
+
+    fn after(&mut self, cpu: &Cpu) {
+        let pending = self.pending.take();
+        if let Some((pc, cycles, _)) = pending {
+            self.record(pc, cpu.cycles - cycles);
+        }
+        self.call_stack.after(cpu);
+        // Calls are counted once the call stack knows the target
+        if let Some((_, _, InstructionType::Jsr)) = pending {
+            if let Some(frame) = self.call_stack.current() {
+                self.subroutines.entry(frame.entry).or_default().calls += 1;
+            }
+        }
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
@@ -0,0 +1,239 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceLocation {
+    pub file: String,
+    /// Line number, starting at 1
+    pub line: usize,
+}
+
+/** Maps program addresses to the source lines that produced them. */
+pub struct SourceMap {
+    by_address: BTreeMap<u16, SourceLocation>,
+}
+
+/** Returns true if two paths name the same file. */
+fn same_file(a: &str, b: &str) -> bool {
+    if a == b {
+        return true;
+    }
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => Path::new(a).file_name() == Path::new(b).file_name(),
+    }
+}
+
+/**
+ * Parses the address at the start of a listing line. Accepts plain hex
+ * (0600), hex with a $ or . prefix ($0600, .0600), and ca65 style
+ * addresses with a relocation marker (000600r).
+ */
+fn listing_address(token: &str) -> Option<u16> {
+    let token = token.trim_start_matches(['$', '.']);
+    let token = token.strip_suffix('r').unwrap_or(token);
+    if token.len() < 4 || token.len() > 6 || !token.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(token, 16).ok().map(|a| a as u16)
+}
+
+fn is_byte(token: &str) -> bool {
+    token.len() == 2 && token.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap {
+            by_address: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_address.is_empty()
+    }
+
+    pub fn insert(&mut self, address: u16, location: SourceLocation) {
+        self.by_address.insert(address, location);
+    }
+
+    /**
+     * Loads a ca65 debug info file if the name ends in .dbg, or an
+     * assembler listing otherwise.
+     */
+    pub fn load(filename: &str) -> io::Result<SourceMap> {
+        if filename.to_lowercase().ends_with(".dbg") {
+            let text = fs::read_to_string(filename)?;
+            let dir = Path::new(filename).parent().unwrap_or_else(|| Path::new(""));
+            Ok(SourceMap::parse_ca65_debug(&text, dir))
+        } else {
+            SourceMap::load_listing(filename)
+        }
+    }
+
+    /** Loads an assembler listing file. */
+    pub fn load_listing(filename: &str) -> io::Result<SourceMap> {
+        let text = fs::read_to_string(filename)?;
+        Ok(SourceMap::parse_listing(filename, &text))
+    }
+
+    /**
+     * Parses an assembler listing. Each line that starts with an address
+     * followed by at least one byte of output is mapped to that line of
+     * the listing. The include depth column written by ca65 is skipped.
+     */
+    pub fn parse_listing(filename: &str, text: &str) -> SourceMap {
+        let mut map = SourceMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let mut tokens = line.split_whitespace().peekable();
+            let address = match tokens.next().and_then(listing_address) {
+                Some(address) => address,
+                None => continue,
+            };
+            // ca65 include depth
+            if let Some(t) = tokens.peek() {
+                if t.len() == 1 && t.chars().all(|c| c.is_ascii_digit()) {
+                    tokens.next();
+                }
+            }
+            if tokens.next().is_some_and(is_byte) {
+                map.insert(address, SourceLocation {
+                    file: filename.to_string(),
+                    line: n + 1,
+                });
+            }
+        }
+        map
+    }
+
+    /**
+     * Parses ca65/ld65 debug info. Source file names are resolved
+     * relative to the given directory. Lines from macro expansions are
+     * only used for addresses that have no other line.
+     */
+    pub fn parse_ca65_debug(text: &str, dir: &Path) -> SourceMap {
+        let mut files: HashMap<String, String> = HashMap::new();
+        let mut segments: HashMap<String, u32> = HashMap::new();
+        let mut spans: HashMap<String, (String, u32)> = HashMap::new();
+        let mut lines: Vec<(String, usize, Vec<String>, bool)> = Vec::new();
+        for record in text.lines() {
+            let mut parts = record.splitn(2, char::is_whitespace);
+            let kind = parts.next().unwrap_or("");
+            let mut fields: HashMap<&str, &str> = HashMap::new();
+            for field in parts.next().unwrap_or("").trim().split(',') {
+                let mut kv = field.splitn(2, '=');
+                if let (Some(k), Some(v)) = (kv.next(), kv.next()) {
+                    fields.insert(k, v);
+                }
+            }
+            let number = |v: &str| -> Option<u32> {
+                match v.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => v.parse().ok(),
+                }
+            };
+            let id = fields.get("id").map(|id| id.to_string()).unwrap_or_default();
+            match kind {
+                "file" => if let Some(name) = fields.get("name") {
+                    let name = name.trim_matches('"');
+                    files.insert(id, dir.join(name).to_string_lossy().into_owned());
+                },
+                "seg" => if let Some(start) = fields.get("start").and_then(|v| number(v)) {
+                    segments.insert(id, start);
+                },
+                "span" => if let (Some(seg), Some(start)) = (fields.get("seg"), fields.get("start").and_then(|v| number(v))) {
+                    spans.insert(id, (seg.to_string(), start));
+                },
+                "line" => if let (Some(file), Some(line), Some(span)) = (fields.get("file"), fields.get("line"), fields.get("span")) {
+                    let is_macro = fields.get("type").is_some_and(|t| *t != "0");
+                    lines.push((file.to_string(), line.parse().unwrap_or(0),
+                        span.split('+').map(|s| s.to_string()).collect(), is_macro));
+                },
+                _ => {},
+            }
+        }
+        let mut map = SourceMap::new();
+        // Source lines first so that they win over macro expansions
+        lines.sort_by_key(|l| l.3);
+        for (file, line, line_spans, is_macro) in lines {
+            let file = match files.get(&file) {
+                Some(file) => file,
+                None => continue,
+            };
+            for span in line_spans {
+                if let Some((seg, start)) = spans.get(&span) {
+                    if let Some(seg_start) = segments.get(seg) {
+                        let address = (seg_start + start) as u16;
+                        if is_macro && map.by_address.contains_key(&address) {
+                            continue;
+                        }
+                        map.insert(address, SourceLocation { file: file.clone(), line });
+                    }
+                }
+            }
+        }
+        map
+    }
+
+    /** Returns the source location of the instruction at an address. */
+    pub fn location(&self, address: u16) -> Option<&SourceLocation> {
+        self.by_address.get(&address)
+    }
+
+    /**
+     * Finds the address for a source line. If the line produced no code,
+     * the next line that did is used. Returns the address and the line
+     * actually used.
+     */
+    pub fn address(&self, file: &str, line: usize) -> Option<(u16, usize)> {
+        let files: Vec<String> = self.files().into_iter()
+            .filter(|f| same_file(f, file))
+            .collect();
+        self.by_address.iter()
+            .filter(|(_, loc)| loc.line >= line && files.contains(&loc.file))
+            .min_by_key(|(address, loc)| (loc.line, **address))
+            .map(|(address, loc)| (*address, loc.line))
+    }
+
+    /** Returns the distinct source files in the map. */
+    pub fn files(&self) -> Vec<String> {
+        let mut files: Vec<String> = Vec::new();
+        for loc in self.by_address.values() {
+            if !files.contains(&loc.file) {
+                files.push(loc.file.clone());
+            }
+        }
+        files
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&u16, &SourceLocation)> {
+        self.by_address.iter()
+    }
+}
+
+impl Default for SourceMap {
+    fn default() -> Self {
+        SourceMap::new()
+    }
+}
@@ -0,0 +1,261 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::debugger::parse_address;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SymbolFormat {
+    /// VICE monitor labels: `al C:0600 .SORT8`
+    Vice,
+    /// ca65/ld65 debug info: `sym id=0,name="SORT8",...,val=0x600,...`
+    Ca65Debug,
+    /// ld65 map file exports list
+    Ld65Map,
+    /// One `name = $addr` per line
+    Simple,
+}
+
+impl SymbolFormat {
+    /** Guesses the format of a symbol file from its extension. */
+    pub fn from_filename(filename: &str) -> SymbolFormat {
+        let extension = Path::new(filename).extension()
+            .map(|e| e.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("lbl") | Some("vs") => SymbolFormat::Vice,
+            Some("dbg") => SymbolFormat::Ca65Debug,
+            Some("map") => SymbolFormat::Ld65Map,
+            _ => SymbolFormat::Simple,
+        }
+    }
+}
+
+/**
+ * A table of symbol names and addresses. An address may have several
+ * names; the first one loaded is used when displaying it.
+ */
+pub struct Symbols {
+    by_name: HashMap<String, u16>,
+    by_address: BTreeMap<u16, String>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols {
+            by_name: HashMap::new(),
+            by_address: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /**
+     * Adds a symbol, or moves it if the name is already defined. An
+     * address keeps the first name given to it.
+     */
+    pub fn insert(&mut self, name: &str, address: u16) {
+        let old = self.by_name.insert(name.to_string(), address);
+        if let Some(old) = old.filter(|old| *old != address) {
+            if self.by_address.get(&old).map(|s| s.as_str()) == Some(name) {
+                self.by_address.remove(&old);
+                // Another name for the old address takes its place
+                let other = self.by_name.iter()
+                    .filter(|(_, a)| **a == old)
+                    .map(|(n, _)| n)
+                    .min();
+                if let Some(other) = other {
+                    self.by_address.insert(old, other.clone());
+                }
+            }
+        }
+        self.by_address.entry(address).or_insert_with(|| name.to_string());
+    }
+
+    /** Returns the address of a symbol. */
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).cloned()
+    }
+
+    /** Returns the name of the symbol at exactly this address. */
+    pub fn name(&self, address: u16) -> Option<&str> {
+        self.by_address.get(&address).map(|s| s.as_str())
+    }
+
+    /** Finds the closest symbol at or below an address. */
+    pub fn nearest(&self, address: u16) -> Option<(&str, u16)> {
+        self.by_address.range(..=address).next_back()
+            .map(|(a, name)| (name.as_str(), address - a))
+    }
+
+    /**
+     * Describes a code address as SYMBOL or SYMBOL+offset, falling back
+     * to $XXXX when there is no symbol at or below it.
+     */
+    pub fn describe(&self, address: u16) -> String {
+        match self.nearest(address) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+{}", name, offset),
+            None => format!("${:04X}", address),
+        }
+    }
+
+    /**
+     * Resolves an address written as a symbol, a symbol plus or minus an
+     * offset (SORT8+3), or a number ($0432, 0x0432, 0432). Symbols take
+     * precedence over numbers, so a label called ADD is not read as $0ADD.
+     */
+    pub fn resolve(&self, s: &str) -> Option<u16> {
+        let s = s.trim();
+        if let Some(address) = self.address(s) {
+            return Some(address);
+        }
+        if let Some(i) = s.rfind(['+', '-']) {
+            if i > 0 {
+                let base = self.resolve(&s[..i])?;
+                let offset_str = s[i + 1..].trim();
+                let offset = match offset_str.strip_prefix('$') {
+                    Some(hex) => u16::from_str_radix(hex, 16).ok()?,
+                    None => offset_str.parse().ok()?,
+                };
+                return Some(if s.as_bytes()[i] == b'+' {
+                    base.wrapping_add(offset)
+                } else {
+                    base.wrapping_sub(offset)
+                });
+            }
+        }
+        parse_address(s)
+    }
+
+    /** Loads symbols from a file, guessing the format from its extension. */
+    pub fn load(&mut self, filename: &str) -> io::Result<()> {
+        let text = fs::read_to_string(filename)?;
+        self.parse(&text, SymbolFormat::from_filename(filename));
+        Ok(())
+    }
+
+    pub fn parse(&mut self, text: &str, format: SymbolFormat) {
+        match format {
+            SymbolFormat::Vice => self.parse_vice(text),
+            SymbolFormat::Ca65Debug => self.parse_ca65_debug(text),
+            SymbolFormat::Ld65Map => self.parse_ld65_map(text),
+            SymbolFormat::Simple => self.parse_simple(text),
+        }
+    }
+
+    fn parse_vice(&mut self, text: &str) {
+        for line in text.lines() {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.len() < 3 || tokens[0] != "al" {
+                continue;
+            }
+            let address = tokens[1].rsplit(':').next().unwrap_or(tokens[1]);
+            if let Ok(address) = u32::from_str_radix(address, 16) {
+                self.insert(tokens[2].trim_start_matches('.'), address as u16);
+            }
+        }
+    }
+
+    fn parse_ca65_debug(&mut self, text: &str) {
+        for line in text.lines() {
+            let fields = match line.strip_prefix("sym") {
+                Some(fields) => fields.trim(),
+                None => continue,
+            };
+            let mut name = None;
+            let mut value = None;
+            for field in fields.split(',') {
+                let mut kv = field.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some("name"), Some(v)) => name = Some(v.trim_matches('"')),
+                    (Some("val"), Some(v)) => {
+                        let v = v.trim_start_matches("0x");
+                        value = u32::from_str_radix(v, 16).ok();
+                    },
+                    _ => {},
+                }
+            }
+            if let (Some(name), Some(value)) = (name, value) {
+                if value <= 0xFFFF {
+                    self.insert(name, value as u16);
+                }
+            }
+        }
+    }
+
+    fn parse_ld65_map(&mut self, text: &str) {
+        let mut in_exports = false;
+        let mut found = false;
+        for line in text.lines() {
+            if line.starts_with("Exports list") {
+                in_exports = true;
+                continue;
+            }
+            if !in_exports || line.starts_with('-') {
+                continue;
+            }
+            if line.trim().is_empty() {
+                if found {
+                    // Only the first exports list is needed
+                    return;
+                }
+                continue;
+            }
+            // Each line holds up to two entries of name, value, and flags
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            for entry in tokens.chunks(3) {
+                if let [name, value, ..] = entry {
+                    if let Ok(value) = u32::from_str_radix(value, 16) {
+                        self.insert(name, value as u16);
+                        found = true;
+                    }
+                }
+            }
+        }
+    }
+
+    fn parse_simple(&mut self, text: &str) {
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("");
+            let mut parts = line.splitn(2, '=');
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                let name = name.trim();
+                if let (false, Some(address)) = (name.is_empty(), parse_address(value)) {
+                    self.insert(name, address);
+                }
+            }
+        }
+    }
+}
+
+impl Default for Symbols {
+    fn default() -> Self {
+        Symbols::new()
+    }
+}
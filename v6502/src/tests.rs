@@ -17,11 +17,46 @@
     along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::cpu::Cpu;
+use std::io::{self, Cursor, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::bus::{parse_range, Bus, Mirror, ReadOnly, UnmappedPolicy, WritePolicy};
+use crate::callstack::CallStack;
+use crate::coverage::Coverage;
+use crate::cpu::{Cpu, Registers};
+use crate::debugger::{parse_address, Debugger, StopReason};
+use crate::disassembler::disassemble_line;
+use crate::device::{self as devices, Device, DeviceSpec, Rand, Terminal};
+use crate::device::acia::{self, Acia};
+use crate::device::bank::{self, BankKind, Banks};
+use crate::device::bitmap::{self, Bitmap};
+use crate::device::block::{self, Block};
+use crate::device::exit::Exit;
+use crate::device::host::HostStream;
+use crate::device::pia::{self, Pia};
+use crate::device::riot::{self, Riot};
+use crate::device::rtc::{self, Rtc};
+use crate::device::screen::{self, TextScreen};
+use crate::device::semihost::{self, Semihost};
+use crate::device::timer::{self, Timer};
+use crate::device::via::{self, Via};
+use crate::gdb::{Connection, GdbServer};
+use crate::heatmap::{Heatmap, RegionKind};
+use crate::history::{History, RunState};
 use crate::addressing::Addressing::*;
 use crate::instruction::Instruction;
 use crate::instruction::InstructionType::*;
-use crate::memory::Memory;
+use crate::memory::{AccessKind, Memory, UnmappedAccess, WriteFault};
+use crate::observer;
+use crate::png;
+use crate::profiler::Profiler;
+use crate::source_map::{SourceLocation, SourceMap};
+use crate::symbols::{SymbolFormat, Symbols};
+use crate::trace::trace_line;
 
 #[test]
 fn status_bits() {
@@ -549,4 +584,1727 @@ fn execute_next_instruction() {
     cpu.execute_next_instruction();
     assert_eq!(cpu.a, 0x40, "LDA Absolute");
     assert_eq!(cpu.pc, 0x0006, "LDA Absolute Moves PC by 3");
-}
\ No newline at end of file
+}
+
+#[test]
+fn reverse_execution() {
+    let mut cpu = Cpu::new6502();
+    let mut debugger = Debugger::new();
+    debugger.attach(&mut cpu, 100);
+    // LDA #$42; STA $0432; INX; STA $0432
+    for (i, b) in [0xA9, 0x42, 0x8D, 0x32, 0x04, 0xE8, 0x8D, 0x32, 0x04].iter().enumerate() {
+        cpu.memory[0x0600 + i] = *b;
+    }
+    cpu.pc = 0x0600;
+    cpu.memory[0x0432] = 0x11;
+
+    assert_eq!(debugger.step(&mut cpu, 2), StopReason::Step);
+    assert_eq!(cpu.memory[0x0432], 0x42, "STA wrote memory");
+    let w = cpu.history.as_ref().unwrap().last_write(0x0432).unwrap();
+    assert_eq!(w.pc, 0x0602, "last writer PC");
+    assert_eq!(w.old, 0x11, "last writer old value");
+    assert_eq!(w.steps_ago, 0, "last writer steps ago");
+
+    debugger.breakpoints.insert(0x0602);
+    debugger.step(&mut cpu, 2);
+    assert_eq!(cpu.x, 1, "INX executed");
+    assert_eq!(cpu.history.as_ref().unwrap().last_write(0x0432).unwrap().pc, 0x0606);
+
+    assert_eq!(debugger.reverse_step(&mut cpu, 1), StopReason::Step);
+    assert_eq!(cpu.pc, 0x0606, "reverse step restores PC");
+    assert_eq!(debugger.reverse_continue(&mut cpu), StopReason::Breakpoint(0x0602));
+    assert_eq!(cpu.x, 0, "reverse continue restores X");
+    assert_eq!(cpu.a, 0x42, "reverse continue keeps A");
+    assert_eq!(cpu.memory[0x0432], 0x11, "reverse continue restores memory");
+    assert_eq!(debugger.reverse_continue(&mut cpu), StopReason::HistoryExhausted);
+    assert_eq!(cpu.pc, 0x0600);
+    assert_eq!(cpu.a, 0x00);
+}
+
+#[test]
+fn undo_restores_run_state() {
+    let mut cpu = Cpu::new6502();
+    cpu.history = Some(History::new(10));
+    cpu.bus.map_spec("exit@$FFF0").unwrap();
+    // LDA #$03; STA $FFF0
+    for (i, b) in [0xA9, 0x03, 0x8D, 0xF0, 0xFF].iter().enumerate() {
+        cpu.memory[0x0600 + i] = *b;
+    }
+    cpu.pc = 0x0600;
+    cpu.execute_next_instruction();
+    // A debugger edit between instructions
+    cpu.set(0x0010, 0x99);
+    let cycles = cpu.cycles;
+    cpu.execute_next_instruction();
+    assert_eq!(cpu.exit_code, Some(3));
+    assert!(cpu.undo());
+    assert_eq!(cpu.cycles, cycles, "undo restores the cycle count");
+    assert_eq!(cpu.exit_code, None, "undo clears the exit code");
+    assert!(!cpu.is_break());
+    assert!(cpu.undo());
+    assert_eq!(cpu.cycles, 0);
+    assert_eq!(cpu.memory[0x0010], 0x99, "writes made outside an instruction aren't undone");
+}
+
+#[test]
+fn undo_restores_banked_ram() {
+    let mut cpu = Cpu::new6502();
+    cpu.history = Some(History::new(10));
+    cpu.bus.map_spec("bank@$8000,size=$1000,banks=2,select=$9000").unwrap();
+    // LDA #$42; STA $8010; LDA #$01; STA $9000
+    let program = [0xA9, 0x42, 0x8D, 0x10, 0x80, 0xA9, 0x01, 0x8D, 0x00, 0x90];
+    cpu.memory[0x0600..0x0600 + program.len()].copy_from_slice(&program);
+    cpu.pc = 0x0600;
+    for _ in 0..4 {
+        cpu.execute_next_instruction();
+    }
+    assert_eq!(cpu.peek(0x8010), 0x00, "bank 1 selected");
+    assert!(cpu.undo());
+    assert_eq!(cpu.peek(0x9000), 0, "undo selects the old bank");
+    assert_eq!(cpu.peek(0x8010), 0x42);
+    assert!(cpu.undo());
+    assert!(cpu.undo());
+    assert_eq!(cpu.peek(0x8010), 0x00, "undo restores banked RAM");
+}
+
+#[test]
+fn history_is_bounded() {
+    let mut history = History::new(2);
+    for pc in 0..3u16 {
+        history.begin(Registers { pc, a: 0, x: 0, y: 0, sr: 0, sp: 0xFF }, RunState::default());
+        history.record_write(0x10, pc as u8, pc as u8 + 1);
+    }
+    assert_eq!(history.len(), 2, "oldest entry dropped");
+    assert_eq!(history.last_write(0x10).unwrap().pc, 2);
+    assert_eq!(history.pop().unwrap().registers.pc, 2);
+    assert_eq!(history.pop().unwrap().registers.pc, 1);
+    assert!(history.pop().is_none());
+}
+
+#[test]
+fn debugger_commands() {
+    let mut cpu = Cpu::new6502();
+    let mut debugger = Debugger::new();
+    debugger.attach(&mut cpu, 100);
+    cpu.memory[0x0600] = 0xA9;
+    cpu.memory[0x0601] = 0x88;
+    cpu.pc = 0x0600;
+    assert_eq!(parse_address("$0432"), Some(0x0432));
+    assert_eq!(parse_address("0x0432"), Some(0x0432));
+    assert_eq!(parse_address("zz"), None);
+    assert!(debugger.command(&mut cpu, "list").starts_with("0600  A9 88     LDA #$88"));
+    assert_eq!(debugger.command(&mut cpu, "b $0602"), "Breakpoint set at $0602");
+    debugger.command(&mut cpu, "step");
+    assert_eq!(cpu.a, 0x88);
+    assert!(debugger.command(&mut cpu, "who-wrote 0432").starts_with("No write to $0432"));
+    // STA $0C32, which writes $0432 through a mirror
+    cpu.bus.add_mirror(Mirror::parse("$0000-$1FFF,$07FF").unwrap());
+    cpu.memory[0x0602..0x0605].copy_from_slice(&[0x8D, 0x32, 0x0C]);
+    debugger.command(&mut cpu, "step");
+    assert!(debugger.command(&mut cpu, "who-wrote $0C32").starts_with("$0C32 last written by instruction at $0602"));
+    debugger.command(&mut cpu, "rs");
+    debugger.command(&mut cpu, "rs");
+    assert_eq!(cpu.a, 0x00);
+    debugger.command(&mut cpu, "quit");
+    assert!(debugger.is_finished());
+}
+
+/** Sends a packet to a GDB stub and returns the reply. */
+fn gdb_exchange(stream: &mut TcpStream, packet: &str) -> String {
+    let sum = packet.bytes().fold(0u8, |s, b| s.wrapping_add(b));
+    write!(stream, "${}#{:02x}", packet, sum).unwrap();
+    let mut reply = Vec::new();
+    let mut buf = [0];
+    let mut in_packet = false;
+    loop {
+        stream.read_exact(&mut buf).unwrap();
+        match buf[0] {
+            b'$' => in_packet = true,
+            b'#' if in_packet => break,
+            b if in_packet => reply.push(b),
+            _ => {},
+        }
+    }
+    let mut sum = [0; 2];
+    stream.read_exact(&mut sum).unwrap();
+    stream.write_all(b"+").unwrap();
+    String::from_utf8(reply).unwrap()
+}
+
+#[test]
+fn gdb_remote_protocol() {
+    let mut cpu = Cpu::new6502();
+    // LDA #$42; STA $10; INX; INX; BRK
+    for (i, b) in [0xA9, 0x42, 0x85, 0x10, 0xE8, 0xE8, 0x00].iter().enumerate() {
+        cpu.memory[0x0600 + i] = *b;
+    }
+    cpu.pc = 0x0600;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = thread::spawn(move || {
+        let mut s = TcpStream::connect(address).unwrap();
+        s.set_nodelay(true).unwrap();
+        let mut replies = Vec::new();
+        for packet in &["qSupported:swbreak+", "?", "g", "s", "p5", "Z0,604,1",
+                        "c", "p1", "m10,2", "M20,2:abcd", "m20,2", "P0=99", "g",
+                        "z0,604,1", "Z1,605,1", "c", "qXfer:features:read:target.xml:0,15", "D"] {
+            replies.push(gdb_exchange(&mut s, packet));
+        }
+        replies
+    });
+    let (stream, _) = listener.accept().unwrap();
+    stream.set_nodelay(true).unwrap();
+    GdbServer::new().serve(&mut cpu, stream).unwrap();
+    let replies = client.join().unwrap();
+
+    assert!(replies[0].contains("swbreak+"), "qSupported");
+    assert_eq!(replies[1], "S05", "stop reason");
+    assert_eq!(replies[2], "00000000ff0006", "read registers");
+    assert_eq!(replies[3], "S05", "step");
+    assert_eq!(replies[4], "0206", "PC after step");
+    assert_eq!(replies[5], "OK", "set software breakpoint");
+    assert_eq!(replies[6], "T05swbreak:;", "continue to software breakpoint");
+    assert_eq!(replies[7], "00", "X at breakpoint");
+    assert_eq!(replies[8], "4200", "read memory");
+    assert_eq!(replies[9], "OK", "write memory");
+    assert_eq!(replies[10], "abcd", "read written memory");
+    assert_eq!(replies[11], "OK", "write register");
+    assert_eq!(replies[12], "99000000ff0406", "registers after write");
+    assert_eq!(replies[15], "T05hwbreak:;", "continue to hardware breakpoint");
+    assert_eq!(replies[16], "m<?xml version=\"1.0\"?>", "target description");
+    assert_eq!(replies[17], "OK", "detach");
+    assert_eq!(cpu.x, 1);
+    assert_eq!(cpu.pc, 0x0605);
+}
+
+#[test]
+fn gdb_exit_code() {
+    let mut cpu = Cpu::new6502();
+    cpu.bus.map_spec("exit@$FFF0").unwrap();
+    // LDA #$07; STA $FFF0
+    cpu.memory[0x0600..0x0605].copy_from_slice(&[0xA9, 0x07, 0x8D, 0xF0, 0xFF]);
+    cpu.pc = 0x0600;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = thread::spawn(move || {
+        let mut s = TcpStream::connect(address).unwrap();
+        s.set_nodelay(true).unwrap();
+        let mut replies = Vec::new();
+        for packet in &["c", "?", "D"] {
+            replies.push(gdb_exchange(&mut s, packet));
+        }
+        replies
+    });
+    let (stream, _) = listener.accept().unwrap();
+    stream.set_nodelay(true).unwrap();
+    GdbServer::new().serve(&mut cpu, stream).unwrap();
+    let replies = client.join().unwrap();
+
+    assert_eq!(replies[0], "W07", "the program exited with code 7");
+    assert_eq!(replies[1], "W07", "stop reason");
+    assert_eq!(replies[2], "OK", "detach");
+}
+
+#[test]
+fn gdb_malformed_packets() {
+    let mut cpu = Cpu::new6502();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = thread::spawn(move || {
+        let mut s = TcpStream::connect(address).unwrap();
+        s.set_nodelay(true).unwrap();
+        let mut replies = Vec::new();
+        for packet in &["", "\u{e9}x", "mfff0,ffffffff", "m0,ffffffff", "D"] {
+            replies.push(gdb_exchange(&mut s, packet));
+        }
+        replies
+    });
+    let (stream, _) = listener.accept().unwrap();
+    stream.set_nodelay(true).unwrap();
+    GdbServer::new().serve(&mut cpu, stream).unwrap();
+    let replies = client.join().unwrap();
+
+    assert_eq!(replies[0], "", "empty packet");
+    assert_eq!(replies[1], "", "non-ASCII packet");
+    assert_eq!(replies[2].len(), 0x10 * 2, "reads stop at the end of memory");
+    assert_eq!(replies[3].len(), 0x2000 * 2, "reads fit in the packet size");
+    assert_eq!(replies[4], "OK", "detach");
+}
+
+#[cfg(unix)]
+#[test]
+fn gdb_unix_interrupt_keeps_other_bytes() {
+    let (mut client, mut server) = std::os::unix::net::UnixStream::pair().unwrap();
+    assert!(!server.interrupted().unwrap());
+    client.write_all(b"$").unwrap();
+    assert!(!server.interrupted().unwrap());
+    let mut buf = [0];
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(buf[0], b'$', "the start of the next packet isn't consumed");
+    client.write_all(&[0x03]).unwrap();
+    assert!(server.interrupted().unwrap());
+}
+
+#[test]
+fn source_map_listing() {
+    let listing = "\
+; bubble sort
+0600  A0 00     SORT8    LDY #$00
+.0602 84 32              STY $32
+
+000604r 1  B1 30         LDA ($30),Y
+000606r 1                NXTEL:
+$0606 AA                 TAX
+";
+    let map = SourceMap::parse_listing("sort.lst", listing);
+    assert_eq!(map.location(0x0600).unwrap().line, 2);
+    assert_eq!(map.location(0x0602).unwrap().line, 3);
+    assert_eq!(map.location(0x0604).unwrap().line, 5);
+    assert_eq!(map.location(0x0606).unwrap().line, 7, "label-only lines are skipped");
+    assert_eq!(map.address("sort.lst", 2), Some((0x0600, 2)));
+    assert_eq!(map.address("/elsewhere/sort.lst", 4), Some((0x0604, 5)), "next line with code");
+    assert_eq!(map.address("other.lst", 2), None);
+}
+
+#[test]
+fn call_stack_tracking() {
+    let mut cpu = Cpu::new6502();
+    // JSR $0610; BRK ... $0610: JSR $0620; RTS ... $0620: RTS
+    for (addr, b) in [(0x0600, 0x20), (0x0601, 0x10), (0x0602, 0x06), (0x0603, 0x00),
+                      (0x0610, 0x20), (0x0611, 0x20), (0x0612, 0x06), (0x0613, 0x60),
+                      (0x0620, 0x60)].iter() {
+        cpu.memory[*addr] = *b;
+    }
+    cpu.pc = 0x0600;
+    let mut stack = CallStack::new();
+    stack.step(&mut cpu);
+    stack.step(&mut cpu);
+    assert_eq!(stack.depth(), 2);
+    assert_eq!(stack.current().unwrap().entry, 0x0620);
+    assert_eq!(stack.current().unwrap().call_site, 0x0610);
+    assert_eq!(stack.frames[0].call_site, 0x0600);
+    stack.step(&mut cpu);
+    assert_eq!(stack.depth(), 1, "RTS pops frame");
+    stack.step(&mut cpu);
+    assert_eq!(stack.depth(), 0);
+    assert_eq!(cpu.pc, 0x0603);
+}
+
+#[test]
+fn symbol_files() {
+    let mut symbols = Symbols::new();
+    symbols.parse("al C:0600 .SORT8\nal 000606 .NXTEL\n", SymbolFormat::Vice);
+    symbols.parse("sym\tid=0,name=\"CHKEND\",addrsize=absolute,scope=0,def=1,val=0x61C,type=lab\n\
+                   sym\tid=1,name=\"missing\",addrsize=absolute,scope=0,type=imp\n", SymbolFormat::Ca65Debug);
+    symbols.parse("Exports list by name:\n---------------------\n\
+                   LIST                      000030 RLA    FLAG                      000032 RZA\n\n\
+                   Exports list by value:\n", SymbolFormat::Ld65Map);
+    symbols.parse("; comment\nADD = $0ADE\nCOUNT = 0x0200 ; trailing\n", SymbolFormat::Simple);
+    assert_eq!(symbols.len(), 7);
+    assert_eq!(symbols.address("SORT8"), Some(0x0600));
+    assert_eq!(symbols.address("NXTEL"), Some(0x0606));
+    assert_eq!(symbols.address("CHKEND"), Some(0x061C));
+    assert_eq!(symbols.address("FLAG"), Some(0x0032));
+    assert_eq!(symbols.name(0x0030), Some("LIST"));
+    assert_eq!(symbols.describe(0x0603), "SORT8+3");
+    assert_eq!(symbols.resolve("SORT8+3"), Some(0x0603));
+    assert_eq!(symbols.resolve("ADD"), Some(0x0ADE), "symbols win over hex");
+    assert_eq!(symbols.resolve("$ADD"), Some(0x0ADD));
+    assert_eq!(SymbolFormat::from_filename("prog.lbl"), SymbolFormat::Vice);
+    assert_eq!(SymbolFormat::from_filename("prog.dbg"), SymbolFormat::Ca65Debug);
+
+    // Redefining a symbol moves it
+    symbols.insert("START", 0x0600);
+    symbols.insert("SORT8", 0x0700);
+    assert_eq!(symbols.name(0x0700), Some("SORT8"));
+    assert_eq!(symbols.name(0x0600), Some("START"), "another name takes the old address");
+    symbols.insert("ADD", 0x0AE0);
+    assert_eq!(symbols.name(0x0ADE), None);
+    assert_eq!(symbols.describe(0x0AE0), "ADD");
+}
+
+#[test]
+fn symbolic_display() {
+    let mut cpu = Cpu::new6502();
+    let mut debugger = Debugger::new();
+    cpu.symbols.parse("SORT8 = $0610\nFLAG = $32\n", SymbolFormat::Simple);
+    // JSR SORT8; STY FLAG
+    for (i, b) in [0x20, 0x10, 0x06, 0x84, 0x32].iter().enumerate() {
+        cpu.memory[0x0600 + i] = *b;
+    }
+    cpu.pc = 0x0600;
+    assert!(trace_line(&cpu).starts_with("0600  20 10 06  JSR SORT8"));
+    assert_eq!(debugger.command(&mut cpu, "break SORT8"), "Breakpoint set at $0610");
+    let listing = debugger.command(&mut cpu, "list $0603 1");
+    assert!(listing.ends_with("STY FLAG"), "{}", listing);
+    cpu.pc = 0x0613;
+    assert!(format!("{:?}", cpu).contains("PC: 0613 (SORT8+3)"));
+}
+
+#[test]
+fn cycle_counts() {
+    let mut cpu = Cpu::new6502();
+    // LDA $10FF,X (page cross); STA $10FF,X; BNE +2 (taken); NOP; NOP
+    for (i, b) in [0xBD, 0xFF, 0x10, 0x9D, 0xFF, 0x10, 0xD0, 0x01, 0xEA, 0xEA].iter().enumerate() {
+        cpu.memory[0x0600 + i] = *b;
+    }
+    cpu.pc = 0x0600;
+    cpu.x = 1;
+    cpu.execute_next_instruction();
+    assert_eq!(cpu.cycles, 5, "LDA abs,X with page cross");
+    cpu.execute_next_instruction();
+    assert_eq!(cpu.cycles, 10, "STA abs,X always takes 5");
+    cpu.clear_zero();
+    cpu.execute_next_instruction();
+    assert_eq!(cpu.cycles, 13, "taken branch");
+    assert_eq!(cpu.pc, 0x0609);
+
+    // LDA ($30),Y with the pointer read through a mirror of $0000-$000F
+    let mut cpu = Cpu::new6502();
+    cpu.bus.add_mirror(Mirror::parse("$0000-$00FF,$000F").unwrap());
+    cpu.memory[0x0000] = 0xF0;
+    cpu.memory[0x0001] = 0x06;
+    cpu.memory[0x0600] = 0xB1;
+    cpu.memory[0x0601] = 0x30;
+    cpu.pc = 0x0600;
+    cpu.y = 0x20;
+    cpu.execute_next_instruction();
+    assert_eq!(cpu.cycles, 6, "LDA (zp),Y with page cross through a mirror");
+}
+
+#[test]
+fn profiler() {
+    let mut cpu = Cpu::new6502();
+    cpu.symbols.parse("SUB = $0610\n", SymbolFormat::Simple);
+    // JSR SUB; JSR SUB; BRK ... SUB: NOP; RTS
+    for (addr, b) in [(0x0600, 0x20), (0x0601, 0x10), (0x0602, 0x06),
+                      (0x0603, 0x20), (0x0604, 0x10), (0x0605, 0x06), (0x0606, 0x00),
+                      (0x0610, 0xEA), (0x0611, 0x60)].iter() {
+        cpu.memory[*addr] = *b;
+    }
+    cpu.pc = 0x0600;
+    let mut profiler = Profiler::new();
+    observer::run(&mut cpu, &mut [&mut profiler]);
+    assert_eq!(profiler.total_instructions, 7);
+    assert_eq!(profiler.total_cycles, 6 + 2 + 6 + 6 + 2 + 6 + 7);
+    assert_eq!(profiler.counts[0x0610], 2);
+    assert_eq!(profiler.cycles[0x0611], 12);
+    let sub = profiler.subroutines[&0x0610];
+    assert_eq!(sub.calls, 2);
+    assert_eq!(sub.self_instructions, 4);
+    assert_eq!(sub.total_cycles, 16);
+    assert!(profiler.report(&cpu.symbols).contains("0610   SUB"));
+    assert_eq!(profiler.folded(&cpu.symbols), "main 19\nmain;SUB 16\n");
+}
+
+#[test]
+fn coverage() {
+    let mut cpu = Cpu::new6502();
+    // LDX #$02; LOOP: DEX; BNE LOOP; BRK; NOP
+    for (addr, b) in [(0x0600, 0xA2), (0x0601, 0x02), (0x0602, 0xCA), (0x0603, 0xD0),
+                      (0x0604, 0xFD), (0x0605, 0x00), (0x0606, 0xEA)].iter() {
+        cpu.memory[*addr] = *b;
+    }
+    cpu.pc = 0x0600;
+    let mut coverage = Coverage::new();
+    observer::run(&mut cpu, &mut [&mut coverage]);
+    assert_eq!(coverage.executed[0x0602], 2);
+    assert_eq!(coverage.branches[&0x0603].taken, 1);
+    assert_eq!(coverage.branches[&0x0603].not_taken, 1);
+    let listing = "\
+0600  A2 02     LDX #$02
+0602  CA        DEX
+0603  D0 FD     BNE $0602
+0605  00        BRK
+0606  EA        NOP
+";
+    let map = SourceMap::parse_listing("loop.lst", listing);
+    let annotated = coverage.annotated(&cpu, &map);
+    assert!(annotated.contains("taken 1 not taken 1"), "{}", annotated);
+    assert!(annotated.lines().last().unwrap().trim_start().starts_with("#####"), "{}", annotated);
+    let lcov = coverage.lcov(&cpu, &map, "loop");
+    assert!(lcov.starts_with("TN:loop\nSF:loop.lst\n"));
+    assert!(lcov.contains("BRDA:3,0,0,1\nBRDA:3,0,1,1\n"));
+    assert!(lcov.contains("DA:2,2\n"));
+    assert!(lcov.contains("DA:5,0\nLF:5\nLH:4\nend_of_record\n"), "{}", lcov);
+}
+
+#[test]
+fn source_map_ca65_debug() {
+    let dbg = "\
+version\tmajor=2,minor=0
+file\tid=0,name=\"sort.s\",size=100,mtime=0x5F000000,mod=0
+seg\tid=0,name=\"CODE\",start=0x000600,size=0x0010,addrsize=absolute,type=rw
+span\tid=0,seg=0,start=0,size=2
+span\tid=1,seg=0,start=2,size=2
+span\tid=2,seg=0,start=2,size=2
+line\tid=0,file=0,line=4,span=0
+line\tid=1,file=0,line=9,type=2,span=2
+line\tid=2,file=0,line=5,span=1
+";
+    let map = SourceMap::parse_ca65_debug(dbg, Path::new("src"));
+    let file = Path::new("src").join("sort.s").to_string_lossy().into_owned();
+    assert_eq!(map.location(0x0600), Some(&SourceLocation { file: file.clone(), line: 4 }));
+    assert_eq!(map.location(0x0602).unwrap().line, 5, "source lines win over macros");
+    assert_eq!(map.address(&file, 5), Some((0x0602, 5)));
+}
+
+#[test]
+fn heatmap() {
+    let mut cpu = Cpu::new6502();
+    // LDA $0300; LDA #$05; STA $0609; LDX #$00; BRK
+    for (addr, b) in [(0x0600, 0xAD), (0x0601, 0x00), (0x0602, 0x03), (0x0603, 0xA9),
+                      (0x0604, 0x05), (0x0605, 0x8D), (0x0606, 0x09), (0x0607, 0x06),
+                      (0x0608, 0xA2), (0x0609, 0x00), (0x060A, 0x00)].iter() {
+        cpu.memory[*addr] = *b;
+    }
+    cpu.pc = 0x0600;
+    let mut heatmap = Heatmap::new();
+    heatmap.attach(&mut cpu);
+    observer::run(&mut cpu, &mut [&mut heatmap]);
+    assert_eq!(cpu.x, 0x05);
+    assert_eq!(heatmap.usage[0x0300].reads, 1);
+    assert_eq!(heatmap.usage[0x0600].executions, 1);
+    assert_eq!(heatmap.usage[0x0601].reads, 0, "instruction fetches are not data reads");
+    assert!(heatmap.usage[0x0601].code);
+    assert_eq!(heatmap.self_modifying.get(&0x0609), Some(&0x0605));
+    let regions = heatmap.regions();
+    assert_eq!(regions[0].start, 0x01FD, "BRK pushes to the stack");
+    assert!(regions.iter().any(|r| r.start == 0x0300 && r.end == 0x0300 && r.kind == RegionKind::Data));
+    let map = heatmap.text_map();
+    assert!(map.contains("\n0300  R..."), "{}", map);
+    assert!(map.contains("\n0600  XXM."), "{}", map);
+    assert!(map.contains("0609  written by 0605"), "{}", map);
+    let pgm = heatmap.pgm();
+    assert!(pgm.starts_with(b"P5\n256 256\n255\n"));
+    assert_eq!(pgm.len(), 15 + 0x10000);
+    assert_eq!(pgm[15 + 0x0300], 255);
+    assert_eq!(pgm[15 + 0x0400], 0);
+    assert_eq!(heatmap.ppm().len(), 15 + 3 * 0x10000);
+}
+
+/** A device that raises IRQ or NMI once enough cycles have passed. */
+struct TestTimer {
+    remaining: u64,
+    nmi: bool,
+}
+
+impl Memory for TestTimer {
+    fn get(&mut self, _: u16) -> u8 {
+        self.remaining = u64::MAX;
+        0
+    }
+
+    fn peek(&self, _: u16) -> u8 {
+        0
+    }
+
+    fn set(&mut self, _: u16, value: u8) {
+        self.remaining = value as u64;
+    }
+}
+
+impl Device for TestTimer {
+    fn name(&self) -> String {
+        "Timer".to_string()
+    }
+
+    fn status(&self) -> String {
+        format!("{} cycles left", self.remaining)
+    }
+
+    fn size(&self) -> u16 {
+        4
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.remaining = self.remaining.saturating_sub(cycles);
+    }
+
+    fn irq(&self) -> bool {
+        !self.nmi && self.remaining == 0
+    }
+
+    fn nmi(&self) -> bool {
+        self.nmi && self.remaining == 0
+    }
+}
+
+#[test]
+fn device_bus() {
+    let mut bus = Bus::new();
+    assert!(bus.map(0xD000, Box::new(TestTimer { remaining: 1, nmi: false })).is_ok());
+    assert!(bus.map(0xD003, Box::new(TestTimer { remaining: 1, nmi: false })).is_err(), "overlap");
+    assert!(bus.map(0xFFFE, Box::new(TestTimer { remaining: 1, nmi: false })).is_err(), "past end");
+    assert_eq!(bus.iter().next().unwrap().end(), 0xD003);
+    assert_eq!(bus.get(0xD004), None);
+    assert!(bus.set(0xD002, 9));
+    assert_eq!(bus.iter().next().unwrap().device.status(), "9 cycles left");
+
+    let mut rand = Rand::from_seed(1);
+    let state = rand.save();
+    let first = rand.rand();
+    rand.restore(&state);
+    assert_eq!(rand.rand(), first);
+}
+
+#[test]
+fn device_interrupts() {
+    let mut cpu = Cpu::new6502();
+    cpu.bus.map(0xD000, Box::new(TestTimer { remaining: u64::MAX, nmi: false })).unwrap();
+    // CLI; LDA #$06; STA $D000; NOP; NOP ... IRQ handler at $0700: LDA $D000; RTI
+    for (addr, b) in [(0x0600, 0x58), (0x0601, 0xA9), (0x0602, 0x06), (0x0603, 0x8D),
+                      (0x0604, 0x00), (0x0605, 0xD0), (0x0606, 0xEA), (0x0607, 0xEA),
+                      (0x0608, 0xEA), (0x0700, 0xAD), (0x0701, 0x00), (0x0702, 0xD0),
+                      (0x0703, 0x40), (0xFFFE, 0x00), (0xFFFF, 0x07)].iter() {
+        cpu.memory[*addr] = *b;
+    }
+    cpu.pc = 0x0600;
+    for _ in 0..3 {
+        cpu.execute_next_instruction();
+    }
+    assert_eq!(cpu.pc, 0x0606, "two cycles left after STA");
+    cpu.execute_next_instruction();
+    assert_eq!(cpu.pc, 0x0700, "IRQ taken after the first NOP");
+    assert!(cpu.is_irq_disabled());
+    assert_eq!(cpu.memory[0x01FD] & 0x10, 0, "break flag clear in pushed status");
+    cpu.execute_next_instruction();
+    cpu.execute_next_instruction();
+    assert_eq!(cpu.pc, 0x0607, "RTI returns to the next instruction");
+    assert!(!cpu.is_irq_disabled());
+
+    let mut cpu = Cpu::new6502();
+    cpu.bus.map(0xD000, Box::new(TestTimer { remaining: 0, nmi: true })).unwrap();
+    cpu.memory[0xFFFA] = 0x00;
+    cpu.memory[0xFFFB] = 0x08;
+    cpu.memory[0x0800] = 0xEA;
+    cpu.set_irq_disabled();
+    cpu.pc = 0x0600;
+    cpu.memory[0x0600] = 0xEA;
+    cpu.execute_next_instruction();
+    assert_eq!(cpu.pc, 0x0800, "NMI ignores the interrupt disable flag");
+    cpu.execute_next_instruction();
+    assert_eq!(cpu.pc, 0x0801, "NMI is edge triggered");
+}
+
+#[test]
+fn via_timers() {
+    // One-shot: the flag is set N + 1.5 cycles after writing T1C-H, which
+    // is seen on the cycle N + 1 after the write
+    let mut v = Via::new();
+    v.set(0xE, 0x80 | via::IRQ_T1);
+    v.set(0x4, 10);
+    v.set(0x5, 0);
+    v.tick(4);
+    assert_eq!(v.get(0x4), 10, "counting starts after the writing instruction");
+    v.tick(10);
+    assert!(!v.irq());
+    v.tick(1);
+    assert!(v.irq());
+    assert_eq!(v.get(0xD), 0x80 | via::IRQ_T1);
+    v.get(0x4);
+    assert!(!v.irq(), "reading T1C-L clears the flag");
+    v.tick(100);
+    assert!(!v.irq(), "one-shot interrupts once");
+
+    // Free running with PB7: period is N + 2 cycles
+    let mut v = Via::new();
+    v.set(0xB, 0xC0);
+    v.set(0x4, 4);
+    v.set(0x5, 0);
+    v.tick(1);
+    assert_eq!(v.port_b() & 0x80, 0, "PB7 low while counting");
+    v.tick(5);
+    assert_eq!(v.get(0xD) & via::IRQ_T1, via::IRQ_T1);
+    assert_eq!(v.port_b() & 0x80, 0x80, "PB7 toggles");
+    v.set(0xD, via::IRQ_T1);
+    v.tick(5);
+    assert_eq!(v.get(0xD) & via::IRQ_T1, 0);
+    v.tick(1);
+    assert_eq!(v.get(0xD) & via::IRQ_T1, via::IRQ_T1);
+    assert_eq!(v.port_b() & 0x80, 0);
+
+    // T2 one-shot and pulse counting
+    let mut v = Via::new();
+    v.set(0x8, 3);
+    v.set(0x9, 0);
+    v.tick(4);
+    v.tick(3);
+    assert_eq!(v.get(0xD) & via::IRQ_T2, 0);
+    v.tick(1);
+    assert_eq!(v.get(0xD) & via::IRQ_T2, via::IRQ_T2);
+    v.set(0xB, 0x20);
+    v.set(0x8, 2);
+    v.set(0x9, 0);
+    v.tick(100);
+    assert_eq!(v.get(0xD) & via::IRQ_T2, 0, "pulse counting ignores the clock");
+    v.set_input_b(0xBF);
+    v.set_input_b(0xFF);
+    v.set_input_b(0xBF);
+    assert_eq!(v.get(0xD) & via::IRQ_T2, via::IRQ_T2);
+}
+
+#[test]
+fn via_ports_and_handshake() {
+    let mut v = Via::new();
+    v.set(0x3, 0xF0);
+    v.set(0x1, 0xA5);
+    v.input_a = 0x0C;
+    assert_eq!(v.port_a(), 0xAC);
+    // CA1 positive edge, latching, CA2 handshake output
+    v.set(0xC, 0x09);
+    v.set(0xB, 0x01);
+    v.set(0xE, 0x80 | via::IRQ_CA1);
+    v.set(0x1, 0x00);
+    assert!(!v.ca2_output(), "CA2 low after writing ORA");
+    v.set_ca1(false);
+    v.set_ca1(true);
+    assert!(v.ca2_output(), "CA2 high after the CA1 edge");
+    assert!(v.irq());
+    v.input_a = 0x03;
+    assert_eq!(v.get(0xF), 0x0C, "latched at the CA1 edge");
+    assert!(v.irq(), "ORA without handshake keeps the flag");
+    v.get(0x1);
+    assert!(!v.irq());
+    // CB2 pulse output
+    v.set(0xC, 0xA0);
+    v.set(0x0, 0x00);
+    assert!(!v.cb2_output());
+    v.tick(2);
+    assert!(!v.cb2_output(), "pulse starts after the writing instruction");
+    v.tick(1);
+    assert!(v.cb2_output());
+    // Interrupt enable register
+    v.set(0xE, 0x7F);
+    assert_eq!(v.get(0xE), 0x80);
+    v.set_cb1(false);
+    assert_eq!(v.get(0xD), via::IRQ_CB1, "flag set but IRQ disabled");
+}
+
+#[test]
+fn via_shift_register() {
+    let mut v = Via::new();
+    v.set(0xE, 0x80 | via::IRQ_SR);
+    v.set(0xB, 0x18);
+    v.set(0xA, 0x81);
+    let mut bits = Vec::new();
+    v.tick(2);
+    for _ in 0..8 {
+        v.tick(2);
+        bits.push(v.cb2_output() as u8);
+    }
+    assert_eq!(bits, vec![1, 0, 0, 0, 0, 0, 0, 1]);
+    assert!(v.irq());
+    v.get(0xA);
+    assert!(!v.irq());
+
+    // Shift in under external clock
+    let mut v = Via::new();
+    v.set(0xB, 0x0C);
+    v.get(0xA);
+    for bit in [true, false, true, true, false, false, true, false].iter() {
+        v.set_cb2(*bit);
+        v.set_cb1(false);
+        v.set_cb1(true);
+    }
+    assert_eq!(v.get(0xD) & via::IRQ_SR, via::IRQ_SR);
+    assert_eq!(v.get(0xA), 0xB2);
+
+    let state = v.save();
+    let mut copy = Via::new();
+    copy.restore(&state);
+    assert_eq!(copy.save(), state);
+}
+
+#[test]
+fn via_on_bus() {
+    assert_eq!(DeviceSpec::parse("VIA@$6000").unwrap().address, 0x6000);
+    assert!(DeviceSpec::parse("via").is_err());
+    assert!(DeviceSpec::parse("via@6000,x").is_err());
+    for (spec, stdio) in &[("acia@$5000", true), ("acia@$5000,port=pty", false), ("acia@$5000,in=a,out=b", false),
+                           ("6551@$5000,in=a", true), ("pia@$D010", false), ("pia@$D010,port=-", true),
+                           ("text@$4000,display=ansi", true), ("text@$4000", false), ("via@$6000", false)] {
+        assert_eq!(DeviceSpec::parse(spec).unwrap().uses_stdio(), *stdio, "{}", spec);
+    }
+    let mut cpu = Cpu::new6502();
+    assert!(cpu.bus.map_spec("via@6000,speed=1").is_err(), "unknown option");
+    assert!(cpu.bus.map_spec("floppy@6000").is_err());
+    cpu.bus.map_spec("via@6000").unwrap();
+    // LDA #$C0; STA $600E; LDA #$20; STA $6004; LDA #$00; STA $6005; CLI; loop: JMP loop
+    let program = [0xA9, 0xC0, 0x8D, 0x0E, 0x60, 0xA9, 0x20, 0x8D, 0x04, 0x60,
+                   0xA9, 0x00, 0x8D, 0x05, 0x60, 0x58, 0x4C, 0x10, 0x06];
+    cpu.memory[0x0600..0x0600 + program.len()].copy_from_slice(&program);
+    cpu.memory[0xFFFE] = 0x00;
+    cpu.memory[0xFFFF] = 0x07;
+    cpu.pc = 0x0600;
+    for _ in 0..20 {
+        cpu.execute_next_instruction();
+        if cpu.pc == 0x0700 {
+            break;
+        }
+    }
+    assert_eq!(cpu.pc, 0x0700, "timer interrupt taken");
+    assert_eq!(cpu.get(0x600D), 0xC0);
+}
+
+/** Collects device output so tests can inspect it. */
+#[derive(Clone, Default)]
+struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl SharedOutput {
+    fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn test_stream(input: &[u8], output: &SharedOutput) -> HostStream {
+    HostStream {
+        input: Box::new(Cursor::new(input.to_vec())),
+        output: Box::new(output.clone()),
+        description: "test".to_string(),
+    }
+}
+
+#[test]
+fn acia_transmit() {
+    let output = SharedOutput::default();
+    let mut a = Acia::new(test_stream(b"", &output), 1_000_000);
+    // 9600 baud, 8N1: 10 bits take 1041 cycles at 1 MHz
+    a.set(3, 0x1E);
+    a.set(2, 0x05);
+    assert_eq!(a.char_cycles(), 1041);
+    a.set(0, b'H');
+    assert_eq!(a.get(1) & acia::STATUS_TDRE, 0);
+    a.tick(2);
+    assert_eq!(a.get(1), acia::STATUS_IRQ | acia::STATUS_TDRE, "moved to the shift register");
+    assert!(!a.irq(), "reading status clears IRQ");
+    a.set(0, b'i');
+    a.tick(1000);
+    assert!(output.contents().is_empty());
+    a.tick(100);
+    assert_eq!(output.contents(), b"H");
+    a.tick(1041);
+    assert_eq!(output.contents(), b"Hi");
+    a.set(3, 0x3F);
+    a.set(2, 0x25);
+    assert_eq!(a.char_cycles(), (1_000_000.0 * 10.0 / 19200.0) as u64, "7 bits, parity, 1 stop");
+}
+
+#[test]
+fn host_stream_regular_file() {
+    let path = std::env::temp_dir().join(format!("v6502-serial-{}.txt", std::process::id()));
+    std::fs::write(&path, b"input").unwrap();
+    let mut stream = HostStream::open(path.to_str().unwrap()).unwrap();
+    stream.output.write_all(b"XY").unwrap();
+    stream.output.flush().unwrap();
+    let mut input = String::new();
+    stream.input.read_to_string(&mut input).unwrap();
+    assert_eq!(input, "input");
+    assert_eq!(std::fs::read(&path).unwrap(), b"input", "output doesn't overwrite the file");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn acia_receive() {
+    let output = SharedOutput::default();
+    let mut a = Acia::new(test_stream(b"", &output), 1_000_000);
+    a.set(3, 0x1F);
+    a.receive(b'A');
+    a.tick(1000);
+    assert_eq!(a.get(1) & acia::STATUS_RDRF, 0, "receiver disabled until DTR is set");
+    a.set(2, 0x09);
+    a.tick(521);
+    assert_eq!(a.get(1), acia::STATUS_IRQ | acia::STATUS_RDRF | acia::STATUS_TDRE);
+    assert_eq!(a.get(0), b'A');
+    assert_eq!(a.get(1) & acia::STATUS_RDRF, 0);
+    a.receive(b'B');
+    a.receive(b'C');
+    a.tick(521);
+    a.tick(521);
+    assert_eq!(a.get(1) & acia::STATUS_OVERRUN, acia::STATUS_OVERRUN);
+    assert_eq!(a.get(0), b'B', "overrun keeps the first character");
+    a.set(1, 0);
+    assert_eq!(a.get(2), 0x00, "programmed reset");
+    // Echo mode
+    a.set(2, 0x13);
+    a.receive(b'E');
+    a.tick(521);
+    assert_eq!(output.contents(), b"E");
+
+    // Host input arrives from the background reader
+    let mut a = Acia::new(test_stream(b"xy", &output), 1_000_000);
+    a.set(2, 0x0B);
+    a.set(3, 0x1F);
+    let mut received = Vec::new();
+    for _ in 0..1000 {
+        a.tick(521);
+        if a.get(1) & acia::STATUS_RDRF != 0 {
+            received.push(a.get(0));
+        }
+        if received.len() == 2 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(received, b"xy");
+    let state = a.save();
+    let mut copy = Acia::new(test_stream(b"", &output), 1_000_000);
+    copy.restore(&state);
+    assert_eq!(copy.save(), state);
+}
+
+#[test]
+fn riot() {
+    let mut r = Riot::new();
+    r.set(0x05, 0x42);
+    assert_eq!(r.get(0x05), 0x42, "RAM");
+    r.set(0x81, 0x0F);
+    r.set(0x80, 0xA5);
+    r.set_input_a(0x30);
+    assert_eq!(r.port_a(), 0x35);
+    assert_eq!(r.get(0x80), 0x35);
+
+    // Divide by 8 with interrupt: 3 * 8 + 1 cycles until the flag is set
+    r.set(0x9D, 3);
+    r.tick(4);
+    assert_eq!(r.timer(), 3, "counting starts after the writing instruction");
+    r.tick(1);
+    assert_eq!(r.timer(), 2);
+    r.tick(23);
+    assert_eq!(r.timer(), 0);
+    assert!(!r.irq());
+    r.tick(1);
+    assert!(r.irq());
+    assert_eq!(r.get(0x85) & riot::IRQ_TIMER, riot::IRQ_TIMER);
+    r.tick(2);
+    assert_eq!(r.timer(), 0xFD, "counts once per cycle after expiring");
+    assert_eq!(r.get(0x8C), 0xFD);
+    assert!(!r.irq(), "reading the timer clears the flag");
+    r.tick(8);
+    assert_eq!(r.timer(), 0xFC, "back to the programmed rate");
+
+    // PA7 positive edge
+    r.set(0x87, 0);
+    r.set_input_a(0x00);
+    r.set_input_a(0x80);
+    assert!(r.irq());
+    assert_eq!(r.get(0x85), riot::IRQ_PA7);
+    assert!(!r.irq(), "reading the flags clears PA7");
+
+    let state = r.save();
+    let mut copy = Riot::new();
+    copy.restore(&state);
+    assert_eq!(copy.save(), state);
+
+    // A saved prescaler count of 0 is treated as 1
+    let mut bad = state.clone();
+    let n = bad.len();
+    bad[n - 2] = 0;
+    bad[n - 1] = 0;
+    copy.restore(&bad);
+    copy.tick(10);
+    bad[n - 4] = 3;
+    let mut other = Riot::new();
+    other.restore(&bad);
+    assert_eq!(other.save(), Riot::new().save(), "a state with an unknown prescaler is ignored");
+}
+
+#[test]
+fn pia() {
+    let mut p = Pia::new();
+    p.set(0, 0x0F);
+    p.set(1, pia::CR_PORT | pia::CR_C1_IRQ_ENABLE);
+    assert_eq!(p.get(1), 0x05);
+    p.set(0, 0x5A);
+    p.set_input_a(0xC0);
+    assert_eq!(p.get(0), 0xCA);
+    p.set(1, 0x00);
+    assert_eq!(p.get(0), 0x0F, "DDR selected by CR bit 2");
+    p.set(1, pia::CR_PORT | pia::CR_C1_IRQ_ENABLE);
+    p.set_ca1(false);
+    assert!(p.irq_a());
+    assert_eq!(p.get(1) & pia::CR_IRQ1, pia::CR_IRQ1);
+    p.get(0);
+    assert!(!p.irq_a(), "reading the port clears the flags");
+    p.set(1, pia::CR_PORT | 0x18);
+    p.set_ca2(false);
+    p.set_ca2(true);
+    assert!(p.irq_a(), "CA2 positive edge");
+
+    // CB2 write handshake and pulse
+    p.set(3, pia::CR_PORT | 0x20);
+    p.set(2, 0x01);
+    assert!(!p.cb2_output());
+    p.set_cb1(false);
+    assert!(p.cb2_output(), "CB1 edge ends the handshake");
+    p.set(3, pia::CR_PORT | 0x28);
+    p.set(2, 0x02);
+    p.tick(4);
+    assert!(!p.cb2_output());
+    p.tick(2);
+    assert!(p.cb2_output(), "pulse lasts until the next instruction");
+    p.set(3, 0x30);
+    assert!(!p.cb2_output(), "manual low");
+
+    let state = p.save();
+    let mut copy = Pia::new();
+    copy.restore(&state);
+    assert_eq!(copy.save(), state);
+}
+
+#[test]
+fn pia_apple1_terminal() {
+    let output = SharedOutput::default();
+    let mut p = Pia::with_terminal(test_stream(b"a\n", &output));
+    // Woz Monitor setup
+    p.set(2, 0x7F);
+    p.set(1, 0xA7);
+    p.set(3, 0xA7);
+    let mut keys = Vec::new();
+    for _ in 0..1000 {
+        p.tick(2);
+        if p.get(1) & pia::CR_IRQ1 != 0 {
+            keys.push(p.get(0));
+        }
+        if keys.len() == 2 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(keys, vec![0xC1, 0x8D]);
+    assert_eq!(p.get(2) & 0x80, 0, "display ready");
+    p.set(2, 0xC1);
+    p.set(2, 0x8D);
+    assert_eq!(output.contents(), b"A\n");
+}
+
+#[test]
+fn terminal_input() {
+    let mut t = Terminal::new();
+    t.set_input(Box::new(Cursor::new(b"hi".to_vec())));
+    let output = SharedOutput::default();
+    t.output = Box::new(output.clone());
+    let mut keys = Vec::new();
+    for _ in 0..1000 {
+        if t.get(1) & devices::TERMINAL_KEY_AVAILABLE != 0 {
+            keys.push(t.get(0));
+            assert_eq!(t.get(1) & devices::TERMINAL_READ, devices::TERMINAL_READ);
+        }
+        if keys.len() == 2 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(keys, b"hi");
+    assert_eq!(t.get(0), 0, "reading with no key waiting doesn't block");
+    assert_eq!(t.get(1), 0);
+    t.set(0, b'!');
+    assert_eq!(output.contents(), b"!");
+
+    // IRQ while a key is waiting
+    let mut cpu = Cpu::new6502();
+    cpu.terminal.set_input(Box::new(io::empty()));
+    // LDA #$01; STA $FE; CLI; NOP ... handler at $0700: LDA $FD; RTI
+    for (addr, b) in [(0x0600, 0xA9), (0x0601, 0x01), (0x0602, 0x85), (0x0603, 0xFE),
+                      (0x0604, 0x58), (0x0605, 0xEA), (0x0700, 0xA5), (0x0701, 0xFD),
+                      (0x0702, 0x40), (0xFFFE, 0x00), (0xFFFF, 0x07)].iter() {
+        cpu.memory[*addr] = *b;
+    }
+    cpu.pc = 0x0600;
+    for _ in 0..3 {
+        cpu.execute_next_instruction();
+    }
+    assert_eq!(cpu.pc, 0x0605, "no IRQ without a key");
+    cpu.terminal.receive(b'k');
+    cpu.execute_next_instruction();
+    assert_eq!(cpu.pc, 0x0700);
+    cpu.execute_next_instruction();
+    assert_eq!(cpu.a, b'k');
+    assert!(!cpu.terminal.irq());
+}
+
+#[test]
+fn seeded_rand() {
+    let mut a = Cpu::new6502();
+    let mut b = Cpu::new6502();
+    a.rand = Rand::from_seed(42);
+    b.rand.reseed(42);
+    let first: Vec<u8> = (0..8).map(|_| a.get(0x00FF)).collect();
+    let second: Vec<u8> = (0..8).map(|_| b.get(0x00FF)).collect();
+    assert_eq!(first, second);
+    assert_eq!(a.rand.seed(), 42);
+
+    let mut r = Rand::from_seed(7).with_size(4);
+    let state = r.save();
+    let low = r.get(0);
+    let high = r.get(1);
+    r.restore(&state);
+    assert_eq!(r.rand() as u16, (high as u16) << 8 | low as u16, "16-bit reads");
+    for (i, b) in 7u32.to_le_bytes().iter().enumerate() {
+        r.set(i as u16, *b);
+    }
+    assert_eq!(r.get(0), low, "writing the seed register reseeds");
+
+    let mut cpu = Cpu::new6502();
+    cpu.bus.map_spec("rand@$D000,seed=$10,generator=lcg").unwrap();
+    let m = cpu.bus.iter().next().unwrap();
+    assert_eq!(m.device.status(), "Seed: 16 (lcg)");
+    assert_eq!(m.end(), 0xD003);
+    assert!(cpu.bus.map_spec("rand@$E000,generator=dice").is_err());
+    assert!(cpu.bus.map_spec("rand@$E000,seed=x").is_err());
+}
+
+#[test]
+fn interval_timer() {
+    let mut t = Timer::new();
+    t.set(2, 10);
+    t.set(3, 0);
+    t.set(4, timer::CONTROL_ENABLE);
+    assert_eq!(t.cycles_remaining(), Some(10));
+    t.tick(4);
+    assert_eq!(t.cycles_remaining(), Some(10), "counting starts after the writing instruction");
+    t.tick(9);
+    assert_eq!(t.get(0), 1);
+    assert_eq!(t.get(5), 0);
+    t.tick(1);
+    assert_eq!(t.get(5), timer::STATUS_EXPIRED);
+    assert_eq!(t.get(4) & timer::CONTROL_ENABLE, 0, "one-shot stops");
+    assert!(!t.irq(), "IRQ not enabled");
+    t.set(5, 0);
+    assert_eq!(t.get(5), 0, "writing the status acknowledges");
+
+    // Periodic with divide by 8: the counter is latched by reading the low byte
+    t.set(2, 0x00);
+    t.set(3, 0x02);
+    t.set(4, timer::CONTROL_ENABLE | timer::CONTROL_PERIODIC | 0x04 | timer::CONTROL_IRQ_ENABLE);
+    t.tick(2);
+    t.tick(8 * 0x101);
+    assert_eq!(t.get(0), 0xFF);
+    t.tick(8 * 0xFE);
+    assert_eq!(t.get(1), 0x00, "high byte latched");
+    assert!(!t.irq());
+    t.tick(8);
+    assert!(t.irq());
+    assert_eq!(t.cycles_remaining(), Some(8 * 0x200), "periodic reloads");
+    t.set(5, 0);
+    assert!(!t.irq());
+
+    let state = t.save();
+    let mut copy = Timer::new();
+    copy.restore(&state);
+    assert_eq!(copy.save(), state);
+
+    // A periodic IRQ through the CPU: LDA #$80; STA $D002; LDA #$03; STA $D003;
+    // LDA #$83; STA $D004; CLI; loop: JMP loop
+    // The handler at $0700 counts interrupts in $10: INC $10; STA $D005; RTI
+    let mut cpu = Cpu::new6502();
+    cpu.bus.map_spec("timer@$D000").unwrap();
+    let program = [0xA9, 0x80, 0x8D, 0x02, 0xD0, 0xA9, 0x03, 0x8D, 0x03, 0xD0,
+                   0xA9, 0x83, 0x8D, 0x04, 0xD0, 0x58, 0x4C, 0x10, 0x06];
+    cpu.memory[0x0600..0x0600 + program.len()].copy_from_slice(&program);
+    let handler = [0xE6, 0x10, 0x8D, 0x05, 0xD0, 0x40];
+    cpu.memory[0x0700..0x0700 + handler.len()].copy_from_slice(&handler);
+    cpu.memory[0xFFFE] = 0x00;
+    cpu.memory[0xFFFF] = 0x07;
+    cpu.pc = 0x0600;
+    while cpu.cycles < 0x380 * 4 + 100 {
+        cpu.execute_next_instruction();
+    }
+    assert_eq!(cpu.memory[0x10], 4, "one interrupt per period");
+    assert!(cpu.bus.map_spec("timer@$E000,period=5").is_err());
+}
+
+#[test]
+fn real_time_clock() {
+    assert_eq!(rtc::parse_time("1970-01-02"), Some(86_400));
+    assert_eq!(rtc::parse_time("2000-01-01T00:00:00"), Some(rtc::DEFAULT_START));
+    assert_eq!(rtc::parse_time("2024-13-01"), None);
+
+    let start = rtc::parse_time("2024-02-29T23:59:58").unwrap();
+    let mut r = Rtc::new(rtc::Source::Virtual { start, clock: 1000 }).unwrap();
+    let registers = |r: &mut Rtc| (0..8).map(|i| r.get(i)).collect::<Vec<u8>>();
+    assert_eq!(registers(&mut r), [58, 59, 23, 29, 2, 24, 20, 4]);
+    r.tick(2000);
+    assert_eq!(r.get(0), 58, "registers hold the latched time");
+    r.set(9, rtc::COMMAND_LATCH);
+    assert_eq!(registers(&mut r), [0, 0, 0, 1, 3, 24, 20, 5]);
+
+    r.set(8, rtc::CONTROL_BCD | rtc::CONTROL_ALARM_IRQ);
+    r.set(2, 0x12);
+    r.set(1, 0x34);
+    r.set(0, 0x56);
+    r.set(9, rtc::COMMAND_SET | rtc::COMMAND_LATCH);
+    assert_eq!(registers(&mut r), [0x56, 0x34, 0x12, 0x01, 0x03, 0x24, 0x20, 0x05]);
+    assert_eq!(r.now(), start + 2 + 12 * 3600 + 34 * 60 + 56);
+
+    // Alarm at 12:35:00, four seconds away
+    r.set(10, 0x00);
+    r.set(11, 0x35);
+    r.set(12, 0x12);
+    r.tick(1);
+    r.tick(3000);
+    assert!(!r.irq());
+    r.tick(1000);
+    assert!(r.irq());
+    assert_eq!(r.get(13), rtc::STATUS_ALARM);
+    r.set(13, 0);
+    assert!(!r.irq());
+    r.tick(10_000);
+    assert!(!r.irq(), "the alarm fires once a day");
+
+    let state = r.save();
+    let mut copy = Rtc::new(rtc::Source::Virtual { start, clock: 1000 }).unwrap();
+    copy.restore(&state);
+    assert_eq!(copy.now(), r.now());
+
+    let mut cpu = Cpu::new6502();
+    cpu.bus.map_spec("rtc@$D000,source=virtual,start=2001-02-03T04:05:06").unwrap();
+    assert_eq!(cpu.bus.iter().next().unwrap().device.status(), "2001-02-03 04:05:06 (virtual)");
+    assert!(cpu.bus.map_spec("rtc@$E000,start=0").is_err());
+    assert!(cpu.bus.map_spec("rtc@$E000,source=virtual,start=soon").is_err());
+    assert!(Rtc::new(rtc::Source::Virtual { start, clock: 0 }).is_err());
+
+    // Setting the clock to 0000-01-01 clamps to the earliest time
+    r.set(8, 0);
+    for (i, value) in [0, 0, 0, 1, 1, 0, 0].iter().enumerate() {
+        r.set(i as u16, *value);
+    }
+    r.set(9, rtc::COMMAND_SET | rtc::COMMAND_LATCH);
+    assert_eq!(r.now(), 0);
+    assert_eq!(registers(&mut r), [0, 0, 0, 1, 1, 70, 19, 4]);
+}
+
+#[test]
+fn block_storage() {
+    let mut image = vec![0; 1000];
+    image[512] = 0x42;
+    image[999] = 0x99;
+    let mut b = Block::new(Box::new(Cursor::new(image)), "test", 512, None).unwrap();
+    assert_eq!(b.size(), 512 + 12);
+    assert_eq!(b.get(512 + 8), 2, "partial blocks count");
+
+    b.set(512 + 4, 1);
+    b.set(512, block::COMMAND_READ);
+    assert_eq!(b.get(512 + 1), block::STATUS_DONE);
+    assert_eq!(b.get(0), 0x42);
+    assert_eq!(b.get(487), 0x99);
+    assert_eq!(b.get(488), 0, "past the end of the image");
+    assert!(!b.irq());
+
+    b.set(512 + 2, block::CONTROL_IRQ_ENABLE);
+    b.set(512 + 1, 0);
+    b.set(5, 0x55);
+    b.set(512 + 4, 0);
+    b.set(512, block::COMMAND_WRITE);
+    assert!(b.irq());
+    b.set(512 + 1, 0);
+    assert!(!b.irq(), "writing the status acknowledges");
+    b.set(5, 0);
+    b.set(512, block::COMMAND_READ);
+    assert_eq!(b.get(5), 0x55);
+
+    b.set(512 + 4, 2);
+    b.set(512, block::COMMAND_READ);
+    assert_eq!(b.get(512 + 1), block::STATUS_DONE | block::STATUS_RANGE_ERROR);
+    b.set(512, 0x7F);
+    assert_eq!(b.get(512 + 1), block::STATUS_DONE | block::STATUS_BAD_COMMAND);
+
+    let state = b.save();
+    let mut copy = Block::new(Box::new(Cursor::new(Vec::new())), "copy", 512, Some(1)).unwrap();
+    copy.restore(&state);
+    assert_eq!(copy.save(), state);
+
+    // Blocks written through the bus persist in the image file
+    let path = std::env::temp_dir().join(format!("v6502-block-{}.img", std::process::id()));
+    let spec = format!("block@$C000,file={},block_size=128,blocks=4", path.display());
+    let mut cpu = Cpu::new6502();
+    cpu.bus.map_spec(&spec).unwrap();
+    cpu.set(0xC000, 0xAB);
+    cpu.set(0xC084, 3);
+    cpu.set(0xC080, block::COMMAND_WRITE);
+    cpu.set(0xC080, block::COMMAND_FLUSH);
+    assert_eq!(cpu.get(0xC081), block::STATUS_DONE);
+    assert_eq!(cpu.get(0xC088), 4);
+    let contents = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(contents.len(), 512);
+    assert_eq!(contents[384], 0xAB);
+    assert!(cpu.bus.map_spec("block@$D000,file=x,block_size=100").is_err());
+    assert!(cpu.bus.map_spec("block@$D000").is_err());
+}
+
+#[test]
+fn text_screen() {
+    let mut s = TextScreen::new(4, 2);
+    assert_eq!(s.size(), 8 + 5);
+    for c in b"ab\r\nxyz" {
+        s.set(8 + 3, *c);
+    }
+    assert_eq!(s.text(), "ab\nxyz\n");
+    assert_eq!(s.cursor(), (3, 1));
+    s.set(8 + 3, b'!');
+    assert_eq!(s.text(), "xyz!\n\n", "scrolled after the last column");
+    s.set(8 + 3, b'?');
+    s.set(8 + 3, 0x08);
+    s.set(8 + 3, b'-');
+    assert_eq!(s.text(), "xyz!\n-\n");
+    s.set(0, b'X' | screen::INVERSE);
+    s.set(8, 2);
+    s.set(8 + 1, 7);
+    assert_eq!(s.cursor(), (2, 1), "the cursor stays on the screen");
+    assert_eq!(s.ansi(), "\x1b[?25l\x1b[H\x1b[7mX\x1b[27myz!\x1b[K\r\n-   \x1b[K\x1b[2;3H\x1b[?25h");
+
+    let png = s.png();
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    assert_eq!(&png[16..24], [0, 0, 0, 32, 0, 0, 0, 16], "8x8 pixels per character");
+    assert_eq!(&png[png.len() - 12..], [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+    assert_eq!(png::crc32(&png[12..29]), u32::from_be_bytes([png[29], png[30], png[31], png[32]]));
+
+    let dir = std::env::temp_dir();
+    let path = dir.join(format!("v6502-screen-{}-{{}}.txt", std::process::id()));
+    let mut cpu = Cpu::new6502();
+    cpu.bus.map_spec(&format!("text@$0400,columns=80,snapshot={}", path.display())).unwrap();
+    assert_eq!(cpu.bus.iter().next().unwrap().device.status(), "80x25 Cursor: 0,0");
+    for c in b"HELLO" {
+        cpu.set(0x0400 + 2000 + 3, *c);
+    }
+    cpu.set(0x0400 + 2000 + 4, 0);
+    assert_eq!(cpu.get(0x0400 + 2000 + 4), 0);
+    let first = path.to_str().unwrap().replace("{}", "1");
+    let text = std::fs::read_to_string(&first).unwrap();
+    std::fs::remove_file(&first).unwrap();
+    assert_eq!(text, format!("HELLO{}", "\n".repeat(25)));
+    assert!(cpu.bus.map_spec("text@$8000,rows=0").is_err());
+    assert!(cpu.bus.map_spec("text@$8000,display=gui").is_err());
+}
+
+#[test]
+fn bitmap_screen() {
+    assert!(Bitmap::new(16, 16, 3).is_err());
+    assert!(Bitmap::new(512, 512, 8).is_err());
+    assert!(Bitmap::new(10, 6553, 8).is_err(), "65536 bytes don't fit in a u16 size");
+    assert_eq!(Bitmap::new(1, 65529, 8).unwrap().size(), 0xFFFF);
+
+    // 2 bits per pixel: 3 bytes per row
+    let mut b = Bitmap::new(10, 2, 2).unwrap().with_frame_cycles(100);
+    assert_eq!(b.size(), 6 + 6);
+    b.set(0, 0b1110_0100);
+    b.set(5, 0b1100_0000);
+    assert_eq!((0..4).map(|x| b.pixel(x, 0)).collect::<Vec<_>>(), [3, 2, 1, 0]);
+    assert_eq!(b.pixel(8, 1), 3);
+    assert_eq!(b.palette(2), [170, 170, 170]);
+
+    // Set color 1 to red and read it back
+    b.set(6, 1);
+    for c in [0xFF, 0x00, 0x00].iter() {
+        b.set(6 + 1, *c);
+    }
+    assert_eq!(b.get(6), 2, "moves to the next color");
+    b.set(6, 1);
+    assert_eq!((0..3).map(|_| b.get(6 + 1)).collect::<Vec<_>>(), [0xFF, 0x00, 0x00]);
+    assert_eq!(&b.rgb()[..12], [255, 255, 255, 170, 170, 170, 255, 0, 0, 0, 0, 0]);
+    assert_eq!(&b.ppm()[..11], b"P6\n10 2\n255");
+
+    // Double buffering: drawing isn't shown until the buffers are flipped
+    b.set(6 + 3, bitmap::CONTROL_DOUBLE_BUFFER | bitmap::CONTROL_IRQ_ENABLE);
+    b.set(0, 0);
+    assert_eq!(b.pixel(0, 0), 3);
+    b.set(6 + 2, 0);
+    assert_eq!(b.pixel(0, 0), 0);
+    assert_eq!(b.get(0), 0b1110_0100, "now drawing in the old buffer");
+
+    b.tick(99);
+    assert!(!b.irq());
+    b.tick(1);
+    assert!(b.irq());
+    assert_eq!(b.get(6 + 5), 1);
+    b.set(6 + 4, 0);
+    assert!(!b.irq());
+
+    let state = b.save();
+    let mut copy = Bitmap::new(10, 2, 2).unwrap();
+    copy.restore(&state);
+    assert_eq!(copy.save(), state);
+
+    let path = std::env::temp_dir().join(format!("v6502-bitmap-{}-{{}}.png", std::process::id()));
+    let mut cpu = Cpu::new6502();
+    let spec = format!("bitmap@$2000,width=8,height=8,fps=1000,dump={},every=2", path.display());
+    cpu.bus.map_spec(&spec).unwrap();
+    cpu.set(0x2000, 0x80);
+    for _ in 0..4000 {
+        cpu.bus.tick(1);
+    }
+    let name = |n: u32| path.to_str().unwrap().replace("{}", &n.to_string());
+    assert!(!Path::new(&name(1)).exists());
+    let png = std::fs::read(name(2)).unwrap();
+    assert_eq!(&png[16..24], [0, 0, 0, 8, 0, 0, 0, 8]);
+    for n in &[2, 4] {
+        std::fs::remove_file(name(*n)).unwrap();
+    }
+    assert_eq!(cpu.bus.iter().next().unwrap().device.status(), "8x8 with 1 bits per pixel, Frame: 4");
+    assert!(cpu.bus.map_spec("bitmap@$4000,bits=16").is_err());
+}
+
+#[test]
+fn semihosting() {
+    let root = std::env::temp_dir().join(format!("v6502-semihost-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("data")).unwrap();
+    std::fs::write(root.join("data/input.txt"), b"fixture").unwrap();
+
+    let mut cpu = Cpu::new6502();
+    cpu.bus.map(0xD000, Box::new(Semihost::new(&root).unwrap())).unwrap();
+    let block = 0x0300u16;
+    let call = |cpu: &mut Cpu, operation: u8, params: &[(u16, u8)]| {
+        for (offset, value) in params {
+            cpu.memory[(block + offset) as usize] = *value;
+        }
+        cpu.set(0xD001, block as u8);
+        cpu.set(0xD002, (block >> 8) as u8);
+        cpu.set(0xD000, operation);
+        assert_eq!(cpu.get(0xD003), semihost::RESULT_BUSY);
+        cpu.tick(0);
+        assert_eq!(cpu.get(0xD003), cpu.memory[block as usize]);
+        cpu.memory[block as usize]
+    };
+    let name = |cpu: &mut Cpu, name: &str| {
+        cpu.memory[0x0400..0x0400 + name.len()].copy_from_slice(name.as_bytes());
+        cpu.memory[0x0400 + name.len()] = 0;
+    };
+
+    name(&mut cpu, "data/input.txt");
+    assert_eq!(call(&mut cpu, semihost::OP_OPEN, &[(2, 0x00), (3, 0x04), (6, semihost::MODE_READ)]),
+        semihost::RESULT_OK);
+    let handle = cpu.memory[0x0301];
+    assert_eq!(handle, 1);
+    assert_eq!(call(&mut cpu, semihost::OP_READ, &[(2, 0x00), (3, 0x05), (4, 100), (5, 0)]),
+        semihost::RESULT_OK);
+    assert_eq!(cpu.memory[0x0304], 7, "bytes read");
+    assert_eq!(&cpu.memory[0x0500..0x0507], b"fixture");
+    assert_eq!(call(&mut cpu, semihost::OP_SEEK, &[(6, 2), (7, 0xFD), (8, 0xFF), (9, 0xFF), (10, 0xFF)]),
+        semihost::RESULT_OK);
+    assert_eq!(&cpu.memory[0x0307..0x030B], [4, 0, 0, 0], "3 bytes from the end");
+    assert_eq!(call(&mut cpu, semihost::OP_CLOSE, &[]), semihost::RESULT_OK);
+    assert_eq!(call(&mut cpu, semihost::OP_CLOSE, &[]), semihost::RESULT_BAD_HANDLE);
+
+    name(&mut cpu, "result.txt");
+    let open_write = [(2, 0x00), (3, 0x04), (6, semihost::MODE_WRITE)];
+    assert_eq!(call(&mut cpu, semihost::OP_OPEN, &open_write), semihost::RESULT_OK);
+    assert_eq!(call(&mut cpu, semihost::OP_WRITE, &[(2, 0x02), (3, 0x05), (4, 3), (5, 0)]),
+        semihost::RESULT_OK);
+    assert_eq!(call(&mut cpu, semihost::OP_CLOSE, &[]), semihost::RESULT_OK);
+    assert_eq!(std::fs::read(root.join("result.txt")).unwrap(), b"xtu");
+
+    name(&mut cpu, "missing.txt");
+    let open_read = [(2, 0x00), (3, 0x04), (6, semihost::MODE_READ)];
+    assert_eq!(call(&mut cpu, semihost::OP_OPEN, &open_read), semihost::RESULT_NOT_FOUND);
+    for path in &["../escape.txt", "/etc/passwd", "data/../../escape.txt"] {
+        name(&mut cpu, path);
+        assert_eq!(call(&mut cpu, semihost::OP_OPEN, &open_write), semihost::RESULT_DENIED, "{}", path);
+    }
+    #[cfg(unix)]
+    {
+        // A dangling link would otherwise create its target outside the root
+        let outside = root.with_extension("outside");
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+        name(&mut cpu, "link");
+        assert_eq!(call(&mut cpu, semihost::OP_OPEN, &open_write), semihost::RESULT_DENIED);
+        assert!(!outside.exists());
+        std::fs::write(root.join("data/inside.txt"), b"").unwrap();
+        std::os::unix::fs::symlink(root.join("data/inside.txt"), root.join("inside")).unwrap();
+        name(&mut cpu, "inside");
+        assert_eq!(call(&mut cpu, semihost::OP_OPEN, &open_read), semihost::RESULT_DENIED);
+    }
+    assert_eq!(call(&mut cpu, 0x42, &[]), semihost::RESULT_BAD_OPERATION);
+
+    // Reads are written like the instruction's own writes, so ROM is protected and undo restores RAM
+    name(&mut cpu, "data/input.txt");
+    assert_eq!(call(&mut cpu, semihost::OP_OPEN, &open_read), semihost::RESULT_OK);
+    cpu.bus.protect(ReadOnly { start: 0xE000, end: 0xFFFF, policy: WritePolicy::Halt });
+    cpu.history = Some(History::new(10));
+    for (offset, value) in &[(2, 0xFE), (3, 0xDF), (4, 4), (5, 0)] {
+        cpu.memory[(block + offset) as usize] = *value;
+    }
+    cpu.memory[0x0600..0x0605].copy_from_slice(&[0xA9, semihost::OP_READ, 0x8D, 0x00, 0xD0]);
+    cpu.pc = 0x0600;
+    cpu.execute_next_instruction();
+    cpu.execute_next_instruction();
+    assert_eq!(&cpu.memory[0xDFFE..0xE002], b"fi\0\0");
+    assert_eq!(cpu.fault, Some(WriteFault { pc: 0x0602, address: 0xE000, value: b'x' }));
+    assert!(cpu.undo());
+    assert_eq!(&cpu.memory[0xDFFE..0xE000], [0, 0]);
+    assert_eq!(cpu.fault, None);
+    cpu.history = None;
+    call(&mut cpu, semihost::OP_CLOSE, &[]);
+
+    assert!(!cpu.is_break());
+    call(&mut cpu, semihost::OP_EXIT, &[(1, 3)]);
+    assert_eq!(cpu.exit_code, Some(3));
+    assert!(cpu.is_break(), "exiting ends the program");
+    std::fs::remove_dir_all(&root).unwrap();
+    assert!(cpu.bus.map_spec("semihost@$E000,root=/no/such/directory").is_err());
+}
+
+#[test]
+fn exit_device() {
+    let mut cpu = Cpu::new6502();
+    cpu.bus.map_spec("exit@$FFF0").unwrap();
+    // LDA #$05; STA $FFF0; NOP
+    let program = [0xA9, 0x05, 0x8D, 0xF0, 0xFF, 0xEA];
+    cpu.memory[0x0600..0x0600 + program.len()].copy_from_slice(&program);
+    cpu.pc = 0x0600;
+    cpu.run();
+    assert_eq!(cpu.exit_code, Some(5));
+    assert_eq!(cpu.pc, 0x0605, "stops after the writing instruction");
+    assert_eq!(cpu.bus.iter().next().unwrap().device.status(), "Exit code: 5");
+    cpu.reset();
+    assert_eq!(cpu.exit_code, None);
+    assert!(!cpu.is_break());
+
+    let mut exit = Exit::new();
+    exit.set(0, 0);
+    assert_eq!(exit.exit_code(), Some(0), "exit code 0 still stops");
+}
+
+#[test]
+fn bank_switching() {
+    let mut cpu = Cpu::new6502();
+    cpu.bus.map_spec("bank@$8000,size=$4000,banks=4,select=$FFF0").unwrap();
+    for bank in 0..4 {
+        cpu.set(0xFFF0, bank);
+        cpu.set(0x8000, 0x10 + bank);
+        cpu.set(0xBFFF, 0x20 + bank);
+    }
+    assert_eq!(cpu.memory[0x8000], 0, "banked writes don't reach RAM");
+    cpu.set(0xFFF0, 1);
+    assert_eq!((cpu.get(0x8000), cpu.get(0xBFFF)), (0x11, 0x21));
+    cpu.set(0xFFF0, 6);
+    assert_eq!(cpu.get(0xFFF0), 2, "bank numbers wrap");
+    assert_eq!(cpu.get(0x8000), 0x12);
+    assert_eq!(cpu.get(0xC000), 0, "outside the window");
+
+    let window = cpu.bus.iter().next().unwrap();
+    assert_eq!(window.device.status(), "RAM bank 2 of 4 ($4000 bytes each)");
+    let state = window.device.save();
+    let (mut copy, _) = bank::banked(Banks::new(BankKind::Ram, 4, 0x4000));
+    copy.restore(&state);
+    assert_eq!(copy.get(0x3FFF), 0x22);
+    assert_eq!(copy.save(), state);
+
+    // A ROM image split into three banks, switched by writing to the window
+    let image: Vec<u8> = (0..0x2800).map(|i| (i / 0x1000) as u8).collect();
+    let path = std::env::temp_dir().join(format!("v6502-bank-{}.rom", std::process::id()));
+    std::fs::write(&path, &image).unwrap();
+    cpu.bus.map_spec(&format!("bank@$C000,size=$1000,type=rom,file={}", path.display())).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(cpu.get(0xC000), 0);
+    cpu.set(0xC000, 2);
+    assert_eq!(cpu.get(0xC000), 2);
+    assert_eq!(cpu.get(0xC800), 0xFF, "the last bank is padded");
+    assert_eq!(cpu.memory[0xC000], 0);
+
+    let count = cpu.bus.iter().count();
+    assert!(cpu.bus.map_spec("bank@$2000,size=$100,select=$C000").is_err());
+    assert_eq!(cpu.bus.iter().count(), count, "nothing is mapped if the select register doesn't fit");
+    assert!(cpu.bus.get(0x2000).is_none());
+    assert!(cpu.bus.map_spec("bank@$2000,size=$100").is_err());
+    assert!(cpu.bus.map_spec("bank@$2000,type=rom").is_err());
+}
+
+#[test]
+fn read_only_memory() {
+    assert_eq!(ReadOnly::parse("$E000-$FFFF"),
+        Ok(ReadOnly { start: 0xE000, end: 0xFFFF, policy: WritePolicy::Ignore }));
+    assert_eq!(ReadOnly::parse("$FFFC-$FFFD,halt").map(|r| r.policy), Ok(WritePolicy::Halt));
+    assert!(ReadOnly::parse("$FFFF-$E000").is_err());
+    assert!(ReadOnly::parse("$E000-$FFFF,panic").is_err());
+
+    // LDA #$42; STA $E000; STA $E001; STA $0200
+    let program = [0xA9, 0x42, 0x8D, 0x00, 0xE0, 0x8D, 0x01, 0xE0, 0x8D, 0x00, 0x02];
+    let run = |policy: WritePolicy| {
+        let mut cpu = Cpu::new6502();
+        cpu.memory[0x0600..0x0600 + program.len()].copy_from_slice(&program);
+        cpu.set(0xE000, 0x99);
+        cpu.bus.protect(ReadOnly { start: 0xE000, end: 0xE0FF, policy });
+        cpu.set(0xE001, 0x98);
+        assert_eq!(cpu.memory[0xE001], 0x98, "only the program is blocked");
+        cpu.pc = 0x0600;
+        for _ in 0..4 {
+            if !cpu.is_break() {
+                cpu.execute_next_instruction();
+            }
+        }
+        assert_eq!((cpu.memory[0xE000], cpu.memory[0xE001]), (0x99, 0x98));
+        cpu
+    };
+
+    let cpu = run(WritePolicy::Ignore);
+    assert!(cpu.write_faults.is_empty());
+    assert_eq!(cpu.memory[0x0200], 0x42);
+
+    let cpu = run(WritePolicy::Log);
+    assert_eq!(cpu.write_faults, [
+        WriteFault { pc: 0x0602, address: 0xE000, value: 0x42 },
+        WriteFault { pc: 0x0605, address: 0xE001, value: 0x42 },
+    ]);
+    assert_eq!(cpu.memory[0x0200], 0x42);
+
+    let mut cpu = run(WritePolicy::Halt);
+    assert_eq!(cpu.fault, Some(WriteFault { pc: 0x0602, address: 0xE000, value: 0x42 }));
+    assert!(cpu.is_break());
+    assert_eq!(cpu.pc, 0x0605);
+    assert_eq!(cpu.memory[0x0200], 0, "stopped after the faulting instruction");
+    assert_eq!(cpu.fault.unwrap().to_string(),
+        "write of $42 to read-only $E000 by the instruction at $0602");
+    let mut debugger = Debugger::new();
+    cpu.clear_break();
+    cpu.pc = 0x0602;
+    cpu.fault = None;
+    assert!(debugger.command(&mut cpu, "step").starts_with("Program stopped by a write of $42"));
+
+    let mut bus = Bus::new();
+    bus.protect(ReadOnly::parse("$E000-$FFFF,log").unwrap());
+    bus.protect(ReadOnly::parse("$FFFA-$FFFF,halt").unwrap());
+    assert_eq!(bus.write_policy(0xFFFC), Some(WritePolicy::Halt), "the last region added wins");
+    assert_eq!(bus.write_policy(0xE000), Some(WritePolicy::Log));
+    assert_eq!(bus.write_policy(0xD000), None);
+}
+
+#[test]
+fn unmapped_memory() {
+    assert_eq!(parse_range("$0000-$7FFF"), Ok((0x0000, 0x7FFF)));
+    assert!(parse_range("$8000").is_err());
+
+    // LDA $9000; STA $0200; STA $9000; LDA $E000
+    let program = [0xAD, 0x00, 0x90, 0x8D, 0x00, 0x02, 0x8D, 0x00, 0x90, 0xAD, 0x00, 0xE0];
+    let setup = |policy: UnmappedPolicy| {
+        let mut cpu = Cpu::new6502();
+        cpu.memory[0x0600..0x0600 + program.len()].copy_from_slice(&program);
+        cpu.memory[0x9000] = 0x55;
+        cpu.memory[0xE000] = 0x66;
+        cpu.bus.add_ram(0x0000, 0x7FFF);
+        cpu.bus.protect(ReadOnly::parse("$E000-$FFFF").unwrap());
+        cpu.bus.set_unmapped_policy(policy);
+        cpu.pc = 0x0600;
+        cpu
+    };
+
+    let mut cpu = setup(UnmappedPolicy::OpenBus);
+    assert!(cpu.bus.is_mapped(0xE000), "read-only memory is mapped");
+    assert!(!cpu.bus.is_mapped(0x9000));
+    assert_eq!(cpu.get(0x9000), 0x55, "only the program sees open bus");
+    for _ in 0..4 {
+        cpu.execute_next_instruction();
+    }
+    assert_eq!(cpu.memory[0x0200], 0x90, "the high byte of the operand was last on the bus");
+    assert_eq!(cpu.memory[0x9000], 0x55, "unmapped writes are dropped");
+    assert_eq!(cpu.a, 0x66);
+    assert!(cpu.unmapped_access.is_none());
+
+    let mut cpu = setup(UnmappedPolicy::Halt);
+    cpu.run();
+    assert_eq!(cpu.unmapped_access, Some(UnmappedAccess { pc: 0x0600, address: 0x9000, kind: AccessKind::Read }));
+    assert_eq!(cpu.pc, 0x0603);
+    assert_eq!(cpu.unmapped_access.unwrap().to_string(),
+        "read of unmapped $9000 by the instruction at $0600");
+    cpu.reset();
+    assert!(cpu.unmapped_access.is_none());
+
+    // Without any RAM declared, all of memory is RAM
+    let mut bus = Bus::new();
+    bus.set_unmapped_policy(UnmappedPolicy::Halt);
+    assert!(bus.is_mapped(0x9000));
+}
+
+#[test]
+fn peek_has_no_side_effects() {
+    let mut cpu = Cpu::new6502();
+    cpu.terminal.set_input(Box::new(std::io::empty()));
+    cpu.terminal.receive(b'K');
+    assert_eq!(cpu.peek(0x00FD), b'K');
+    assert_eq!(cpu.peek(0x00FE), devices::TERMINAL_KEY_AVAILABLE);
+    let dump = format!("{:?}", cpu);
+    assert!(dump.contains("00F0 : ") && dump.contains(" 4B "), "the dump shows the waiting key");
+    assert_eq!(cpu.get(0x00FD), b'K', "peeking and dumping didn't consume the key");
+    assert_eq!(cpu.peek(0x00FD), 0);
+
+    cpu.bus.map_spec("via@$D000").unwrap();
+    cpu.bus.map_spec("timer@$D100").unwrap();
+    cpu.set(0xD004, 0x02);
+    cpu.set(0xD005, 0x00);
+    cpu.bus.tick(1);
+    cpu.bus.tick(10);
+    assert_ne!(cpu.peek(0xD00D) & via::IRQ_T1, 0);
+    cpu.peek(0xD004);
+    assert_ne!(cpu.peek(0xD00D) & via::IRQ_T1, 0, "peeking the counter leaves the flag set");
+    cpu.get(0xD004);
+    assert_eq!(cpu.peek(0xD00D) & via::IRQ_T1, 0, "reading the counter clears it");
+
+    cpu.set(0xD102, 0x34);
+    cpu.set(0xD103, 0x12);
+    cpu.set(0xD104, timer::CONTROL_ENABLE);
+    assert_eq!(cpu.peek(0xD100), 0x34);
+    assert_eq!(cpu.peek(0xD101), 0x00, "peeking the low byte doesn't latch the high byte");
+    cpu.get(0xD100);
+    assert_eq!(cpu.peek(0xD101), 0x12);
+
+    cpu.bus.map_spec("bank@$8000,size=$100,select=$FFF0").unwrap();
+    cpu.set(0x8000, 0xEA);
+    assert!(disassemble_line(&cpu, 0x8000).0.contains("EA"), "code in devices is disassembled");
+    assert!(disassemble_line(&cpu, 0x8000).0.ends_with("NOP"));
+}
+
+#[test]
+fn mirrored_memory() {
+    let mut cpu = Cpu::new6502();
+    cpu.bus.add_ram(0x0000, 0x07FF);
+    cpu.bus.add_mirror(Mirror::parse("$0000-$1FFF,$07FF").unwrap());
+    cpu.set(0x0805, 0x42);
+    assert_eq!(cpu.memory[0x0005], 0x42);
+    assert_eq!(cpu.memory[0x0805], 0x00);
+    assert_eq!(cpu.get(0x1805), 0x42);
+    assert_eq!(cpu.peek(0x1005), 0x42);
+    assert!(cpu.bus.is_mapped(0x1FFF));
+    assert!(!cpu.bus.is_mapped(0x2000));
+    // The terminal and random number registers repeat with the RAM they sit in
+    cpu.terminal.set_input(Box::new(io::empty()));
+    cpu.terminal.receive(b'K');
+    cpu.memory[0x00FD] = 0x99;
+    assert_eq!(cpu.peek(0x08FD), b'K');
+    assert_eq!(cpu.get(0x10FD), b'K');
+    cpu.set(0x18FF, 0x12);
+    assert_eq!(cpu.memory[0x00FF], 0x00, "the write went to the random number generator");
+
+    cpu.bus.map_spec("via@$D000,mirror=$D0FF").unwrap();
+    let m = cpu.bus.iter().next().unwrap();
+    assert_eq!(m.end(), 0xD0FF);
+    assert_eq!(m.mirror().unwrap().mask, 0x0F, "the mask covers the VIA's 16 registers");
+    cpu.set(0xD0F3, 0x5A);
+    assert_eq!(cpu.get(0xD003), 0x5A, "DDRA repeats every 16 bytes");
+    assert!(cpu.bus.map_spec("exit@$D080").is_err(), "devices can't be mapped over a mirror");
+
+    // The RTC's 14 registers are decoded with 4 bits, leaving gaps
+    cpu.bus.map_spec("rtc@$C000,source=virtual,mirror=$C0FF").unwrap();
+    assert!(cpu.bus.peek(0xC018).is_some());
+    assert!(cpu.bus.peek(0xC01E).is_none());
+    cpu.bus.map_spec("exit@$C100,mirror=$C1FF,mask=$00").unwrap();
+    cpu.set(0xC1AB, 3);
+    assert_eq!(cpu.bus.exit_code(), Some(3));
+
+    assert!(Mirror::parse("$0000-$1FFF").is_err());
+    assert!(Mirror::parse("$FF00-$FFFF,$0FFF").is_err());
+    assert!(cpu.bus.map_spec("acia@$B000,mask=$03").is_err());
+    assert!(cpu.bus.map_spec("via@$B000,mirror=$B008").is_err());
+    assert!(cpu.bus.peek(0xB000).is_none(), "failed mappings are rolled back");
+}
@@ -0,0 +1,73 @@
+/*
+    Copyright 2021, Andrew C. Young <andrew@vaelen.org>
+
+    This file is part of the v6502 library.
+
+    The v6502 library is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Foobar is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with the v6502 library.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io::{self, Write};
+
+use crate::cpu::Cpu;
+use crate::disassembler::disassemble_line;
+use crate::observer::Observer;
+
+/**
+ * Formats one trace line for the instruction at PC: the disassembly,
+ * the registers before it executes, and its location relative to the
+ * nearest symbol.
+ */
+pub fn trace_line(cpu: &Cpu) -> String {
+    let (line, _) = disassemble_line(cpu, cpu.pc);
+    let mut trace = format!("{:<36} A:{:02X} X:{:02X} Y:{:02X} SP:{:02X} SR:{:02X}",
+        line, cpu.a, cpu.x, cpu.y, cpu.sp, cpu.sr);
+    if cpu.symbols.nearest(cpu.pc).is_some() {
+        trace.push_str("  ");
+        trace.push_str(&cpu.symbols.describe(cpu.pc));
+    }
+    trace
+}
+
+/** Writes a line for every instruction executed. */
+pub struct Tracer {
+    out: Box<dyn Write>,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>) -> Tracer {
+        Tracer { out, error: None }
+    }
+
+    /**
+     * Flushes the trace. Returns the first error that happened while
+     * tracing, if any.
+     */
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.out.flush()
+    }
+}
+
+impl Observer for Tracer {
+    fn before(&mut self, cpu: &Cpu) {
+        if self.error.is_none() {
+            if let Err(e) = writeln!(self.out, "{}", trace_line(cpu)) {
+                self.error = Some(e);
+            }
+        }
+    }
+}
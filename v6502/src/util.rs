@@ -26,39 +26,46 @@ use std::io::prelude::*;
 use std::path::Path;
 
 pub fn load_hex(cpu: &mut Cpu, filename: &str) {
+    if let Err(e) = try_load_hex(cpu, filename) {
+        panic!("{}", e);
+    }
+}
+
+/**
+ * Loads a program like load_hex, but returns an error instead of
+ * panicking. Nothing is loaded unless the whole file is valid.
+ */
+pub fn try_load_hex(cpu: &mut Cpu, filename: &str) -> Result<(), String> {
     // Create a path to the desired file
     let path = Path::new(filename);
     let display = path.display();
 
     // Open the path in read-only mode, returns `io::Result<File>`
-    let file = match File::open(&path) {
-        Err(why) => panic!("couldn't open {}: {}", display, why),
-        Ok(file) => file,
-    };
+    let file = File::open(path).map_err(|why| format!("couldn't open {}: {}", display, why))?;
 
+    let mut bytes = Vec::new();
     let buffered = BufReader::new(file);
     for result in buffered.lines() {
-        match result {
-            Err(why) => panic!("couldn't read from {}: {}", display, why),
-            Ok(line) => {
-                let parts: Vec<&str> = line.split(":").collect();
-                let offset = parts[0];
-                let mut pos = match u16::from_str_radix(offset, 16) {
-                    Err(why) => panic!("invalid offset {} in {}: {}", offset, display, why),
-                    Ok(pos) => pos,
-                };
-                for h in parts[1].split(" ") {
-                    let hex = h.trim();
-                    if !hex.is_empty() {
-                        let byte = match u8::from_str_radix(hex, 16) {
-                            Err(why) => panic!("invalid hex value {} in {}: {}", hex, display, why),
-                            Ok(byte) => byte,
-                        };
-                        cpu.set(pos, byte);
-                        pos += 1;
-                    }
-                }
+        let line = result.map_err(|why| format!("couldn't read from {}: {}", display, why))?;
+        let (offset, data) = match line.find(':') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None if line.trim().is_empty() => continue,
+            None => return Err(format!("missing offset in {}: {}", display, line)),
+        };
+        let mut pos = u16::from_str_radix(offset, 16)
+            .map_err(|why| format!("invalid offset {} in {}: {}", offset, display, why))?;
+        for h in data.split(' ') {
+            let hex = h.trim();
+            if !hex.is_empty() {
+                let byte = u8::from_str_radix(hex, 16)
+                    .map_err(|why| format!("invalid hex value {} in {}: {}", hex, display, why))?;
+                bytes.push((pos, byte));
+                pos = pos.wrapping_add(1);
             }
         }
     }
-}
\ No newline at end of file
+    for (pos, byte) in bytes {
+        cpu.set(pos, byte);
+    }
+    Ok(())
+}